@@ -1,14 +1,141 @@
-use std::io::Result;
+use std::{env, fmt::Write, fs, io::Result, path::Path};
+
+use heck::ToUpperCamelCase;
+
+#[derive(Default)]
+struct MessageOptions {
+    proto_name: String,
+    name: String,
+    id: u32,
+    source: String,
+    ifdef: Option<String>,
+    log: bool,
+    no_delay: bool,
+}
+
+// Reads the `api_options.proto` extensions of all top level messages. protoc drops unknown
+// extensions from the descriptors available to prost, so the proto source is parsed instead.
+fn message_options(proto: &str) -> Vec<MessageOptions> {
+    let mut messages = Vec::new();
+    let mut current: Option<MessageOptions> = None;
+    let mut depth = 0;
+
+    for line in proto.lines() {
+        let line = line.split("//").next().unwrap_or_default().trim();
+        if depth == 0 {
+            if let Some(name) = line.strip_prefix("message ") {
+                let name = name.trim_end_matches('{').trim();
+                current = Some(MessageOptions {
+                    proto_name: name.to_string(),
+                    name: name.to_upper_camel_case(),
+                    source: "SourceBoth".to_string(),
+                    log: true,
+                    ..Default::default()
+                });
+            }
+        } else if depth == 1 {
+            if let (Some(message), Some(option)) = (current.as_mut(), line.strip_prefix("option (")) {
+                let (key, value) = option.split_once(')').unwrap_or_default();
+                let value = value.trim().trim_start_matches('=').trim().trim_end_matches(';').trim();
+                match key {
+                    "id" => message.id = value.parse().expect("invalid message id"),
+                    "source" => message.source = value.to_upper_camel_case(),
+                    "ifdef" => message.ifdef = Some(value.trim_matches('"').to_string()),
+                    "log" => message.log = value == "true",
+                    "no_delay" => message.no_delay = value == "true",
+                    _ => (),
+                }
+            }
+        }
+
+        depth += line.matches('{').count();
+        depth -= line.matches('}').count();
+        if depth == 0 {
+            if let Some(message) = current.take() {
+                // messages without id are only used as fields of other messages
+                if message.id != 0 {
+                    messages.push(message);
+                }
+            }
+        }
+    }
+    messages
+}
+
+// fields never shown in Debug output or serialized, e.g. to the audit log
+const SECRET_FIELDS: &[(&str, &str)] = &[
+    ("ConnectRequest", "password"),
+    ("LockCommandRequest", "code"),
+    ("AlarmControlPanelCommandRequest", "code"),
+];
+
+// cargo feature required for the messages of a firmware component, other messages are always available
+fn feature(ifdef: Option<&str>) -> Option<&'static str> {
+    match ifdef? {
+        "USE_BLUETOOTH_PROXY" => Some("bluetooth"),
+        "USE_VOICE_ASSISTANT" => Some("voice"),
+        "USE_ESP32_CAMERA" => Some("camera"),
+        "USE_MEDIA_PLAYER" => Some("media-player"),
+        "USE_ALARM_CONTROL_PANEL" => Some("alarm"),
+        _ => None,
+    }
+}
+
+fn generate_messages(messages: &[MessageOptions], out_path: &Path) -> Result<()> {
+    let mut code = String::from("esphome_messages! {\n");
+    for m in messages {
+        if let Some(feature) = feature(m.ifdef.as_deref()) {
+            writeln!(code, "    #[cfg(feature = {:?})]", feature).unwrap();
+        }
+        let ifdef = match &m.ifdef {
+            Some(ifdef) => format!("Some({:?})", ifdef),
+            None => "None".to_string(),
+        };
+        writeln!(
+            code,
+            "    {} => {} {{ source: {}, ifdef: {}, log: {}, no_delay: {} }},",
+            m.id, m.name, m.source, ifdef, m.log, m.no_delay
+        )
+        .unwrap();
+    }
+    code.push_str("}\n");
+    fs::write(out_path, code)
+}
 
 fn main() -> Result<()> {
+    let messages = message_options(&fs::read_to_string("protobuf/api.proto")?);
+
     // Prost
     let mut builder = prost_build::Config::new();
     builder.default_package_filename("api");
     builder.out_dir("./src/");
+    // payloads of `bytes` fields are sliced out of the frame buffer instead of copied
+    builder.bytes(["."]);
+    // serialized for the audit log
+    builder.type_attribute(".", "#[derive(serde::Serialize)]");
+    for m in &messages {
+        if let Some(feature) = feature(m.ifdef.as_deref()) {
+            builder.type_attribute(format!(".{}", m.proto_name), format!("#[cfg(feature = {:?})]", feature));
+        }
+    }
+    // skip_debug replaces the previous list, so all messages are passed at once
+    builder.skip_debug(SECRET_FIELDS.iter().map(|(message, _)| format!(".{}", message)));
+    for (message, field) in SECRET_FIELDS {
+        builder.type_attribute(format!(".{}", message), "#[derive(custom_debug_derive::Debug)]");
+        builder.field_attribute(
+            format!(".{}.{}", message, field),
+            "#[debug(with = \"crate::redact::redacted\")]",
+        );
+        builder.field_attribute(format!(".{}.{}", message, field), "#[serde(skip_serializing)]");
+    }
     builder.compile_protos(&["protobuf/api.proto"], &["protobuf/"])?;
     // builder.disable_comments(&["."]);
     // builder.enable_type_names();
     builder.protoc_arg("--include_source_info");
 
+    // ESPHomeMessage enum and id mapping
+    let out_dir = env::var("OUT_DIR").unwrap();
+    generate_messages(&messages, &Path::new(&out_dir).join("esphome_messages.rs"))?;
+
     Ok(())
 }
@@ -0,0 +1,58 @@
+use std::{future::Future, path::Path, pin::Pin};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+
+use crate::result::Result;
+
+// Command sent to a device, secret fields and the state of secret entities are left out
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub device_name: String,
+    pub mac_address: String,
+    // e.g. switch.heater, none for commands not targeting an entity like service calls
+    pub entity_id: Option<String>,
+    pub command: &'static str,
+    pub message_id: u32,
+    pub fields: serde_json::Value,
+    // who sent the command, see `Connection::send_message_as`
+    pub actor: Option<String>,
+}
+
+pub type AuditFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+// Receives every command before it is sent, a failing sink stops the command from being sent
+pub trait AuditSink: Send + Sync {
+    fn record<'a>(&'a self, record: &'a AuditRecord) -> AuditFuture<'a>;
+}
+
+// Appends one JSON object per command to a file
+pub struct JsonLinesAudit {
+    file: Mutex<File>,
+}
+
+impl JsonLinesAudit {
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path).await?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+}
+
+impl AuditSink for JsonLinesAudit {
+    fn record<'a>(&'a self, record: &'a AuditRecord) -> AuditFuture<'a> {
+        Box::pin(async move {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            let mut file = self.file.lock().await;
+            file.write_all(&line).await?;
+            file.flush().await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,575 @@
+use std::collections::HashMap;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use esphome_client_rs::{
+    api::*,
+    commands::{DeviceCommand, DeviceMessage},
+    connection::Connection,
+    device::{api_address, Device},
+    messages::ESPHomeMessage,
+    result::{Error, Result},
+};
+use serde_json::{json, Value};
+
+#[derive(Parser)]
+#[command(
+    name = "esphome-cli",
+    about = "Inspect and control ESPHome devices over the native API"
+)]
+struct Cli {
+    /// Host, host:port, IPv6 address or [IPv6]:port of the device
+    #[arg(short, long, env = "ESPHOME_ADDRESS")]
+    address: String,
+    /// API password of the device
+    #[arg(short, long, env = "ESPHOME_PASSWORD")]
+    password: Option<String>,
+    /// Client name the device shows in its logs
+    #[arg(long, default_value = "esphome-cli")]
+    client_name: String,
+    /// One JSON object per line instead of human readable output
+    #[arg(long)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Device information
+    Info,
+    /// Entities with their metadata
+    Entities {
+        /// Only entities of this domain, e.g. switch
+        #[arg(long)]
+        domain: Option<String>,
+    },
+    /// Stream state changes until interrupted
+    States,
+    /// Stream log lines of the device
+    Logs {
+        /// Most verbose level to receive
+        #[arg(long, value_enum, default_value = "debug")]
+        level: Level,
+        /// Also receive the configuration dump of the device
+        #[arg(long)]
+        dump_config: bool,
+    },
+    /// User defined services with their arguments
+    Services,
+    /// Call a user defined service, e.g. `service start_irrigation minutes=10`
+    Service {
+        /// Name of the service
+        name: String,
+        /// name=value, arrays are comma separated
+        args: Vec<String>,
+    },
+    /// Turn a switch on or off
+    Switch {
+        #[arg(value_enum)]
+        action: OnOff,
+        /// Object id or entity id of the switch
+        id: String,
+    },
+    /// Control a light, e.g. `light set kitchen --brightness 40`
+    Light {
+        #[arg(value_enum)]
+        action: LightAction,
+        /// Object id or entity id of the light
+        id: String,
+        /// Brightness in percent
+        #[arg(long)]
+        brightness: Option<f32>,
+        /// Transition length in milliseconds
+        #[arg(long)]
+        transition: Option<u32>,
+    },
+    /// Control a fan
+    Fan {
+        #[arg(value_enum)]
+        action: LightAction,
+        /// Object id or entity id of the fan
+        id: String,
+        /// Speed level, 1 is the slowest
+        #[arg(long)]
+        speed_level: Option<i32>,
+        /// Turn oscillation on or off
+        #[arg(long)]
+        oscillating: Option<bool>,
+    },
+    /// Open, close or position a cover
+    Cover {
+        #[arg(value_enum)]
+        action: CoverAction,
+        /// Object id or entity id of the cover
+        id: String,
+        /// Position in percent, for `set`
+        #[arg(long)]
+        position: Option<f32>,
+    },
+    /// Change mode or target temperature of a climate device
+    Climate {
+        /// Object id or entity id of the climate device
+        id: String,
+        /// e.g. off, heat, cool, heat_cool, auto
+        #[arg(long)]
+        mode: Option<String>,
+        /// Target temperature
+        #[arg(long)]
+        target: Option<f32>,
+    },
+    /// Set the value of a number
+    Number {
+        /// Object id or entity id of the number
+        id: String,
+        /// New value
+        value: f32,
+    },
+    /// Choose an option of a select
+    Select {
+        /// Object id or entity id of the select
+        id: String,
+        /// Option to choose
+        option: String,
+    },
+    /// Press a button
+    Button {
+        /// Object id or entity id of the button
+        id: String,
+    },
+    /// Lock, unlock or open a lock
+    Lock {
+        #[arg(value_enum)]
+        action: LockAction,
+        /// Object id or entity id of the lock
+        id: String,
+        /// Code required by the lock
+        #[arg(long, env = "ESPHOME_LOCK_CODE")]
+        code: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Level {
+    Error,
+    Warn,
+    Info,
+    Config,
+    Debug,
+    Verbose,
+    VeryVerbose,
+}
+
+impl From<Level> for LogLevel {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Error => LogLevel::Error,
+            Level::Warn => LogLevel::Warn,
+            Level::Info => LogLevel::Info,
+            Level::Config => LogLevel::Config,
+            Level::Debug => LogLevel::Debug,
+            Level::Verbose => LogLevel::Verbose,
+            Level::VeryVerbose => LogLevel::VeryVerbose,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OnOff {
+    On,
+    Off,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LightAction {
+    On,
+    Off,
+    /// Only change the given attributes
+    Set,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CoverAction {
+    Open,
+    Close,
+    Stop,
+    Set,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LockAction {
+    Lock,
+    Unlock,
+    Open,
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::builder().init();
+    let cli = Cli::parse();
+    if let Err(err) = run(cli).await {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let address = api_address(&cli.address);
+    let mut device = Device::new(cli.client_name.clone(), address, cli.password.clone());
+    let mut connection = device.connect().await?;
+    let output = Output { json: cli.json };
+
+    match cli.command {
+        Command::Info => output.info(&device.device_info()?),
+        Command::Entities { domain } => {
+            let mut entities: Vec<_> = device.entities().await.into_values().collect();
+            entities.retain(|e| domain.as_deref().is_none_or(|d| e.domain == d));
+            entities.sort_by_key(|e| e.entity_id());
+            for entity in entities {
+                output.print(
+                    json!({
+                        "entity_id": entity.entity_id(),
+                        "name": entity.name,
+                        "key": entity.key,
+                        "unique_id": entity.unique_id,
+                        "icon": entity.icon,
+                        "category": entity.category.as_str_name(),
+                    }),
+                    format!("{:<40} {:<30} key={}", entity.entity_id(), entity.name, entity.key),
+                );
+            }
+        }
+        Command::States => {
+            send(
+                &mut connection,
+                ESPHomeMessage::SubscribeStatesRequest(SubscribeStatesRequest {}),
+            )
+            .await?;
+            stream(&device, &mut connection, &output).await?;
+        }
+        Command::Logs { level, dump_config } => {
+            let request = SubscribeLogsRequest {
+                level: LogLevel::from(level) as i32,
+                dump_config,
+            };
+            send(&mut connection, ESPHomeMessage::SubscribeLogsRequest(request)).await?;
+            stream(&device, &mut connection, &output).await?;
+        }
+        Command::Services => {
+            let mut services: Vec<_> = device.services().into_values().collect();
+            services.sort_by(|a, b| a.name.cmp(&b.name));
+            for service in services {
+                let args: Vec<(&str, &str)> = service
+                    .args
+                    .iter()
+                    .map(|a| (a.name.as_str(), arg_type_name(a.r#type)))
+                    .collect();
+                let human: Vec<String> = args.iter().map(|(name, t)| format!("{}:{}", name, t)).collect();
+                output.print(
+                    json!({ "name": service.name, "key": service.key, "args": args.iter().map(|(name, t)| json!({ "name": name, "type": t })).collect::<Vec<_>>() }),
+                    format!("{} {}", service.name, human.join(" ")),
+                );
+            }
+        }
+        Command::Service { name, args } => {
+            let service = device
+                .services()
+                .remove(&name)
+                .ok_or_else(|| Error::new(&format!("unknown service {}", name)))?;
+            let request = ExecuteServiceRequest {
+                key: service.key,
+                args: service_args(&service, &args)?,
+            };
+            command(
+                &mut connection,
+                &output,
+                &name,
+                ESPHomeMessage::ExecuteServiceRequest(request),
+            )
+            .await?;
+        }
+        command_line => {
+            let (domain, id) = entity_of(&command_line);
+            let entity_id = format!("{}.{}", domain, id);
+            let key = device
+                .entities()
+                .await
+                .into_values()
+                .find(|e| e.domain == domain && e.id == id)
+                .map(|e| e.key)
+                .ok_or_else(|| Error::new(&format!("unknown entity {}", entity_id)))?;
+            let msg = entity_command(command_line, key)?;
+            command(&mut connection, &output, &entity_id, msg).await?;
+        }
+    }
+    Ok(())
+}
+
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn print(&self, value: Value, human: String) {
+        if self.json {
+            println!("{}", value);
+        } else {
+            println!("{}", human);
+        }
+    }
+
+    fn info(&self, info: &DeviceInfoResponse) {
+        let value = serde_json::to_value(info).unwrap_or_default();
+        if self.json {
+            println!("{}", value);
+            return;
+        }
+        if let Value::Object(fields) = value {
+            for (name, value) in fields {
+                println!("{:<32} {}", name, value);
+            }
+        }
+    }
+}
+
+fn arg_type_name(arg_type: i32) -> &'static str {
+    match ServiceArgType::try_from(arg_type) {
+        Ok(ServiceArgType::Bool) => "bool",
+        Ok(ServiceArgType::Int) => "int",
+        Ok(ServiceArgType::Float) => "float",
+        Ok(ServiceArgType::String) => "string",
+        Ok(ServiceArgType::BoolArray) => "bool[]",
+        Ok(ServiceArgType::IntArray) => "int[]",
+        Ok(ServiceArgType::FloatArray) => "float[]",
+        Ok(ServiceArgType::StringArray) => "string[]",
+        Err(_) => "unknown",
+    }
+}
+
+fn service_args(service: &ListEntitiesServicesResponse, args: &[String]) -> Result<Vec<ExecuteServiceArgument>> {
+    let mut values: HashMap<&str, &str> = HashMap::new();
+    for arg in args {
+        let (name, value) = arg
+            .split_once('=')
+            .ok_or_else(|| Error::new(&format!("expected name=value, got {}", arg)))?;
+        values.insert(name, value);
+    }
+    // the device expects all arguments in the declared order
+    service
+        .args
+        .iter()
+        .map(|a| {
+            let value = values
+                .get(a.name.as_str())
+                .ok_or_else(|| Error::new(&format!("missing argument {}", a.name)))?;
+            service_arg(a.r#type, value).map_err(|_| Error::new(&format!("invalid value for {}: {}", a.name, value)))
+        })
+        .collect()
+}
+
+fn service_arg(arg_type: i32, value: &str) -> std::result::Result<ExecuteServiceArgument, Box<dyn std::error::Error>> {
+    let list = || value.split(',').map(str::trim);
+    let mut arg = ExecuteServiceArgument::default();
+    match ServiceArgType::try_from(arg_type)? {
+        ServiceArgType::Bool => arg.bool = value.parse()?,
+        ServiceArgType::Int => {
+            arg.int = value.parse()?;
+            arg.legacy_int = arg.int;
+        }
+        ServiceArgType::Float => arg.float = value.parse()?,
+        ServiceArgType::String => arg.string = value.to_string(),
+        ServiceArgType::BoolArray => arg.bool_array = list().map(str::parse).collect::<std::result::Result<_, _>>()?,
+        ServiceArgType::IntArray => arg.int_array = list().map(str::parse).collect::<std::result::Result<_, _>>()?,
+        ServiceArgType::FloatArray => {
+            arg.float_array = list().map(str::parse).collect::<std::result::Result<_, _>>()?
+        }
+        ServiceArgType::StringArray => arg.string_array = list().map(str::to_string).collect(),
+    }
+    Ok(arg)
+}
+
+fn entity_of(command: &Command) -> (&'static str, &str) {
+    match command {
+        Command::Switch { id, .. } => ("switch", id),
+        Command::Light { id, .. } => ("light", id),
+        Command::Fan { id, .. } => ("fan", id),
+        Command::Cover { id, .. } => ("cover", id),
+        Command::Climate { id, .. } => ("climate", id),
+        Command::Number { id, .. } => ("number", id),
+        Command::Select { id, .. } => ("select", id),
+        Command::Button { id } => ("button", id),
+        Command::Lock { id, .. } => ("lock", id),
+        _ => unreachable!("not an entity command"),
+    }
+}
+
+fn entity_command(command: Command, key: u32) -> Result<ESPHomeMessage> {
+    let msg = match command {
+        Command::Switch { action, .. } => ESPHomeMessage::SwitchCommandRequest(SwitchCommandRequest {
+            key,
+            state: matches!(action, OnOff::On),
+        }),
+        Command::Light {
+            action,
+            brightness,
+            transition,
+            ..
+        } => ESPHomeMessage::LightCommandRequest(LightCommandRequest {
+            key,
+            has_state: !matches!(action, LightAction::Set),
+            state: matches!(action, LightAction::On),
+            has_brightness: brightness.is_some(),
+            brightness: brightness.unwrap_or_default() / 100.0,
+            has_transition_length: transition.is_some(),
+            transition_length: transition.unwrap_or_default(),
+            ..Default::default()
+        }),
+        Command::Fan {
+            action,
+            speed_level,
+            oscillating,
+            ..
+        } => ESPHomeMessage::FanCommandRequest(FanCommandRequest {
+            key,
+            has_state: !matches!(action, LightAction::Set),
+            state: matches!(action, LightAction::On),
+            has_speed_level: speed_level.is_some(),
+            speed_level: speed_level.unwrap_or_default(),
+            has_oscillating: oscillating.is_some(),
+            oscillating: oscillating.unwrap_or_default(),
+            ..Default::default()
+        }),
+        Command::Cover { action, position, .. } => {
+            let position = match (action, position) {
+                (CoverAction::Open, _) => Some(1.0),
+                (CoverAction::Close, _) => Some(0.0),
+                (CoverAction::Stop, _) => None,
+                (CoverAction::Set, Some(position)) => Some(position / 100.0),
+                (CoverAction::Set, None) => return Err(Error::new("cover set needs --position")),
+            };
+            ESPHomeMessage::CoverCommandRequest(CoverCommandRequest {
+                key,
+                has_position: position.is_some(),
+                position: position.unwrap_or_default(),
+                stop: matches!(action, CoverAction::Stop),
+                ..Default::default()
+            })
+        }
+        Command::Climate { mode, target, .. } => {
+            let mode = mode
+                .map(|m| {
+                    ClimateMode::from_str_name(&format!("CLIMATE_MODE_{}", m.to_uppercase()))
+                        .ok_or_else(|| Error::new(&format!("unknown climate mode {}", m)))
+                })
+                .transpose()?;
+            ESPHomeMessage::ClimateCommandRequest(ClimateCommandRequest {
+                key,
+                has_mode: mode.is_some(),
+                mode: mode.unwrap_or(ClimateMode::Off) as i32,
+                has_target_temperature: target.is_some(),
+                target_temperature: target.unwrap_or_default(),
+                ..Default::default()
+            })
+        }
+        Command::Number { value, .. } => {
+            ESPHomeMessage::NumberCommandRequest(NumberCommandRequest { key, state: value })
+        }
+        Command::Select { option, .. } => {
+            ESPHomeMessage::SelectCommandRequest(SelectCommandRequest { key, state: option })
+        }
+        Command::Button { .. } => ESPHomeMessage::ButtonCommandRequest(ButtonCommandRequest { key }),
+        Command::Lock { action, code, .. } => {
+            let command = match action {
+                LockAction::Lock => LockCommand::LockLock,
+                LockAction::Unlock => LockCommand::LockUnlock,
+                LockAction::Open => LockCommand::LockOpen,
+            };
+            ESPHomeMessage::LockCommandRequest(LockCommandRequest {
+                key,
+                command: command as i32,
+                has_code: code.is_some(),
+                code: code.unwrap_or_default(),
+            })
+        }
+        _ => unreachable!("not an entity command"),
+    };
+    Ok(msg)
+}
+
+async fn send(connection: &mut Connection, msg: ESPHomeMessage) -> Result<()> {
+    connection.send_message(DeviceCommand::SendMessage(Box::new(msg))).await
+}
+
+// sends the command and disconnects, which makes sure it reached the device
+async fn command(connection: &mut Connection, output: &Output, target: &str, msg: ESPHomeMessage) -> Result<()> {
+    let name = msg.name();
+    let fields = msg.fields();
+    send(connection, msg).await?;
+    connection.disconnect().await?;
+    output.print(
+        json!({ "target": target, "command": name, "fields": fields }),
+        format!("sent {} to {}", name, target),
+    );
+    Ok(())
+}
+
+async fn stream(device: &Device, connection: &mut Connection, output: &Output) -> Result<()> {
+    loop {
+        match connection.wait_for_message().await? {
+            DeviceMessage::Disconnected => return Err(Error::new("disconnected")),
+            DeviceMessage::DeviceStateChange(change) => {
+                let (entity_id, mut state) = match device.get_entity(&change.name).await {
+                    Some(mut entity) => {
+                        entity.update_state(change.state.clone());
+                        (entity.entity_id(), entity.masked_state().fields())
+                    }
+                    None => (change.name.clone(), change.state.fields()),
+                };
+                if let Value::Object(fields) = &mut state {
+                    fields.remove("key");
+                }
+                output.print(
+                    json!({ "entity_id": entity_id, "message": change.state.name(), "state": state }),
+                    format!("{:<40} {}", entity_id, state),
+                );
+            }
+            DeviceMessage::Log(log) => {
+                let level = LogLevel::try_from(log.level).unwrap_or(LogLevel::None);
+                let level = level.as_str_name().trim_start_matches("LOG_LEVEL_");
+                output.print(json!({ "level": level, "message": log.message }), log.message.clone());
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn help_is_documented() {
+        Cli::command().debug_assert();
+        let mut cli = Cli::command();
+        for arg in cli.get_arguments().filter(|a| !a.is_positional()) {
+            assert!(arg.get_help().is_some(), "--{} has no help", arg.get_id());
+        }
+        for command in cli.get_subcommands_mut() {
+            let help = command.render_help().to_string();
+            assert!(command.get_about().is_some(), "{} has no help", command.get_name());
+            for arg in command
+                .get_arguments()
+                .filter(|a| a.get_value_parser().possible_values().is_none())
+            {
+                assert!(
+                    arg.get_help().is_some(),
+                    "{} {} has no help",
+                    command.get_name(),
+                    arg.get_id()
+                );
+            }
+            assert!(help.contains(&command.get_about().unwrap().to_string()));
+        }
+    }
+}
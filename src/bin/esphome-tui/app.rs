@@ -0,0 +1,312 @@
+use std::collections::VecDeque;
+
+use esphome_client_rs::{api::*, entity::Entity, messages::ESPHomeMessage};
+use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+use crate::DeviceEvent;
+
+const MAX_LOG_LINES: usize = 1000;
+// percent of the range a light or cover moves with +/-
+const ADJUST_STEP: f32 = 0.1;
+const CLIMATE_STEP: f32 = 0.5;
+
+pub enum Status {
+    Connecting,
+    Connected,
+    Failed(String),
+}
+
+pub struct DeviceView {
+    pub address: String,
+    pub name: Option<String>,
+    pub status: Status,
+    // sorted by entity id, which groups them by domain
+    pub entities: Vec<Entity>,
+    pub logs: VecDeque<(LogLevel, String)>,
+}
+
+pub struct App {
+    pub devices: Vec<DeviceView>,
+    pub selected_device: usize,
+    pub selected_entity: usize,
+    // result of the last command, shown in the status line
+    pub message: Option<String>,
+    commands: Vec<Sender<ESPHomeMessage>>,
+    quit: bool,
+}
+
+impl App {
+    pub fn new(addresses: Vec<String>, commands: Vec<Sender<ESPHomeMessage>>) -> Self {
+        let devices = addresses
+            .into_iter()
+            .map(|address| DeviceView {
+                address,
+                name: None,
+                status: Status::Connecting,
+                entities: Vec::new(),
+                logs: VecDeque::new(),
+            })
+            .collect();
+        Self {
+            devices,
+            selected_device: 0,
+            selected_entity: 0,
+            message: None,
+            commands,
+            quit: false,
+        }
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    pub fn device(&self) -> &DeviceView {
+        &self.devices[self.selected_device]
+    }
+
+    pub fn entity(&self) -> Option<&Entity> {
+        self.device().entities.get(self.selected_entity)
+    }
+
+    pub fn handle_event(&mut self, event: DeviceEvent) {
+        match event {
+            DeviceEvent::Connected { device, name, entities } => {
+                let view = &mut self.devices[device];
+                view.name = Some(name);
+                view.status = Status::Connected;
+                view.entities = entities;
+                if device == self.selected_device {
+                    self.selected_entity = self.selected_entity.min(view.entities.len().saturating_sub(1));
+                }
+            }
+            DeviceEvent::State { device, entity } => {
+                let view = &mut self.devices[device];
+                match view.entities.iter_mut().find(|e| e.key == entity.key) {
+                    Some(e) => *e = *entity,
+                    None => {
+                        view.entities.push(*entity);
+                        view.entities.sort_by_key(|e| e.entity_id());
+                    }
+                }
+            }
+            DeviceEvent::Log { device, level, message } => {
+                let logs = &mut self.devices[device].logs;
+                logs.push_back((level, strip_colors(&message)));
+                if logs.len() > MAX_LOG_LINES {
+                    logs.pop_front();
+                }
+            }
+            DeviceEvent::CommandFailed { device, message } => {
+                self.message = Some(format!("{}: {}", self.devices[device].address, message));
+            }
+            DeviceEvent::Failed { device, message } => self.devices[device].status = Status::Failed(message),
+            DeviceEvent::Disconnected { device } => {
+                self.devices[device].status = Status::Failed("disconnected".to_string())
+            }
+        }
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) {
+        let entities = self.device().entities.len();
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Tab => self.select_device(self.selected_device + 1),
+            KeyCode::BackTab => self.select_device(self.selected_device + self.devices.len() - 1),
+            KeyCode::Down | KeyCode::Char('j') if self.selected_entity + 1 < entities => self.selected_entity += 1,
+            KeyCode::Up | KeyCode::Char('k') => self.selected_entity = self.selected_entity.saturating_sub(1),
+            KeyCode::Enter | KeyCode::Char(' ') => {
+                let command = self.entity().and_then(activate);
+                self.send(command);
+            }
+            KeyCode::Char('+') | KeyCode::Char('=') | KeyCode::Right => {
+                let command = self.entity().and_then(|e| adjust(e, 1.0));
+                self.send(command);
+            }
+            KeyCode::Char('-') | KeyCode::Left => {
+                let command = self.entity().and_then(|e| adjust(e, -1.0));
+                self.send(command);
+            }
+            _ => (),
+        }
+    }
+
+    fn select_device(&mut self, index: usize) {
+        self.selected_device = index % self.devices.len();
+        self.selected_entity = 0;
+    }
+
+    fn send(&mut self, command: Option<ESPHomeMessage>) {
+        let Some(command) = command else {
+            return;
+        };
+        if !matches!(self.device().status, Status::Connected) {
+            self.message = Some("device is not connected".to_string());
+            return;
+        }
+        let name = command.name();
+        self.message = match self.commands[self.selected_device].try_send(command) {
+            Ok(()) => Some(format!("sent {}", name)),
+            Err(TrySendError::Full(_)) => Some("device is busy, try again".to_string()),
+            Err(TrySendError::Closed(_)) => Some("device is not connected".to_string()),
+        };
+    }
+}
+
+// Enter: toggles switches, lights, fans, locks and covers, presses buttons
+fn activate(entity: &Entity) -> Option<ESPHomeMessage> {
+    let key = entity.key;
+    let msg = match &entity.state {
+        ESPHomeMessage::SwitchStateResponse(s) => {
+            ESPHomeMessage::SwitchCommandRequest(SwitchCommandRequest { key, state: !s.state })
+        }
+        ESPHomeMessage::LightStateResponse(s) => ESPHomeMessage::LightCommandRequest(LightCommandRequest {
+            key,
+            has_state: true,
+            state: !s.state,
+            ..Default::default()
+        }),
+        ESPHomeMessage::FanStateResponse(s) => ESPHomeMessage::FanCommandRequest(FanCommandRequest {
+            key,
+            has_state: true,
+            state: !s.state,
+            ..Default::default()
+        }),
+        ESPHomeMessage::LockStateResponse(s) => {
+            let command = if s.state == LockState::Locked as i32 {
+                LockCommand::LockUnlock
+            } else {
+                LockCommand::LockLock
+            };
+            ESPHomeMessage::LockCommandRequest(LockCommandRequest {
+                key,
+                command: command as i32,
+                ..Default::default()
+            })
+        }
+        ESPHomeMessage::CoverStateResponse(s) => ESPHomeMessage::CoverCommandRequest(CoverCommandRequest {
+            key,
+            has_position: true,
+            position: if s.position > 0.0 { 0.0 } else { 1.0 },
+            ..Default::default()
+        }),
+        _ if entity.domain == "button" => ESPHomeMessage::ButtonCommandRequest(ButtonCommandRequest { key }),
+        _ => return None,
+    };
+    Some(msg)
+}
+
+// +/-: steps numbers, brightness, cover position and climate target, cycles select options
+fn adjust(entity: &Entity, direction: f32) -> Option<ESPHomeMessage> {
+    let key = entity.key;
+    let msg = match (&entity.state, &entity.definition) {
+        (ESPHomeMessage::NumberStateResponse(s), ESPHomeMessage::ListEntitiesNumberResponse(d)) => {
+            let state = (s.state + direction * d.step).clamp(d.min_value, d.max_value);
+            ESPHomeMessage::NumberCommandRequest(NumberCommandRequest { key, state })
+        }
+        (ESPHomeMessage::SelectStateResponse(s), ESPHomeMessage::ListEntitiesSelectResponse(d)) => {
+            if d.options.is_empty() {
+                return None;
+            }
+            let current = d.options.iter().position(|o| *o == s.state).unwrap_or(0);
+            let count = d.options.len();
+            let next = if direction > 0.0 {
+                (current + 1) % count
+            } else {
+                (current + count - 1) % count
+            };
+            ESPHomeMessage::SelectCommandRequest(SelectCommandRequest {
+                key,
+                state: d.options[next].clone(),
+            })
+        }
+        (ESPHomeMessage::LightStateResponse(s), _) => ESPHomeMessage::LightCommandRequest(LightCommandRequest {
+            key,
+            has_state: true,
+            state: true,
+            has_brightness: true,
+            brightness: (s.brightness + direction * ADJUST_STEP).clamp(0.0, 1.0),
+            ..Default::default()
+        }),
+        (ESPHomeMessage::CoverStateResponse(s), _) => ESPHomeMessage::CoverCommandRequest(CoverCommandRequest {
+            key,
+            has_position: true,
+            position: (s.position + direction * ADJUST_STEP).clamp(0.0, 1.0),
+            ..Default::default()
+        }),
+        (ESPHomeMessage::ClimateStateResponse(s), _) => ESPHomeMessage::ClimateCommandRequest(ClimateCommandRequest {
+            key,
+            has_target_temperature: true,
+            target_temperature: s.target_temperature + direction * CLIMATE_STEP,
+            ..Default::default()
+        }),
+        _ => return None,
+    };
+    Some(msg)
+}
+
+// the firmware colors its log lines with ANSI escape sequences
+fn strip_colors(message: &str) -> String {
+    let mut text = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // skip up to and including the final letter of the sequence
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            text.push(c);
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::crossterm::event::KeyModifiers;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn switch() -> Entity {
+        let mut entity = Entity::from_definition(
+            "switch",
+            ESPHomeMessage::ListEntitiesSwitchResponse(ListEntitiesSwitchResponse {
+                object_id: "pump".to_string(),
+                key: 7,
+                name: "Pump".to_string(),
+                ..Default::default()
+            }),
+        );
+        entity.update_state(ESPHomeMessage::SwitchStateResponse(SwitchStateResponse {
+            key: 7,
+            state: false,
+        }));
+        entity
+    }
+
+    #[test]
+    fn commands_need_a_connection() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut app = App::new(vec!["pump.local".to_string()], vec![tx]);
+        app.handle_event(DeviceEvent::Connected {
+            device: 0,
+            name: "pump".to_string(),
+            entities: vec![switch()],
+        });
+        let enter = KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE);
+
+        app.handle_key(enter);
+        assert!(matches!(rx.try_recv(), Ok(ESPHomeMessage::SwitchCommandRequest(c)) if c.state));
+
+        app.handle_event(DeviceEvent::Disconnected { device: 0 });
+        app.handle_key(enter);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(app.message.as_deref(), Some("device is not connected"));
+    }
+}
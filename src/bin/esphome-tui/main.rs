@@ -0,0 +1,244 @@
+use std::time::Duration;
+
+use clap::Parser;
+use esphome_client_rs::{
+    api::{LogLevel, SubscribeLogsRequest, SubscribeStatesRequest},
+    commands::{DeviceCommand, DeviceMessage},
+    connection::Connection,
+    device::{api_address, Device},
+    entity::Entity,
+    messages::ESPHomeMessage,
+    result::Result,
+};
+use ratatui::crossterm::event::{self, Event, KeyEvent, KeyEventKind};
+use tokio::sync::mpsc;
+
+mod app;
+mod ui;
+
+use app::App;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Parser)]
+#[command(
+    name = "esphome-tui",
+    about = "Dashboard of the entities and logs of ESPHome devices"
+)]
+struct Args {
+    /// Host, host:port, IPv6 address or [IPv6]:port of each device
+    #[arg(required = true, env = "ESPHOME_ADDRESS", value_delimiter = ',')]
+    devices: Vec<String>,
+    /// API password, used for all devices
+    #[arg(short, long, env = "ESPHOME_PASSWORD")]
+    password: Option<String>,
+    /// Client name the devices show in their logs
+    #[arg(long, default_value = "esphome-tui")]
+    client_name: String,
+    /// error, warn, info, config, debug, verbose or very_verbose
+    #[arg(long, default_value = "debug", value_parser = parse_log_level)]
+    log_level: LogLevel,
+}
+
+fn parse_log_level(level: &str) -> std::result::Result<LogLevel, String> {
+    LogLevel::from_str_name(&format!("LOG_LEVEL_{}", level.to_uppercase()))
+        .ok_or_else(|| format!("unknown log level {}", level))
+}
+
+// what the device tasks report to the dashboard
+#[derive(Debug)]
+pub enum DeviceEvent {
+    Connected {
+        device: usize,
+        name: String,
+        entities: Vec<Entity>,
+    },
+    State {
+        device: usize,
+        entity: Box<Entity>,
+    },
+    Log {
+        device: usize,
+        level: LogLevel,
+        message: String,
+    },
+    // the connection was lost or could not be established, it is retried
+    Failed {
+        device: usize,
+        message: String,
+    },
+    CommandFailed {
+        device: usize,
+        message: String,
+    },
+    Disconnected {
+        device: usize,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let (events_tx, mut events) = mpsc::channel(256);
+    let mut commands = Vec::new();
+    for (index, address) in args.devices.iter().enumerate() {
+        let address = api_address(address);
+        let (commands_tx, commands_rx) = mpsc::channel(16);
+        commands.push(commands_tx);
+        let device = Device::new(args.client_name.clone(), address, args.password.clone());
+        tokio::spawn(run_device(
+            index,
+            device,
+            args.log_level,
+            events_tx.clone(),
+            commands_rx,
+        ));
+    }
+
+    // crossterm only offers blocking reads without its event-stream feature
+    let (keys_tx, mut keys) = mpsc::channel::<KeyEvent>(16);
+    std::thread::spawn(move || loop {
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                if keys_tx.blocking_send(key).is_err() {
+                    break;
+                }
+            }
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    });
+
+    let mut app = App::new(args.devices.clone(), commands);
+    let mut terminal = ratatui::init();
+    let result = loop {
+        if let Err(err) = terminal.draw(|frame| ui::draw(frame, &app)) {
+            break Err(err.into());
+        }
+        tokio::select! {
+            Some(event) = events.recv() => app.handle_event(event),
+            Some(key) = keys.recv() => app.handle_key(key),
+        }
+        if app.should_quit() {
+            break Ok(());
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+// keeps one device connected, reconnecting after errors
+async fn run_device(
+    index: usize,
+    mut device: Device,
+    log_level: LogLevel,
+    events: mpsc::Sender<DeviceEvent>,
+    mut commands: mpsc::Receiver<ESPHomeMessage>,
+) {
+    loop {
+        let result = match device.connect().await {
+            Ok(connection) => serve_device(index, &device, connection, log_level, &events, &mut commands).await,
+            Err(err) => Err(err),
+        };
+        let event = match result {
+            Ok(()) => DeviceEvent::Disconnected { device: index },
+            Err(err) => DeviceEvent::Failed {
+                device: index,
+                message: err.to_string(),
+            },
+        };
+        if events.send(event).await.is_err() {
+            return;
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+        // commands sent while the connection went down are not replayed after reconnecting
+        let mut dropped = 0;
+        while commands.try_recv().is_ok() {
+            dropped += 1;
+        }
+        if dropped > 0 {
+            let event = DeviceEvent::CommandFailed {
+                device: index,
+                message: format!("dropped {} commands, device is not connected", dropped),
+            };
+            if events.send(event).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn serve_device(
+    index: usize,
+    device: &Device,
+    mut connection: Connection,
+    log_level: LogLevel,
+    events: &mpsc::Sender<DeviceEvent>,
+    commands: &mut mpsc::Receiver<ESPHomeMessage>,
+) -> Result<()> {
+    let mut entities: Vec<Entity> = device.entities().await.into_values().collect();
+    entities.sort_by_key(|e| e.entity_id());
+    let name = device.device_info()?.friendly_name;
+    events
+        .send(DeviceEvent::Connected {
+            device: index,
+            name,
+            entities,
+        })
+        .await?;
+
+    let subscriptions = [
+        ESPHomeMessage::SubscribeStatesRequest(SubscribeStatesRequest {}),
+        ESPHomeMessage::SubscribeLogsRequest(SubscribeLogsRequest {
+            level: log_level as i32,
+            dump_config: false,
+        }),
+    ];
+    for msg in subscriptions {
+        connection
+            .send_message(DeviceCommand::SendMessage(Box::new(msg)))
+            .await?;
+    }
+
+    loop {
+        tokio::select! {
+            msg = connection.wait_for_message() => {
+                let event = match msg? {
+                    DeviceMessage::Disconnected => return Ok(()),
+                    DeviceMessage::DeviceStateChange(change) => match device.get_entity(&change.name).await {
+                        Some(entity) => DeviceEvent::State { device: index, entity: Box::new(entity) },
+                        None => continue,
+                    },
+                    DeviceMessage::Log(log) => DeviceEvent::Log {
+                        device: index,
+                        level: LogLevel::try_from(log.level).unwrap_or(LogLevel::None),
+                        message: log.message,
+                    },
+                    _ => continue,
+                };
+                events.send(event).await?;
+            }
+            Some(msg) = commands.recv() => {
+                // a denied or invalid command does not end the connection
+                if let Err(err) = connection.send_message(DeviceCommand::SendMessage(Box::new(msg))).await {
+                    events.send(DeviceEvent::CommandFailed { device: index, message: err.to_string() }).await?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn help_is_documented() {
+        Args::command().debug_assert();
+        for arg in Args::command().get_arguments() {
+            assert!(arg.get_help().is_some(), "{} has no help", arg.get_id());
+        }
+    }
+}
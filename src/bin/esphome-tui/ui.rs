@@ -0,0 +1,146 @@
+use esphome_client_rs::{api::*, entity::Entity, messages::ESPHomeMessage};
+use ratatui::{
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, Paragraph, Tabs},
+    Frame,
+};
+
+use crate::app::{App, Status};
+
+const LOG_PANE_HEIGHT: u16 = 12;
+const HELP: &str = "q quit  tab device  ↑↓ select  enter toggle/press  +/- adjust";
+
+pub fn draw(frame: &mut Frame, app: &App) {
+    let [tabs_area, entities_area, logs_area, status_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(5),
+        Constraint::Length(LOG_PANE_HEIGHT),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let titles = app.devices.iter().map(|d| {
+        let (mark, color) = match d.status {
+            Status::Connecting => ("…", Color::Yellow),
+            Status::Connected => ("●", Color::Green),
+            Status::Failed(_) => ("✕", Color::Red),
+        };
+        Line::from(vec![
+            Span::styled(mark, Style::default().fg(color)),
+            Span::raw(format!(" {}", d.name.as_deref().unwrap_or(&d.address))),
+        ])
+    });
+    frame.render_widget(Tabs::new(titles).select(app.selected_device), tabs_area);
+
+    let device = app.device();
+    let title = match &device.status {
+        Status::Failed(message) => format!(" {} - {} ", device.address, message),
+        _ => format!(" {} ", device.address),
+    };
+
+    // a header line before the first entity of each domain
+    let mut items = Vec::new();
+    let mut selected = None;
+    let mut domain = "";
+    for (index, entity) in device.entities.iter().enumerate() {
+        if entity.domain != domain {
+            domain = entity.domain;
+            items.push(ListItem::new(Line::from(domain.bold().fg(Color::Cyan))));
+        }
+        if index == app.selected_entity {
+            selected = Some(items.len());
+        }
+        items.push(ListItem::new(Line::from(vec![
+            Span::raw(format!("  {:<32} ", entity.name)),
+            Span::styled(state_text(entity), Style::default().fg(Color::White)),
+        ])));
+    }
+    let list = List::new(items)
+        .block(Block::bordered().title(title))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    let mut state = ListState::default().with_selected(selected);
+    frame.render_stateful_widget(list, entities_area, &mut state);
+
+    // the newest lines that fit into the pane
+    let visible = logs_area.height.saturating_sub(2) as usize;
+    let lines: Vec<Line> = device
+        .logs
+        .iter()
+        .skip(device.logs.len().saturating_sub(visible))
+        .map(|(level, message)| Line::styled(message.as_str(), Style::default().fg(log_color(*level))))
+        .collect();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" logs ")),
+        logs_area,
+    );
+
+    let status = match &app.message {
+        Some(message) => format!("{}  |  {}", message, HELP),
+        None => HELP.to_string(),
+    };
+    frame.render_widget(Paragraph::new(status).dim(), status_area);
+}
+
+fn log_color(level: LogLevel) -> Color {
+    match level {
+        LogLevel::Error => Color::Red,
+        LogLevel::Warn => Color::Yellow,
+        LogLevel::Info => Color::Green,
+        LogLevel::Config => Color::Magenta,
+        LogLevel::Debug => Color::Cyan,
+        _ => Color::Gray,
+    }
+}
+
+fn on_off(state: bool) -> String {
+    if state { "on" } else { "off" }.to_string()
+}
+
+fn state_text(entity: &Entity) -> String {
+    let state = entity.masked_state();
+    match (state.as_ref(), &entity.definition) {
+        (ESPHomeMessage::None, _) => "-".to_string(),
+        (ESPHomeMessage::SensorStateResponse(s), ESPHomeMessage::ListEntitiesSensorResponse(d)) => {
+            if s.missing_state {
+                return "unknown".to_string();
+            }
+            let decimals = d.accuracy_decimals.max(0) as usize;
+            format!("{:.*} {}", decimals, s.state, d.unit_of_measurement)
+        }
+        (ESPHomeMessage::NumberStateResponse(s), ESPHomeMessage::ListEntitiesNumberResponse(d)) => {
+            format!("{} {}", s.state, d.unit_of_measurement)
+        }
+        (ESPHomeMessage::BinarySensorStateResponse(s), _) => on_off(s.state),
+        (ESPHomeMessage::SwitchStateResponse(s), _) => on_off(s.state),
+        (ESPHomeMessage::FanStateResponse(s), _) => on_off(s.state),
+        (ESPHomeMessage::LightStateResponse(s), _) if s.state => format!("on {:.0}%", s.brightness * 100.0),
+        (ESPHomeMessage::LightStateResponse(_), _) => on_off(false),
+        (ESPHomeMessage::CoverStateResponse(s), _) => format!("{:.0}%", s.position * 100.0),
+        (ESPHomeMessage::ClimateStateResponse(s), _) => {
+            let mode = ClimateMode::try_from(s.mode).unwrap_or_default();
+            format!(
+                "{} {:.1} → {:.1}",
+                mode.as_str_name().trim_start_matches("CLIMATE_MODE_").to_lowercase(),
+                s.current_temperature,
+                s.target_temperature
+            )
+        }
+        (ESPHomeMessage::LockStateResponse(s), _) => {
+            let state = LockState::try_from(s.state).unwrap_or_default();
+            state.as_str_name().trim_start_matches("LOCK_STATE_").to_lowercase()
+        }
+        (ESPHomeMessage::SelectStateResponse(s), _) => s.state.clone(),
+        (ESPHomeMessage::TextSensorStateResponse(s), _) => s.state.clone(),
+        (ESPHomeMessage::TextStateResponse(s), _) => s.state.clone(),
+        (state, _) => {
+            // compact form of the remaining state messages
+            let mut fields = state.fields();
+            if let serde_json::Value::Object(fields) = &mut fields {
+                fields.remove("key");
+            }
+            fields.to_string()
+        }
+    }
+}
@@ -0,0 +1,235 @@
+pub mod decoders;
+pub mod gatt;
+pub mod slots;
+
+use bytes::Bytes;
+use uuid::Uuid;
+
+use crate::api::{BluetoothLeAdvertisementResponse, BluetoothLeRawAdvertisement, BluetoothServiceData};
+
+// Bluetooth base UUID (0000xxxx-0000-1000-8000-00805f9b34fb) used to expand 16 and 32-bit UUIDs
+const BASE_UUID: u128 = 0x0000_0000_0000_1000_8000_0080_5f9b_34fb;
+
+// bluetooth_proxy_feature_flags reported in DeviceInfoResponse
+pub const FEATURE_PASSIVE_SCAN: u32 = 1 << 0;
+pub const FEATURE_ACTIVE_CONNECTIONS: u32 = 1 << 1;
+pub const FEATURE_REMOTE_CACHING: u32 = 1 << 2;
+pub const FEATURE_PAIRING: u32 = 1 << 3;
+pub const FEATURE_CACHE_CLEARING: u32 = 1 << 4;
+pub const FEATURE_RAW_ADVERTISEMENTS: u32 = 1 << 5;
+
+// AD types used when parsing raw advertisements
+const AD_INCOMPLETE_UUID16: u8 = 0x02;
+const AD_COMPLETE_UUID16: u8 = 0x03;
+const AD_INCOMPLETE_UUID32: u8 = 0x04;
+const AD_COMPLETE_UUID32: u8 = 0x05;
+const AD_INCOMPLETE_UUID128: u8 = 0x06;
+const AD_COMPLETE_UUID128: u8 = 0x07;
+const AD_SHORT_NAME: u8 = 0x08;
+const AD_COMPLETE_NAME: u8 = 0x09;
+const AD_SERVICE_DATA_UUID16: u8 = 0x16;
+const AD_SERVICE_DATA_UUID32: u8 = 0x20;
+const AD_SERVICE_DATA_UUID128: u8 = 0x21;
+const AD_MANUFACTURER_DATA: u8 = 0xFF;
+
+pub fn uuid_from_u16(short: u16) -> Uuid {
+    uuid_from_u32(short as u32)
+}
+
+pub fn uuid_from_u32(short: u32) -> Uuid {
+    Uuid::from_u128(BASE_UUID | ((short as u128) << 96))
+}
+
+// returns the 16-bit short form of an UUID built on top of the Bluetooth base UUID
+pub fn uuid_to_u16(uuid: &Uuid) -> Option<u16> {
+    let value = uuid.as_u128();
+    if value & !(0xFFFF_u128 << 96) == BASE_UUID {
+        Some((value >> 96) as u16)
+    } else {
+        None
+    }
+}
+
+// ESPHome sends UUIDs either in short ("0xFCD2") or in full 128-bit form
+pub fn parse_uuid(value: &str) -> Option<Uuid> {
+    if let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        return u32::from_str_radix(hex, 16).ok().map(uuid_from_u32);
+    }
+    Uuid::parse_str(value).ok()
+}
+
+pub fn format_mac(address: u64) -> String {
+    let bytes = address.to_be_bytes();
+    bytes[2..]
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+pub fn parse_mac(mac: &str) -> Option<u64> {
+    let parts: Vec<&str> = mac.split(':').collect();
+    if parts.len() != 6 {
+        return None;
+    }
+    parts.iter().try_fold(0u64, |address, part| {
+        u8::from_str_radix(part, 16).ok().map(|b| (address << 8) | b as u64)
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceData {
+    pub uuid: Uuid,
+    pub data: Bytes,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManufacturerData {
+    pub company_id: u16,
+    pub data: Bytes,
+}
+
+// Advertisement normalised from either parsed or raw advertisements sent by the proxy
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Advertisement {
+    pub address: u64,
+    pub address_type: u32,
+    pub rssi: i32,
+    pub name: String,
+    pub service_uuids: Vec<Uuid>,
+    pub service_data: Vec<ServiceData>,
+    pub manufacturer_data: Vec<ManufacturerData>,
+}
+
+impl Advertisement {
+    pub fn mac(&self) -> String {
+        format_mac(self.address)
+    }
+
+    pub fn service_data(&self, uuid: u16) -> Option<&[u8]> {
+        let uuid = uuid_from_u16(uuid);
+        self.service_data
+            .iter()
+            .find(|sd| sd.uuid == uuid)
+            .map(|sd| sd.data.as_ref())
+    }
+
+    pub fn manufacturer_data(&self, company_id: u16) -> Option<&[u8]> {
+        self.manufacturer_data
+            .iter()
+            .find(|md| md.company_id == company_id)
+            .map(|md| md.data.as_ref())
+    }
+
+    pub fn from_raw(raw: &BluetoothLeRawAdvertisement) -> Self {
+        let mut adv = Advertisement {
+            address: raw.address,
+            address_type: raw.address_type,
+            rssi: raw.rssi,
+            ..Default::default()
+        };
+
+        // AD structures are sliced out of the raw data without copying
+        let data = &raw.data;
+        let mut pos = 0;
+        while data.len() - pos >= 2 {
+            let len = data[pos] as usize;
+            if len == 0 || len >= data.len() - pos {
+                break;
+            }
+            let ad_type = data[pos + 1];
+            let value = data.slice(pos + 2..=pos + len);
+            pos += len + 1;
+
+            match ad_type {
+                AD_INCOMPLETE_UUID16 | AD_COMPLETE_UUID16 => adv.service_uuids.extend(
+                    value
+                        .chunks_exact(2)
+                        .map(|c| uuid_from_u16(u16::from_le_bytes([c[0], c[1]]))),
+                ),
+                AD_INCOMPLETE_UUID32 | AD_COMPLETE_UUID32 => adv.service_uuids.extend(
+                    value
+                        .chunks_exact(4)
+                        .map(|c| uuid_from_u32(u32::from_le_bytes([c[0], c[1], c[2], c[3]]))),
+                ),
+                AD_INCOMPLETE_UUID128 | AD_COMPLETE_UUID128 => {
+                    adv.service_uuids.extend(value.chunks_exact(16).map(uuid_from_le_bytes))
+                }
+                // complete name wins over the shortened one
+                AD_SHORT_NAME | AD_COMPLETE_NAME if adv.name.is_empty() || ad_type == AD_COMPLETE_NAME => {
+                    adv.name = String::from_utf8_lossy(&value).to_string();
+                }
+                AD_SERVICE_DATA_UUID16 if value.len() >= 2 => adv.service_data.push(ServiceData {
+                    uuid: uuid_from_u16(u16::from_le_bytes([value[0], value[1]])),
+                    data: value.slice(2..),
+                }),
+                AD_SERVICE_DATA_UUID32 if value.len() >= 4 => adv.service_data.push(ServiceData {
+                    uuid: uuid_from_u32(u32::from_le_bytes([value[0], value[1], value[2], value[3]])),
+                    data: value.slice(4..),
+                }),
+                AD_SERVICE_DATA_UUID128 if value.len() >= 16 => adv.service_data.push(ServiceData {
+                    uuid: uuid_from_le_bytes(&value[..16]),
+                    data: value.slice(16..),
+                }),
+                AD_MANUFACTURER_DATA if value.len() >= 2 => adv.manufacturer_data.push(ManufacturerData {
+                    company_id: u16::from_le_bytes([value[0], value[1]]),
+                    data: value.slice(2..),
+                }),
+                _ => (),
+            }
+        }
+        adv
+    }
+}
+
+impl From<&BluetoothLeAdvertisementResponse> for Advertisement {
+    fn from(resp: &BluetoothLeAdvertisementResponse) -> Self {
+        Advertisement {
+            address: resp.address,
+            address_type: resp.address_type,
+            rssi: resp.rssi,
+            name: resp.name.clone(),
+            service_uuids: resp.service_uuids.iter().filter_map(|u| parse_uuid(u)).collect(),
+            service_data: resp
+                .service_data
+                .iter()
+                .filter_map(|sd| {
+                    parse_uuid(&sd.uuid).map(|uuid| ServiceData {
+                        uuid,
+                        data: service_data_bytes(sd),
+                    })
+                })
+                .collect(),
+            manufacturer_data: resp
+                .manufacturer_data
+                .iter()
+                .filter_map(|md| {
+                    parse_uuid(&md.uuid)
+                        .and_then(|uuid| uuid_to_u16(&uuid))
+                        .map(|company_id| ManufacturerData {
+                            company_id,
+                            data: service_data_bytes(md),
+                        })
+                })
+                .collect(),
+        }
+    }
+}
+
+// proxies older than API 1.7 only fill the deprecated `legacy_data`
+#[allow(deprecated)]
+fn service_data_bytes(sd: &BluetoothServiceData) -> Bytes {
+    if sd.data.is_empty() && !sd.legacy_data.is_empty() {
+        sd.legacy_data.iter().map(|b| *b as u8).collect::<Vec<u8>>().into()
+    } else {
+        sd.data.clone()
+    }
+}
+
+fn uuid_from_le_bytes(bytes: &[u8]) -> Uuid {
+    let mut be = [0u8; 16];
+    for (i, b) in bytes.iter().take(16).enumerate() {
+        be[15 - i] = *b;
+    }
+    Uuid::from_bytes(be)
+}
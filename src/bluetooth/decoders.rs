@@ -0,0 +1,984 @@
+use std::collections::HashMap;
+
+use aes::Aes128;
+use ccm::{
+    aead::{generic_array::GenericArray, Aead, KeyInit, Payload},
+    consts::{U12, U4},
+    Ccm,
+};
+use log::debug;
+use uuid::Uuid;
+
+use crate::{
+    bluetooth::{format_mac, Advertisement},
+    result::{Error, Result},
+};
+
+const BTHOME_UUID: u16 = 0xFCD2;
+const MIBEACON_UUID: u16 = 0xFE95;
+const EDDYSTONE_UUID: u16 = 0xFEAA;
+const APPLE_COMPANY_ID: u16 = 0x004C;
+const GOVEE_COMPANY_ID: u16 = 0xEC88;
+const GOVEE_H5179_COMPANY_ID: u16 = 0x8801;
+
+type MiBeaconCcm = Ccm<Aes128, U4, U12>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MeasurementValue {
+    Temperature(f32),  // °C
+    Humidity(f32),     // %
+    Battery(u8),       // %
+    Voltage(f32),      // V
+    Pressure(f32),     // hPa
+    Illuminance(f32),  // lx
+    Moisture(f32),     // %
+    Conductivity(f32), // µS/cm
+    Co2(f32),          // ppm
+    Tvoc(f32),         // µg/m3
+    Pm25(f32),         // µg/m3
+    Pm10(f32),         // µg/m3
+    Power(f32),        // W
+    Energy(f32),       // kWh
+    PacketId(u8),
+    Button(u8),
+    Binary {
+        name: &'static str,
+        value: bool,
+    },
+    Other {
+        name: &'static str,
+        value: f32,
+    },
+    IBeacon {
+        uuid: Uuid,
+        major: u16,
+        minor: u16,
+        tx_power: i8,
+    },
+    EddystoneUid {
+        tx_power: i8,
+        namespace: [u8; 10],
+        instance: [u8; 6],
+    },
+    EddystoneUrl {
+        tx_power: i8,
+        url: String,
+    },
+    EddystoneTelemetry {
+        advertisement_count: u32,
+        uptime_seconds: f32,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Measurement {
+    pub address: u64,
+    pub rssi: i32,
+    pub decoder: &'static str,
+    pub value: MeasurementValue,
+}
+
+impl Measurement {
+    pub fn mac(&self) -> String {
+        format_mac(self.address)
+    }
+}
+
+pub trait PayloadDecoder: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn decode(&self, adv: &Advertisement) -> Vec<MeasurementValue>;
+}
+
+#[derive(Default)]
+pub struct DecoderRegistry {
+    decoders: Vec<Box<dyn PayloadDecoder>>,
+}
+
+impl DecoderRegistry {
+    pub fn new() -> Self {
+        Self { decoders: Vec::new() }
+    }
+
+    // registry with all built-in decoders, bindkeys are used for encrypted MiBeacon payloads
+    pub fn builtin(bindkeys: HashMap<u64, [u8; 16]>) -> Self {
+        let mut registry = Self::new();
+        registry.register(BTHomeDecoder);
+        registry.register(MiBeaconDecoder::new(bindkeys));
+        registry.register(GoveeDecoder);
+        registry.register(InkbirdDecoder);
+        registry.register(IBeaconDecoder);
+        registry.register(EddystoneDecoder);
+        registry
+    }
+
+    pub fn register<D: PayloadDecoder + 'static>(&mut self, decoder: D) {
+        self.decoders.push(Box::new(decoder));
+    }
+
+    pub fn decode(&self, adv: &Advertisement) -> Vec<Measurement> {
+        self.decoders
+            .iter()
+            .flat_map(|decoder| {
+                decoder.decode(adv).into_iter().map(|value| Measurement {
+                    address: adv.address,
+                    rssi: adv.rssi,
+                    decoder: decoder.name(),
+                    value,
+                })
+            })
+            .collect()
+    }
+}
+
+pub fn parse_bindkey(key: &str) -> Result<[u8; 16]> {
+    if key.len() != 32 || !key.is_ascii() {
+        return Err(Error::new("bindkey must be 32 hex characters"));
+    }
+    let mut bindkey = [0u8; 16];
+    for (i, b) in bindkey.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&key[i * 2..i * 2 + 2], 16).map_err(|_| Error::new("invalid bindkey"))?;
+    }
+    Ok(bindkey)
+}
+
+fn u16_le(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+fn i16_le(data: &[u8]) -> i16 {
+    i16::from_le_bytes([data[0], data[1]])
+}
+
+fn u24_le(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], 0])
+}
+
+fn u32_le(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+// ==================== BTHOME V2 ====================
+
+pub struct BTHomeDecoder;
+
+#[derive(Clone, Copy)]
+enum BTHomeFormat {
+    U8,
+    U16,
+    S16,
+    U24,
+    U32,
+    Binary,
+    Skip(usize),
+}
+
+// object id -> (format, factor, name); objects must be parsed in order so unknown ids stop parsing
+fn bthome_object(id: u8) -> Option<(BTHomeFormat, f32, &'static str)> {
+    use BTHomeFormat::*;
+    let object = match id {
+        0x00 => (U8, 1.0, "packet_id"),
+        0x01 => (U8, 1.0, "battery"),
+        0x02 => (S16, 0.01, "temperature"),
+        0x03 => (U16, 0.01, "humidity"),
+        0x04 => (U24, 0.01, "pressure"),
+        0x05 => (U24, 0.01, "illuminance"),
+        0x06 => (U16, 0.01, "mass_kg"),
+        0x07 => (U16, 0.01, "mass_lb"),
+        0x08 => (S16, 0.01, "dewpoint"),
+        0x09 => (U8, 1.0, "count"),
+        0x0A => (U24, 0.001, "energy"),
+        0x0B => (U24, 0.01, "power"),
+        0x0C => (U16, 0.001, "voltage"),
+        0x0D => (U16, 1.0, "pm25"),
+        0x0E => (U16, 1.0, "pm10"),
+        0x0F => (Binary, 1.0, "generic_boolean"),
+        0x10 => (Binary, 1.0, "power"),
+        0x11 => (Binary, 1.0, "opening"),
+        0x12 => (U16, 1.0, "co2"),
+        0x13 => (U16, 1.0, "tvoc"),
+        0x14 => (U16, 0.01, "moisture"),
+        0x15 => (Binary, 1.0, "battery_low"),
+        0x16 => (Binary, 1.0, "battery_charging"),
+        0x17 => (Binary, 1.0, "carbon_monoxide"),
+        0x18 => (Binary, 1.0, "cold"),
+        0x19 => (Binary, 1.0, "connectivity"),
+        0x1A => (Binary, 1.0, "door"),
+        0x1B => (Binary, 1.0, "garage_door"),
+        0x1C => (Binary, 1.0, "gas"),
+        0x1D => (Binary, 1.0, "heat"),
+        0x1E => (Binary, 1.0, "light"),
+        0x1F => (Binary, 1.0, "lock"),
+        0x20 => (Binary, 1.0, "moisture"),
+        0x21 => (Binary, 1.0, "motion"),
+        0x22 => (Binary, 1.0, "moving"),
+        0x23 => (Binary, 1.0, "occupancy"),
+        0x24 => (Binary, 1.0, "plug"),
+        0x25 => (Binary, 1.0, "presence"),
+        0x26 => (Binary, 1.0, "problem"),
+        0x27 => (Binary, 1.0, "running"),
+        0x28 => (Binary, 1.0, "safety"),
+        0x29 => (Binary, 1.0, "smoke"),
+        0x2A => (Binary, 1.0, "sound"),
+        0x2B => (Binary, 1.0, "tamper"),
+        0x2C => (Binary, 1.0, "vibration"),
+        0x2D => (Binary, 1.0, "window"),
+        0x2E => (U8, 1.0, "humidity"),
+        0x2F => (U8, 1.0, "moisture"),
+        0x3A => (U8, 1.0, "button"),
+        0x3C => (Skip(2), 1.0, "dimmer"),
+        0x3D => (U16, 1.0, "count"),
+        0x3E => (U32, 1.0, "count"),
+        0x3F => (S16, 0.1, "rotation"),
+        0x40 => (U16, 1.0, "distance_mm"),
+        0x41 => (U16, 0.1, "distance_m"),
+        0x42 => (U24, 0.001, "duration"),
+        0x43 => (U16, 0.001, "current"),
+        0x44 => (U16, 0.01, "speed"),
+        0x45 => (S16, 0.1, "temperature"),
+        0x46 => (U8, 0.1, "uv_index"),
+        0x47 => (U16, 0.1, "volume_l"),
+        0x48 => (U16, 1.0, "volume_ml"),
+        0x49 => (U16, 0.001, "volume_flow_rate"),
+        0x4A => (U16, 0.1, "voltage"),
+        0x4B => (U24, 0.001, "gas"),
+        0x4C => (U32, 0.001, "gas"),
+        0x4D => (U32, 0.001, "energy"),
+        0x4E => (U32, 0.001, "volume"),
+        0x4F => (U32, 0.001, "water"),
+        0x50 => (U32, 1.0, "timestamp"),
+        0x51 => (U16, 0.001, "acceleration"),
+        0x52 => (U16, 0.001, "gyroscope"),
+        0x55 => (U32, 0.001, "volume_storage"),
+        _ => return None,
+    };
+    Some(object)
+}
+
+impl PayloadDecoder for BTHomeDecoder {
+    fn name(&self) -> &'static str {
+        "bthome"
+    }
+
+    fn decode(&self, adv: &Advertisement) -> Vec<MeasurementValue> {
+        let mut values = Vec::new();
+        let Some(data) = adv.service_data(BTHOME_UUID) else {
+            return values;
+        };
+        if data.is_empty() {
+            return values;
+        }
+
+        let device_info = data[0];
+        if device_info >> 5 != 2 {
+            debug!("unsupported BTHome version from {}", adv.mac());
+            return values;
+        }
+        if device_info & 0x01 != 0 {
+            debug!("encrypted BTHome payload from {} is not supported", adv.mac());
+            return values;
+        }
+
+        let mut data = &data[1..];
+        while !data.is_empty() {
+            let id = data[0];
+            data = &data[1..];
+            let Some((format, factor, name)) = bthome_object(id) else {
+                debug!("unknown BTHome object {:#04x} from {}", id, adv.mac());
+                break;
+            };
+            let len = match format {
+                BTHomeFormat::U8 | BTHomeFormat::Binary => 1,
+                BTHomeFormat::U16 | BTHomeFormat::S16 => 2,
+                BTHomeFormat::U24 => 3,
+                BTHomeFormat::U32 => 4,
+                BTHomeFormat::Skip(len) => len,
+            };
+            if data.len() < len {
+                break;
+            }
+            let raw = match format {
+                BTHomeFormat::U8 => data[0] as f32,
+                BTHomeFormat::U16 => u16_le(data) as f32,
+                BTHomeFormat::S16 => i16_le(data) as f32,
+                BTHomeFormat::U24 => u24_le(data) as f32,
+                BTHomeFormat::U32 => u32_le(data) as f32,
+                BTHomeFormat::Binary => {
+                    values.push(MeasurementValue::Binary {
+                        name,
+                        value: data[0] != 0,
+                    });
+                    data = &data[len..];
+                    continue;
+                }
+                BTHomeFormat::Skip(_) => {
+                    data = &data[len..];
+                    continue;
+                }
+            };
+            data = &data[len..];
+
+            let value = raw * factor;
+            values.push(match (id, name) {
+                (0x00, _) => MeasurementValue::PacketId(raw as u8),
+                (0x01, _) => MeasurementValue::Battery(raw as u8),
+                (0x3A, _) => MeasurementValue::Button(raw as u8),
+                (_, "temperature") => MeasurementValue::Temperature(value),
+                (_, "humidity") => MeasurementValue::Humidity(value),
+                (_, "pressure") => MeasurementValue::Pressure(value),
+                (_, "illuminance") => MeasurementValue::Illuminance(value),
+                (_, "energy") => MeasurementValue::Energy(value),
+                (_, "power") => MeasurementValue::Power(value),
+                (_, "voltage") => MeasurementValue::Voltage(value),
+                (_, "pm25") => MeasurementValue::Pm25(value),
+                (_, "pm10") => MeasurementValue::Pm10(value),
+                (_, "co2") => MeasurementValue::Co2(value),
+                (_, "tvoc") => MeasurementValue::Tvoc(value),
+                (_, "moisture") => MeasurementValue::Moisture(value),
+                _ => MeasurementValue::Other { name, value },
+            });
+        }
+        values
+    }
+}
+
+// ==================== XIAOMI MIBEACON ====================
+
+pub struct MiBeaconDecoder {
+    bindkeys: HashMap<u64, [u8; 16]>,
+}
+
+impl MiBeaconDecoder {
+    pub fn new(bindkeys: HashMap<u64, [u8; 16]>) -> Self {
+        Self { bindkeys }
+    }
+
+    // MiBeacon v4/v5 AES-CCM, nonce is mac + product id + frame counter + extended counter
+    fn decrypt(&self, adv: &Advertisement, mac: &[u8], header: &[u8], payload: &[u8]) -> Option<Vec<u8>> {
+        let Some(key) = self.bindkeys.get(&adv.address) else {
+            debug!("no bindkey for encrypted MiBeacon from {}", adv.mac());
+            return None;
+        };
+        // payload = ciphertext | 3 bytes extended counter | 4 bytes MIC
+        if payload.len() < 7 {
+            return None;
+        }
+        let (ciphertext, rest) = payload.split_at(payload.len() - 7);
+        let (ext_counter, mic) = rest.split_at(3);
+
+        let mut nonce = Vec::with_capacity(12);
+        nonce.extend_from_slice(mac);
+        nonce.extend_from_slice(&header[2..5]);
+        nonce.extend_from_slice(ext_counter);
+
+        let mut msg = ciphertext.to_vec();
+        msg.extend_from_slice(mic);
+
+        let cipher = MiBeaconCcm::new(GenericArray::from_slice(key));
+        match cipher.decrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                msg: &msg,
+                aad: &[0x11],
+            },
+        ) {
+            Ok(plain) => Some(plain),
+            Err(_) => {
+                debug!("unable to decrypt MiBeacon from {}, wrong bindkey?", adv.mac());
+                None
+            }
+        }
+    }
+}
+
+impl PayloadDecoder for MiBeaconDecoder {
+    fn name(&self) -> &'static str {
+        "mibeacon"
+    }
+
+    fn decode(&self, adv: &Advertisement) -> Vec<MeasurementValue> {
+        let mut values = Vec::new();
+        let Some(data) = adv.service_data(MIBEACON_UUID) else {
+            return values;
+        };
+        if data.len() < 5 {
+            return values;
+        }
+
+        let frame_control = u16_le(data);
+        let encrypted = frame_control & 0x0008 != 0;
+        let mac_included = frame_control & 0x0010 != 0;
+        let capability_included = frame_control & 0x0020 != 0;
+        let object_included = frame_control & 0x0040 != 0;
+        let version = frame_control >> 12;
+
+        if !object_included {
+            return values;
+        }
+
+        let mut i = 5;
+        // MAC in the frame is little endian, same as in the nonce
+        let mac: Vec<u8> = if mac_included {
+            if data.len() < 11 {
+                return values;
+            }
+            i = 11;
+            data[5..11].to_vec()
+        } else {
+            adv.address.to_le_bytes()[..6].to_vec()
+        };
+        if capability_included {
+            if data.len() <= i {
+                return values;
+            }
+            let capability = data[i];
+            i += 1;
+            if capability & 0x20 != 0 {
+                i += 2;
+            }
+        }
+        if data.len() <= i {
+            return values;
+        }
+
+        let objects = if encrypted {
+            if version < 4 {
+                debug!(
+                    "legacy MiBeacon v{} encryption from {} is not supported",
+                    version,
+                    adv.mac()
+                );
+                return values;
+            }
+            match self.decrypt(adv, &mac, data, &data[i..]) {
+                Some(plain) => plain,
+                None => return values,
+            }
+        } else {
+            data[i..].to_vec()
+        };
+
+        let mut objects = objects.as_slice();
+        while objects.len() >= 3 {
+            let object_type = u16_le(objects);
+            let len = objects[2] as usize;
+            if objects.len() < 3 + len {
+                break;
+            }
+            let value = &objects[3..3 + len];
+            objects = &objects[3 + len..];
+
+            match (object_type, len) {
+                (0x1004, 2) => values.push(MeasurementValue::Temperature(i16_le(value) as f32 / 10.0)),
+                (0x1006, 2) => values.push(MeasurementValue::Humidity(u16_le(value) as f32 / 10.0)),
+                (0x1007, 3) => values.push(MeasurementValue::Illuminance(u24_le(value) as f32)),
+                (0x1008, 1) => values.push(MeasurementValue::Moisture(value[0] as f32)),
+                (0x1009, 2) => values.push(MeasurementValue::Conductivity(u16_le(value) as f32)),
+                (0x100A, 1) | (0x4803, 1) => values.push(MeasurementValue::Battery(value[0])),
+                (0x100D, 4) => {
+                    values.push(MeasurementValue::Temperature(i16_le(value) as f32 / 10.0));
+                    values.push(MeasurementValue::Humidity(u16_le(&value[2..]) as f32 / 10.0));
+                }
+                (0x4C01, 4) => values.push(MeasurementValue::Temperature(f32::from_le_bytes([
+                    value[0], value[1], value[2], value[3],
+                ]))),
+                (0x4C02, 1) => values.push(MeasurementValue::Humidity(value[0] as f32)),
+                _ => debug!("unknown MiBeacon object {:#06x} from {}", object_type, adv.mac()),
+            }
+        }
+        values
+    }
+}
+
+// ==================== GOVEE ====================
+
+pub struct GoveeDecoder;
+
+impl PayloadDecoder for GoveeDecoder {
+    fn name(&self) -> &'static str {
+        "govee"
+    }
+
+    fn decode(&self, adv: &Advertisement) -> Vec<MeasurementValue> {
+        let mut values = Vec::new();
+        if let Some(data) = adv.manufacturer_data(GOVEE_COMPANY_ID) {
+            match data.len() {
+                // H5072, H5075, H5101, H5102, H5177: temperature and humidity packed in 3 bytes
+                6 => {
+                    let packed = u32::from_be_bytes([0, data[1], data[2], data[3]]);
+                    let negative = packed & 0x80_0000 != 0;
+                    let packed = packed & 0x7F_FFFF;
+                    let temperature = (packed / 1000) as f32 / 10.0;
+                    values.push(MeasurementValue::Temperature(if negative {
+                        -temperature
+                    } else {
+                        temperature
+                    }));
+                    values.push(MeasurementValue::Humidity((packed % 1000) as f32 / 10.0));
+                    values.push(MeasurementValue::Battery(data[4]));
+                }
+                // H5074, H5051
+                7 => {
+                    values.push(MeasurementValue::Temperature(i16_le(&data[1..]) as f32 / 100.0));
+                    values.push(MeasurementValue::Humidity(u16_le(&data[3..]) as f32 / 100.0));
+                    values.push(MeasurementValue::Battery(data[5]));
+                }
+                _ => (),
+            }
+        }
+        if let Some(data) = adv.manufacturer_data(GOVEE_H5179_COMPANY_ID) {
+            if data.len() == 9 {
+                values.push(MeasurementValue::Temperature(i16_le(&data[4..]) as f32 / 100.0));
+                values.push(MeasurementValue::Humidity(u16_le(&data[6..]) as f32 / 100.0));
+                values.push(MeasurementValue::Battery(data[8]));
+            }
+        }
+        values
+    }
+}
+
+// ==================== INKBIRD ====================
+
+pub struct InkbirdDecoder;
+
+impl PayloadDecoder for InkbirdDecoder {
+    fn name(&self) -> &'static str {
+        "inkbird"
+    }
+
+    // IBS-TH1/TH2 put the temperature into the company id field of manufacturer data
+    fn decode(&self, adv: &Advertisement) -> Vec<MeasurementValue> {
+        let mut values = Vec::new();
+        if adv.name != "sps" && adv.name != "tps" {
+            return values;
+        }
+        for md in adv.manufacturer_data.iter().filter(|md| md.data.len() == 7) {
+            values.push(MeasurementValue::Temperature(md.company_id as i16 as f32 / 100.0));
+            if adv.name == "sps" {
+                values.push(MeasurementValue::Humidity(u16_le(&md.data) as f32 / 100.0));
+            }
+            values.push(MeasurementValue::Battery(md.data[5]));
+        }
+        values
+    }
+}
+
+// ==================== IBEACON ====================
+
+pub struct IBeaconDecoder;
+
+impl PayloadDecoder for IBeaconDecoder {
+    fn name(&self) -> &'static str {
+        "ibeacon"
+    }
+
+    fn decode(&self, adv: &Advertisement) -> Vec<MeasurementValue> {
+        match adv.manufacturer_data(APPLE_COMPANY_ID) {
+            Some(data) if data.len() == 23 && data[0] == 0x02 && data[1] == 0x15 => {
+                let mut uuid = [0u8; 16];
+                uuid.copy_from_slice(&data[2..18]);
+                vec![MeasurementValue::IBeacon {
+                    uuid: Uuid::from_bytes(uuid),
+                    major: u16::from_be_bytes([data[18], data[19]]),
+                    minor: u16::from_be_bytes([data[20], data[21]]),
+                    tx_power: data[22] as i8,
+                }]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+// ==================== EDDYSTONE ====================
+
+pub struct EddystoneDecoder;
+
+const EDDYSTONE_URL_SCHEMES: [&str; 4] = ["http://www.", "https://www.", "http://", "https://"];
+const EDDYSTONE_URL_EXPANSIONS: [&str; 14] = [
+    ".com/", ".org/", ".edu/", ".net/", ".info/", ".biz/", ".gov/", ".com", ".org", ".edu", ".net", ".info", ".biz",
+    ".gov",
+];
+
+impl PayloadDecoder for EddystoneDecoder {
+    fn name(&self) -> &'static str {
+        "eddystone"
+    }
+
+    fn decode(&self, adv: &Advertisement) -> Vec<MeasurementValue> {
+        let mut values = Vec::new();
+        let Some(data) = adv.service_data(EDDYSTONE_UUID) else {
+            return values;
+        };
+        match data.first() {
+            Some(0x00) if data.len() >= 18 => {
+                let mut namespace = [0u8; 10];
+                let mut instance = [0u8; 6];
+                namespace.copy_from_slice(&data[2..12]);
+                instance.copy_from_slice(&data[12..18]);
+                values.push(MeasurementValue::EddystoneUid {
+                    tx_power: data[1] as i8,
+                    namespace,
+                    instance,
+                });
+            }
+            Some(0x10) if data.len() >= 3 => {
+                let Some(scheme) = EDDYSTONE_URL_SCHEMES.get(data[2] as usize) else {
+                    return values;
+                };
+                let mut url = scheme.to_string();
+                for b in &data[3..] {
+                    match EDDYSTONE_URL_EXPANSIONS.get(*b as usize) {
+                        Some(expansion) => url.push_str(expansion),
+                        None => url.push(*b as char),
+                    }
+                }
+                values.push(MeasurementValue::EddystoneUrl {
+                    tx_power: data[1] as i8,
+                    url,
+                });
+            }
+            // only unencrypted TLM (version 0) is supported
+            Some(0x20) if data.len() >= 14 && data[1] == 0 => {
+                let battery = u16::from_be_bytes([data[2], data[3]]);
+                if battery != 0 {
+                    values.push(MeasurementValue::Voltage(battery as f32 / 1000.0));
+                }
+                let temperature = i16::from_be_bytes([data[4], data[5]]);
+                if temperature != i16::MIN {
+                    values.push(MeasurementValue::Temperature(temperature as f32 / 256.0));
+                }
+                values.push(MeasurementValue::EddystoneTelemetry {
+                    advertisement_count: u32::from_be_bytes([data[6], data[7], data[8], data[9]]),
+                    uptime_seconds: u32::from_be_bytes([data[10], data[11], data[12], data[13]]) as f32 / 10.0,
+                });
+            }
+            _ => (),
+        }
+        values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bluetooth::{uuid_from_u16, ManufacturerData, ServiceData};
+
+    const ADDRESS: u64 = 0xA4C1_3812_3456;
+
+    fn with_service_data(uuid: u16, data: &[u8]) -> Advertisement {
+        Advertisement {
+            address: ADDRESS,
+            service_data: vec![ServiceData {
+                uuid: uuid_from_u16(uuid),
+                data: data.to_vec().into(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn with_manufacturer_data(company_id: u16, data: &[u8]) -> Advertisement {
+        Advertisement {
+            address: ADDRESS,
+            manufacturer_data: vec![ManufacturerData {
+                company_id,
+                data: data.to_vec().into(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    // floats are compared at the resolution of the sensors
+    fn assert_readings(actual: Vec<MeasurementValue>, expected: Vec<MeasurementValue>) {
+        assert_eq!(format!("{:.3?}", actual), format!("{:.3?}", expected));
+    }
+
+    #[test]
+    fn bthome_temperature_and_humidity() {
+        // example of the BTHome v2 format specification
+        let adv = with_service_data(BTHOME_UUID, &[0x40, 0x02, 0xCA, 0x09, 0x03, 0xBF, 0x13]);
+        assert_readings(
+            BTHomeDecoder.decode(&adv),
+            vec![MeasurementValue::Temperature(25.06), MeasurementValue::Humidity(50.55)],
+        );
+    }
+
+    #[test]
+    fn bthome_objects_of_every_size() {
+        let adv = with_service_data(
+            BTHOME_UUID,
+            &[
+                0x40, 0x00, 0x09, 0x01, 0x61, 0x04, 0x13, 0x8A, 0x01, 0x0C, 0xB8, 0x0B, 0x21, 0x01, 0x3A, 0x02, 0x3E,
+                0x01, 0x00, 0x00, 0x00,
+            ],
+        );
+        assert_readings(
+            BTHomeDecoder.decode(&adv),
+            vec![
+                MeasurementValue::PacketId(9),
+                MeasurementValue::Battery(97),
+                MeasurementValue::Pressure(1008.83),
+                MeasurementValue::Voltage(3.0),
+                MeasurementValue::Binary {
+                    name: "motion",
+                    value: true,
+                },
+                MeasurementValue::Button(2),
+                MeasurementValue::Other {
+                    name: "count",
+                    value: 1.0,
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn bthome_stops_at_unknown_or_truncated_objects() {
+        let adv = with_service_data(BTHOME_UUID, &[0x40, 0x01, 0x61, 0xFF, 0x01, 0x50]);
+        assert_readings(BTHomeDecoder.decode(&adv), vec![MeasurementValue::Battery(97)]);
+        let adv = with_service_data(BTHOME_UUID, &[0x40, 0x01, 0x61, 0x02, 0xCA]);
+        assert_readings(BTHomeDecoder.decode(&adv), vec![MeasurementValue::Battery(97)]);
+    }
+
+    #[test]
+    fn bthome_ignores_encrypted_and_other_versions() {
+        let encrypted = with_service_data(BTHOME_UUID, &[0x41, 0x01, 0x61]);
+        assert!(BTHomeDecoder.decode(&encrypted).is_empty());
+        let version_1 = with_service_data(BTHOME_UUID, &[0x20, 0x01, 0x61]);
+        assert!(BTHomeDecoder.decode(&version_1).is_empty());
+    }
+
+    // MiBeacon v5 frame of a LYWSD03MMC: frame control, product id 0x055B, frame counter, MAC
+    fn mibeacon_header(frame_control: u16) -> Vec<u8> {
+        let mut data = frame_control.to_le_bytes().to_vec();
+        data.extend_from_slice(&[0x5B, 0x05, 0x2A]);
+        data.extend_from_slice(&ADDRESS.to_le_bytes()[..6]);
+        data
+    }
+
+    #[test]
+    fn mibeacon_plain_objects() {
+        let mut data = mibeacon_header(0x5050);
+        data.extend_from_slice(&[0x0D, 0x10, 0x04, 0xE8, 0x00, 0xF4, 0x01, 0x0A, 0x10, 0x01, 0x5D]);
+        let adv = with_service_data(MIBEACON_UUID, &data);
+        assert_readings(
+            MiBeaconDecoder::new(HashMap::new()).decode(&adv),
+            vec![
+                MeasurementValue::Temperature(23.2),
+                MeasurementValue::Humidity(50.0),
+                MeasurementValue::Battery(93),
+            ],
+        );
+    }
+
+    #[test]
+    fn mibeacon_decrypts_with_bindkey() {
+        let bindkey = parse_bindkey("e9ea895fac7cca6d30532432a516f3a8").unwrap();
+        let ext_counter = [0x01, 0x00, 0x00];
+        let mut data = mibeacon_header(0x5858);
+
+        // nonce: MAC, product id, frame counter, extended counter
+        let mut nonce = ADDRESS.to_le_bytes()[..6].to_vec();
+        nonce.extend_from_slice(&data[2..5]);
+        nonce.extend_from_slice(&ext_counter);
+        let sealed = MiBeaconCcm::new(GenericArray::from_slice(&bindkey))
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: &[0x06, 0x10, 0x02, 0xC5, 0x01],
+                    aad: &[0x11],
+                },
+            )
+            .unwrap();
+        let (ciphertext, mic) = sealed.split_at(sealed.len() - 4);
+        data.extend_from_slice(ciphertext);
+        data.extend_from_slice(&ext_counter);
+        data.extend_from_slice(mic);
+        let adv = with_service_data(MIBEACON_UUID, &data);
+
+        let decoder = MiBeaconDecoder::new(HashMap::from([(ADDRESS, bindkey)]));
+        assert_readings(decoder.decode(&adv), vec![MeasurementValue::Humidity(45.3)]);
+
+        let wrong_key = parse_bindkey("00000000000000000000000000000000").unwrap();
+        assert!(MiBeaconDecoder::new(HashMap::from([(ADDRESS, wrong_key)]))
+            .decode(&adv)
+            .is_empty());
+        assert!(MiBeaconDecoder::new(HashMap::new()).decode(&adv).is_empty());
+    }
+
+    #[test]
+    fn bindkey_parsing() {
+        assert_eq!(parse_bindkey("000102030405060708090a0b0c0d0e0f").unwrap()[15], 0x0F);
+        assert!(parse_bindkey("0001").is_err());
+        assert!(parse_bindkey("zz0102030405060708090a0b0c0d0e0f").is_err());
+        // 32 bytes, but not 32 characters
+        assert!(parse_bindkey("é00102030405060708090a0b0c0d0e0").is_err());
+    }
+
+    #[test]
+    fn govee_packed_reading() {
+        // H5075: 224716 -> 22.4 °C, 71.6 %
+        let adv = with_manufacturer_data(GOVEE_COMPANY_ID, &[0x00, 0x03, 0x6D, 0xCC, 0x64, 0x00]);
+        assert_readings(
+            GoveeDecoder.decode(&adv),
+            vec![
+                MeasurementValue::Temperature(22.4),
+                MeasurementValue::Humidity(71.6),
+                MeasurementValue::Battery(100),
+            ],
+        );
+        // sign bit set: -5.3 °C, 40.2 %
+        let adv = with_manufacturer_data(GOVEE_COMPANY_ID, &[0x00, 0x80, 0xD0, 0x9A, 0x32, 0x00]);
+        assert_readings(
+            GoveeDecoder.decode(&adv),
+            vec![
+                MeasurementValue::Temperature(-5.3),
+                MeasurementValue::Humidity(40.2),
+                MeasurementValue::Battery(50),
+            ],
+        );
+    }
+
+    #[test]
+    fn govee_little_endian_readings() {
+        let adv = with_manufacturer_data(GOVEE_COMPANY_ID, &[0x00, 0x2C, 0x09, 0x6C, 0x13, 0x5A, 0x02]);
+        assert_readings(
+            GoveeDecoder.decode(&adv),
+            vec![
+                MeasurementValue::Temperature(23.48),
+                MeasurementValue::Humidity(49.72),
+                MeasurementValue::Battery(90),
+            ],
+        );
+        let adv = with_manufacturer_data(
+            GOVEE_H5179_COMPANY_ID,
+            &[0xEC, 0x00, 0x01, 0x01, 0x0A, 0x09, 0xB8, 0x11, 0x64],
+        );
+        assert_readings(
+            GoveeDecoder.decode(&adv),
+            vec![
+                MeasurementValue::Temperature(23.14),
+                MeasurementValue::Humidity(45.36),
+                MeasurementValue::Battery(100),
+            ],
+        );
+    }
+
+    #[test]
+    fn inkbird_temperature_in_company_id() {
+        let mut adv = Advertisement {
+            name: "sps".to_string(),
+            manufacturer_data: vec![ManufacturerData {
+                company_id: 0x0915,
+                data: vec![0x88, 0x13, 0x00, 0x00, 0x00, 0x55, 0x08].into(),
+            }],
+            ..Default::default()
+        };
+        assert_readings(
+            InkbirdDecoder.decode(&adv),
+            vec![
+                MeasurementValue::Temperature(23.25),
+                MeasurementValue::Humidity(50.0),
+                MeasurementValue::Battery(85),
+            ],
+        );
+        // tps only measures temperature, below zero here
+        adv.name = "tps".to_string();
+        adv.manufacturer_data[0].company_id = 0xFF38;
+        assert_readings(
+            InkbirdDecoder.decode(&adv),
+            vec![MeasurementValue::Temperature(-2.0), MeasurementValue::Battery(85)],
+        );
+        adv.name = "other".to_string();
+        assert!(InkbirdDecoder.decode(&adv).is_empty());
+    }
+
+    #[test]
+    fn ibeacon() {
+        let mut data = vec![0x02, 0x15];
+        data.extend_from_slice(&[
+            0xFD, 0xA5, 0x06, 0x93, 0xA4, 0xE2, 0x4F, 0xB1, 0xAF, 0xCF, 0xC6, 0xEB, 0x07, 0x64, 0x78, 0x25,
+        ]);
+        data.extend_from_slice(&[0x27, 0x11, 0x00, 0x2A, 0xC5]);
+        let adv = with_manufacturer_data(APPLE_COMPANY_ID, &data);
+        assert_eq!(
+            IBeaconDecoder.decode(&adv),
+            vec![MeasurementValue::IBeacon {
+                uuid: Uuid::parse_str("fda50693-a4e2-4fb1-afcf-c6eb07647825").unwrap(),
+                major: 10001,
+                minor: 42,
+                tx_power: -59,
+            }]
+        );
+        // other Apple advertisements
+        let adv = with_manufacturer_data(APPLE_COMPANY_ID, &[0x10, 0x05, 0x01, 0x18, 0x44, 0x00, 0x00]);
+        assert!(IBeaconDecoder.decode(&adv).is_empty());
+    }
+
+    #[test]
+    fn eddystone_uid() {
+        let mut data = vec![0x00, 0xEE];
+        data.extend_from_slice(&[0x8B, 0x0C, 0xA7, 0x50, 0xE7, 0xA7, 0x4E, 0x14, 0xBD, 0x99]);
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        let adv = with_service_data(EDDYSTONE_UUID, &data);
+        assert_eq!(
+            EddystoneDecoder.decode(&adv),
+            vec![MeasurementValue::EddystoneUid {
+                tx_power: -18,
+                namespace: [0x8B, 0x0C, 0xA7, 0x50, 0xE7, 0xA7, 0x4E, 0x14, 0xBD, 0x99],
+                instance: [0x00, 0x00, 0x00, 0x00, 0x00, 0x01],
+            }]
+        );
+    }
+
+    #[test]
+    fn eddystone_url() {
+        let mut data = vec![0x10, 0xEB, 0x01];
+        data.extend_from_slice(b"esphome");
+        data.extend_from_slice(&[0x08, b'/']);
+        let adv = with_service_data(EDDYSTONE_UUID, &data);
+        assert_eq!(
+            EddystoneDecoder.decode(&adv),
+            vec![MeasurementValue::EddystoneUrl {
+                tx_power: -21,
+                url: "https://www.esphome.org/".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn eddystone_telemetry() {
+        let adv = with_service_data(
+            EDDYSTONE_UUID,
+            &[
+                0x20, 0x00, 0x0B, 0xB8, 0x17, 0x80, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x27, 0x10,
+            ],
+        );
+        assert_readings(
+            EddystoneDecoder.decode(&adv),
+            vec![
+                MeasurementValue::Voltage(3.0),
+                MeasurementValue::Temperature(23.5),
+                MeasurementValue::EddystoneTelemetry {
+                    advertisement_count: 256,
+                    uptime_seconds: 1000.0,
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn registry_tags_measurements_with_decoder_and_address() {
+        let mut adv = with_service_data(BTHOME_UUID, &[0x40, 0x01, 0x61]);
+        adv.rssi = -70;
+        let measurements = DecoderRegistry::builtin(HashMap::new()).decode(&adv);
+        assert_eq!(
+            measurements,
+            vec![Measurement {
+                address: ADDRESS,
+                rssi: -70,
+                decoder: "bthome",
+                value: MeasurementValue::Battery(97),
+            }]
+        );
+        assert_eq!(measurements[0].mac(), "A4:C1:38:12:34:56");
+    }
+}
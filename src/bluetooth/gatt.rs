@@ -0,0 +1,843 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use log::{debug, warn};
+use tokio::{
+    sync::{mpsc, oneshot, Mutex},
+    time::timeout,
+};
+use uuid::Uuid;
+
+use crate::{
+    api::*,
+    bluetooth::{format_mac, FEATURE_CACHE_CLEARING, FEATURE_PAIRING, FEATURE_REMOTE_CACHING},
+    connection::CommandSender,
+    messages::ESPHomeMessage,
+    result::{Error, ErrorKind, Result},
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const NOTIFICATION_QUEUE: usize = 32;
+
+// characteristic properties as reported by the proxy
+pub const PROPERTY_BROADCAST: u32 = 0x01;
+pub const PROPERTY_READ: u32 = 0x02;
+pub const PROPERTY_WRITE_WITHOUT_RESPONSE: u32 = 0x04;
+pub const PROPERTY_WRITE: u32 = 0x08;
+pub const PROPERTY_NOTIFY: u32 = 0x10;
+pub const PROPERTY_INDICATE: u32 = 0x20;
+
+// proxy sends UUIDs as two uint64 values, most significant half first
+fn uuid_from_pair(uuid: &[u64]) -> Uuid {
+    match uuid {
+        [high, low] => Uuid::from_u64_pair(*high, *low),
+        _ => Uuid::nil(),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GattDescriptor {
+    pub uuid: Uuid,
+    pub handle: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GattCharacteristic {
+    pub uuid: Uuid,
+    pub handle: u32,
+    pub properties: u32,
+    pub descriptors: Vec<GattDescriptor>,
+}
+
+impl GattCharacteristic {
+    pub fn has_property(&self, property: u32) -> bool {
+        self.properties & property != 0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GattService {
+    pub uuid: Uuid,
+    pub handle: u32,
+    pub characteristics: Vec<GattCharacteristic>,
+}
+
+impl From<&BluetoothGattService> for GattService {
+    fn from(service: &BluetoothGattService) -> Self {
+        GattService {
+            uuid: uuid_from_pair(&service.uuid),
+            handle: service.handle,
+            characteristics: service
+                .characteristics
+                .iter()
+                .map(|c| GattCharacteristic {
+                    uuid: uuid_from_pair(&c.uuid),
+                    handle: c.handle,
+                    properties: c.properties,
+                    descriptors: c
+                        .descriptors
+                        .iter()
+                        .map(|d| GattDescriptor {
+                            uuid: uuid_from_pair(&d.uuid),
+                            handle: d.handle,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GattOperation {
+    Read,
+    Write,
+    Notify,
+}
+
+type GattWaiter = oneshot::Sender<Result<Bytes>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum DeviceOperation {
+    Connection,
+    Pairing,
+    Unpairing,
+    ClearCache,
+}
+
+// outcome of a BluetoothDeviceRequest as reported by the matching response
+#[derive(Clone, Copy, Debug)]
+struct DeviceResult {
+    success: bool,
+    error: i32,
+    mtu: u32,
+}
+
+struct ServiceDiscovery {
+    services: Vec<GattService>,
+    waiter: oneshot::Sender<Result<Vec<GattService>>>,
+}
+
+// Pending GATT requests shared between the BLE clients and the device reader task
+#[derive(Default)]
+pub(crate) struct GattRequests {
+    pending: HashMap<(u64, u32), VecDeque<(GattOperation, GattWaiter)>>,
+    devices: HashMap<(u64, DeviceOperation), Vec<oneshot::Sender<Result<DeviceResult>>>>,
+    discoveries: HashMap<u64, ServiceDiscovery>,
+    notifications: HashMap<(u64, u32), mpsc::Sender<Bytes>>,
+}
+
+impl GattRequests {
+    fn resolve(&mut self, address: u64, handle: u32, operation: GattOperation, result: Result<Bytes>) {
+        let Some(queue) = self.pending.get_mut(&(address, handle)) else {
+            debug!("no pending request for {} handle {}", format_mac(address), handle);
+            return;
+        };
+        // waiters which timed out are still queued, skip them
+        queue.retain(|(_, waiter)| !waiter.is_closed());
+        if let Some(pos) = queue.iter().position(|(op, _)| *op == operation) {
+            if let Some((_, waiter)) = queue.remove(pos) {
+                let _ = waiter.send(result);
+            }
+        }
+        if queue.is_empty() {
+            self.pending.remove(&(address, handle));
+        }
+    }
+
+    fn fail_all(&mut self, address: u64, error: i32) {
+        let keys: Vec<(u64, u32)> = self.pending.keys().filter(|(a, _)| *a == address).cloned().collect();
+        for key in keys {
+            for (_, waiter) in self.pending.remove(&key).unwrap_or_default() {
+                let _ = waiter.send(Err(Error::with_kind(
+                    ErrorKind::BluetoothConnection { address, error },
+                    "bluetooth device disconnected",
+                )));
+            }
+        }
+        if let Some(discovery) = self.discoveries.remove(&address) {
+            let _ = discovery.waiter.send(Err(Error::with_kind(
+                ErrorKind::BluetoothConnection { address, error },
+                "bluetooth device disconnected",
+            )));
+        }
+        self.notifications.retain(|(a, _), _| *a != address);
+    }
+
+    // fails every request once the connection to the proxy is gone instead of letting them time out
+    pub(crate) fn fail_all_disconnected(&mut self) {
+        let disconnected = || Error::with_kind(ErrorKind::Disconnected, "bluetooth proxy disconnected");
+        for (_, waiter) in self.pending.drain().flat_map(|(_, queue)| queue) {
+            let _ = waiter.send(Err(disconnected()));
+        }
+        for waiter in self.devices.drain().flat_map(|(_, waiters)| waiters) {
+            let _ = waiter.send(Err(disconnected()));
+        }
+        for (_, discovery) in self.discoveries.drain() {
+            let _ = discovery.waiter.send(Err(disconnected()));
+        }
+        self.notifications.clear();
+    }
+
+    fn resolve_device(&mut self, address: u64, operation: DeviceOperation, result: DeviceResult) {
+        for waiter in self.devices.remove(&(address, operation)).unwrap_or_default() {
+            let _ = waiter.send(Ok(result));
+        }
+        // requests which timed out are never answered
+        self.devices.retain(|_, waiters| {
+            waiters.retain(|waiter| !waiter.is_closed());
+            !waiters.is_empty()
+        });
+        self.discoveries.retain(|_, discovery| !discovery.waiter.is_closed());
+    }
+
+    // returns true when the message was consumed by a pending request
+    pub(crate) fn handle_message(&mut self, msg: &ESPHomeMessage) -> bool {
+        match msg {
+            ESPHomeMessage::BluetoothDeviceConnectionResponse(m) => {
+                if !m.connected {
+                    self.fail_all(m.address, m.error);
+                }
+                let result = DeviceResult {
+                    success: m.connected,
+                    error: m.error,
+                    mtu: m.mtu,
+                };
+                self.resolve_device(m.address, DeviceOperation::Connection, result);
+            }
+            ESPHomeMessage::BluetoothDevicePairingResponse(m) => {
+                let result = DeviceResult {
+                    success: m.paired,
+                    error: m.error,
+                    mtu: 0,
+                };
+                self.resolve_device(m.address, DeviceOperation::Pairing, result);
+            }
+            ESPHomeMessage::BluetoothDeviceUnpairingResponse(m) => {
+                let result = DeviceResult {
+                    success: m.success,
+                    error: m.error,
+                    mtu: 0,
+                };
+                self.resolve_device(m.address, DeviceOperation::Unpairing, result);
+            }
+            ESPHomeMessage::BluetoothDeviceClearCacheResponse(m) => {
+                let result = DeviceResult {
+                    success: m.success,
+                    error: m.error,
+                    mtu: 0,
+                };
+                self.resolve_device(m.address, DeviceOperation::ClearCache, result);
+            }
+            ESPHomeMessage::BluetoothGattGetServicesResponse(m) => {
+                if let Some(discovery) = self.discoveries.get_mut(&m.address) {
+                    discovery.services.extend(m.services.iter().map(GattService::from));
+                }
+            }
+            ESPHomeMessage::BluetoothGattGetServicesDoneResponse(m) => {
+                if let Some(discovery) = self.discoveries.remove(&m.address) {
+                    let _ = discovery.waiter.send(Ok(discovery.services));
+                }
+            }
+            ESPHomeMessage::BluetoothGattReadResponse(m) => {
+                self.resolve(m.address, m.handle, GattOperation::Read, Ok(m.data.clone()));
+            }
+            ESPHomeMessage::BluetoothGattWriteResponse(m) => {
+                self.resolve(m.address, m.handle, GattOperation::Write, Ok(Bytes::new()));
+            }
+            ESPHomeMessage::BluetoothGattNotifyResponse(m) => {
+                self.resolve(m.address, m.handle, GattOperation::Notify, Ok(Bytes::new()));
+            }
+            ESPHomeMessage::BluetoothGattNotifyDataResponse(m) => {
+                if let Some(tx) = self.notifications.get(&(m.address, m.handle)) {
+                    if let Err(err) = tx.try_send(m.data.clone()) {
+                        warn!(
+                            "dropping notification from {} handle {}: {}",
+                            format_mac(m.address),
+                            m.handle,
+                            err
+                        );
+                    }
+                }
+            }
+            ESPHomeMessage::BluetoothGattErrorResponse(m) => {
+                let error = Error::with_kind(
+                    ErrorKind::BluetoothGatt {
+                        address: m.address,
+                        handle: m.handle,
+                        error: m.error,
+                    },
+                    &format!("GATT error {} on handle {}", m.error, m.handle),
+                );
+                // the error does not say which operation failed, so the oldest one for the handle fails
+                if let Some(queue) = self.pending.get_mut(&(m.address, m.handle)) {
+                    queue.retain(|(_, waiter)| !waiter.is_closed());
+                    if let Some((_, waiter)) = queue.pop_front() {
+                        let _ = waiter.send(Err(error));
+                    }
+                    if queue.is_empty() {
+                        self.pending.remove(&(m.address, m.handle));
+                    }
+                } else if let Some(discovery) = self.discoveries.remove(&m.address) {
+                    let _ = discovery.waiter.send(Err(error));
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+pub struct BleClient {
+    address: u64,
+    address_type: Option<u32>,
+    feature_flags: u32,
+    commands: CommandSender,
+    requests: Arc<Mutex<GattRequests>>,
+    timeout: Duration,
+    mtu: u32,
+}
+
+impl BleClient {
+    pub(crate) fn new(
+        address: u64,
+        feature_flags: u32,
+        commands: CommandSender,
+        requests: Arc<Mutex<GattRequests>>,
+    ) -> Self {
+        Self {
+            address,
+            address_type: None,
+            feature_flags,
+            commands,
+            requests,
+            timeout: DEFAULT_TIMEOUT,
+            mtu: 0,
+        }
+    }
+
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    pub fn mtu(&self) -> u32 {
+        self.mtu
+    }
+
+    // address type as seen in advertisements (public/random), sent along the connect request
+    pub fn set_address_type(&mut self, address_type: u32) {
+        self.address_type = Some(address_type);
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    async fn send(&self, msg: ESPHomeMessage) -> Result<()> {
+        self.commands.send_message(msg).await
+    }
+
+    async fn request(&self, handle: u32, operation: GattOperation, msg: ESPHomeMessage) -> Result<Bytes> {
+        let (tx, rx) = oneshot::channel();
+        self.requests
+            .lock()
+            .await
+            .pending
+            .entry((self.address, handle))
+            .or_default()
+            .push_back((operation, tx));
+        self.send(msg).await?;
+        timeout(self.timeout, rx).await??
+    }
+
+    async fn device_request(
+        &self,
+        request_type: BluetoothDeviceRequestType,
+        operation: DeviceOperation,
+    ) -> Result<DeviceResult> {
+        let (tx, rx) = oneshot::channel();
+        self.requests
+            .lock()
+            .await
+            .devices
+            .entry((self.address, operation))
+            .or_default()
+            .push(tx);
+        self.send(ESPHomeMessage::BluetoothDeviceRequest(BluetoothDeviceRequest {
+            address: self.address,
+            request_type: request_type as i32,
+            has_address_type: self.address_type.is_some(),
+            address_type: self.address_type.unwrap_or_default(),
+        }))
+        .await?;
+        timeout(self.timeout, rx).await??
+    }
+
+    fn check_device_result(&self, request_type: BluetoothDeviceRequestType, result: DeviceResult) -> Result<()> {
+        if !result.success || result.error != 0 {
+            return Err(Error::with_kind(
+                ErrorKind::BluetoothDevice {
+                    address: self.address,
+                    error: result.error,
+                },
+                &format!(
+                    "{} for {} failed with error {}",
+                    request_type.as_str_name(),
+                    format_mac(self.address),
+                    result.error
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn require_feature(&self, feature: u32, name: &str) -> Result<()> {
+        if self.feature_flags & feature == 0 {
+            return Err(Error::with_kind(
+                ErrorKind::Unsupported,
+                &format!("bluetooth proxy does not support {}", name),
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn connect(&mut self) -> Result<()> {
+        self.connect_with_cache(false).await
+    }
+
+    // proxies with remote caching need to know whether we already have the services of the device
+    pub async fn connect_with_cache(&mut self, has_cache: bool) -> Result<()> {
+        let request_type = if self.feature_flags & FEATURE_REMOTE_CACHING == 0 {
+            BluetoothDeviceRequestType::Connect
+        } else if has_cache {
+            BluetoothDeviceRequestType::ConnectV3WithCache
+        } else {
+            BluetoothDeviceRequestType::ConnectV3WithoutCache
+        };
+        let result = self.device_request(request_type, DeviceOperation::Connection).await?;
+        self.check_device_result(request_type, result)?;
+        self.mtu = result.mtu;
+        Ok(())
+    }
+
+    // the proxy answers a disconnect with connected = false, only the error code matters
+    pub async fn disconnect(&mut self) -> Result<()> {
+        let request_type = BluetoothDeviceRequestType::Disconnect;
+        let result = self.device_request(request_type, DeviceOperation::Connection).await?;
+        self.check_device_result(
+            request_type,
+            DeviceResult {
+                success: true,
+                ..result
+            },
+        )
+    }
+
+    pub async fn pair(&self) -> Result<()> {
+        self.require_feature(FEATURE_PAIRING, "pairing")?;
+        let request_type = BluetoothDeviceRequestType::Pair;
+        let result = self.device_request(request_type, DeviceOperation::Pairing).await?;
+        self.check_device_result(request_type, result)
+    }
+
+    pub async fn unpair(&self) -> Result<()> {
+        self.require_feature(FEATURE_PAIRING, "pairing")?;
+        let request_type = BluetoothDeviceRequestType::Unpair;
+        let result = self.device_request(request_type, DeviceOperation::Unpairing).await?;
+        self.check_device_result(request_type, result)
+    }
+
+    pub async fn clear_cache(&self) -> Result<()> {
+        self.require_feature(FEATURE_CACHE_CLEARING, "cache clearing")?;
+        let request_type = BluetoothDeviceRequestType::ClearCache;
+        let result = self.device_request(request_type, DeviceOperation::ClearCache).await?;
+        self.check_device_result(request_type, result)
+    }
+
+    pub async fn services(&self) -> Result<Vec<GattService>> {
+        let (tx, rx) = oneshot::channel();
+        self.requests.lock().await.discoveries.insert(
+            self.address,
+            ServiceDiscovery {
+                services: Vec::new(),
+                waiter: tx,
+            },
+        );
+        self.send(ESPHomeMessage::BluetoothGattGetServicesRequest(
+            BluetoothGattGetServicesRequest { address: self.address },
+        ))
+        .await?;
+        timeout(self.timeout, rx).await??
+    }
+
+    pub async fn read_characteristic(&self, handle: u32) -> Result<Bytes> {
+        let msg = ESPHomeMessage::BluetoothGattReadRequest(BluetoothGattReadRequest {
+            address: self.address,
+            handle,
+        });
+        self.request(handle, GattOperation::Read, msg).await
+    }
+
+    // the proxy only confirms writes when a response was requested
+    pub async fn write_characteristic(&self, handle: u32, data: impl Into<Bytes>, response: bool) -> Result<()> {
+        let msg = ESPHomeMessage::BluetoothGattWriteRequest(BluetoothGattWriteRequest {
+            address: self.address,
+            handle,
+            response,
+            data: data.into(),
+        });
+        if response {
+            self.request(handle, GattOperation::Write, msg).await?;
+        } else {
+            self.send(msg).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn read_descriptor(&self, handle: u32) -> Result<Bytes> {
+        let msg = ESPHomeMessage::BluetoothGattReadDescriptorRequest(BluetoothGattReadDescriptorRequest {
+            address: self.address,
+            handle,
+        });
+        self.request(handle, GattOperation::Read, msg).await
+    }
+
+    pub async fn write_descriptor(&self, handle: u32, data: impl Into<Bytes>) -> Result<()> {
+        let msg = ESPHomeMessage::BluetoothGattWriteDescriptorRequest(BluetoothGattWriteDescriptorRequest {
+            address: self.address,
+            handle,
+            data: data.into(),
+        });
+        self.request(handle, GattOperation::Write, msg).await?;
+        Ok(())
+    }
+
+    // enables notifications on the characteristic and streams the received values
+    pub async fn start_notify(&self, handle: u32) -> Result<mpsc::Receiver<Bytes>> {
+        let (tx, rx) = mpsc::channel(NOTIFICATION_QUEUE);
+        self.requests
+            .lock()
+            .await
+            .notifications
+            .insert((self.address, handle), tx);
+        let msg = ESPHomeMessage::BluetoothGattNotifyRequest(BluetoothGattNotifyRequest {
+            address: self.address,
+            handle,
+            enable: true,
+        });
+        if let Err(err) = self.request(handle, GattOperation::Notify, msg).await {
+            self.requests.lock().await.notifications.remove(&(self.address, handle));
+            return Err(err);
+        }
+        Ok(rx)
+    }
+
+    pub async fn stop_notify(&self, handle: u32) -> Result<()> {
+        self.requests.lock().await.notifications.remove(&(self.address, handle));
+        let msg = ESPHomeMessage::BluetoothGattNotifyRequest(BluetoothGattNotifyRequest {
+            address: self.address,
+            handle,
+            enable: false,
+        });
+        self.request(handle, GattOperation::Notify, msg).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: u64 = 0xA4C138000001;
+
+    fn request(requests: &mut GattRequests, handle: u32, operation: GattOperation) -> oneshot::Receiver<Result<Bytes>> {
+        let (tx, rx) = oneshot::channel();
+        requests
+            .pending
+            .entry((ADDRESS, handle))
+            .or_default()
+            .push_back((operation, tx));
+        rx
+    }
+
+    fn device_request(
+        requests: &mut GattRequests,
+        operation: DeviceOperation,
+    ) -> oneshot::Receiver<Result<DeviceResult>> {
+        let (tx, rx) = oneshot::channel();
+        requests.devices.entry((ADDRESS, operation)).or_default().push(tx);
+        rx
+    }
+
+    fn discovery(requests: &mut GattRequests) -> oneshot::Receiver<Result<Vec<GattService>>> {
+        let (tx, rx) = oneshot::channel();
+        requests.discoveries.insert(
+            ADDRESS,
+            ServiceDiscovery {
+                services: Vec::new(),
+                waiter: tx,
+            },
+        );
+        rx
+    }
+
+    fn read_response(handle: u32, data: &'static [u8]) -> ESPHomeMessage {
+        ESPHomeMessage::BluetoothGattReadResponse(BluetoothGattReadResponse {
+            address: ADDRESS,
+            handle,
+            data: Bytes::from_static(data),
+        })
+    }
+
+    fn gatt_error(handle: u32, error: i32) -> ESPHomeMessage {
+        ESPHomeMessage::BluetoothGattErrorResponse(BluetoothGattErrorResponse {
+            address: ADDRESS,
+            handle,
+            error,
+        })
+    }
+
+    fn connection(connected: bool, error: i32) -> ESPHomeMessage {
+        ESPHomeMessage::BluetoothDeviceConnectionResponse(BluetoothDeviceConnectionResponse {
+            address: ADDRESS,
+            connected,
+            mtu: 247,
+            error,
+        })
+    }
+
+    fn kind<T: std::fmt::Debug>(rx: &mut oneshot::Receiver<Result<T>>) -> ErrorKind {
+        rx.try_recv().unwrap().unwrap_err().kind().clone()
+    }
+
+    #[test]
+    fn responses_are_matched_in_order_per_handle() {
+        let mut requests = GattRequests::default();
+        let mut first = request(&mut requests, 12, GattOperation::Read);
+        let mut write = request(&mut requests, 12, GattOperation::Write);
+        let mut second = request(&mut requests, 12, GattOperation::Read);
+        let mut other = request(&mut requests, 14, GattOperation::Read);
+
+        assert!(requests.handle_message(&read_response(12, b"a")));
+        assert!(requests.handle_message(&ESPHomeMessage::BluetoothGattWriteResponse(
+            BluetoothGattWriteResponse {
+                address: ADDRESS,
+                handle: 12
+            }
+        )));
+        assert!(requests.handle_message(&read_response(12, b"b")));
+
+        assert_eq!(first.try_recv().unwrap().unwrap(), Bytes::from_static(b"a"));
+        assert_eq!(write.try_recv().unwrap().unwrap(), Bytes::new());
+        assert_eq!(second.try_recv().unwrap().unwrap(), Bytes::from_static(b"b"));
+        assert!(other.try_recv().is_err());
+        assert!(!requests.pending.contains_key(&(ADDRESS, 12)));
+    }
+
+    #[test]
+    fn timed_out_requests_are_skipped() {
+        let mut requests = GattRequests::default();
+        drop(request(&mut requests, 12, GattOperation::Read));
+        let mut waiting = request(&mut requests, 12, GattOperation::Read);
+
+        requests.handle_message(&read_response(12, b"a"));
+        assert_eq!(waiting.try_recv().unwrap().unwrap(), Bytes::from_static(b"a"));
+        assert!(requests.pending.is_empty());
+    }
+
+    #[test]
+    fn gatt_error_fails_the_oldest_request() {
+        let mut requests = GattRequests::default();
+        let mut first = request(&mut requests, 12, GattOperation::Read);
+        let mut second = request(&mut requests, 12, GattOperation::Write);
+
+        requests.handle_message(&gatt_error(12, 5));
+        assert_eq!(
+            kind(&mut first),
+            ErrorKind::BluetoothGatt {
+                address: ADDRESS,
+                handle: 12,
+                error: 5
+            }
+        );
+        assert!(second.try_recv().is_err());
+
+        // without a request for the handle the error ends a running service discovery
+        let mut services = discovery(&mut requests);
+        requests.handle_message(&gatt_error(40, 133));
+        assert!(matches!(
+            kind(&mut services),
+            ErrorKind::BluetoothGatt { handle: 40, .. }
+        ));
+    }
+
+    #[test]
+    fn disconnect_fails_requests_of_the_device() {
+        let mut requests = GattRequests::default();
+        let mut read = request(&mut requests, 12, GattOperation::Read);
+        let mut services = discovery(&mut requests);
+        let mut disconnect = device_request(&mut requests, DeviceOperation::Connection);
+        let (notify_tx, _notify_rx) = mpsc::channel(1);
+        requests.notifications.insert((ADDRESS, 14), notify_tx);
+        let (other_tx, mut other) = oneshot::channel();
+        requests
+            .pending
+            .entry((ADDRESS + 1, 12))
+            .or_default()
+            .push_back((GattOperation::Read, other_tx));
+
+        requests.handle_message(&connection(false, 22));
+        let expected = ErrorKind::BluetoothConnection {
+            address: ADDRESS,
+            error: 22,
+        };
+        assert_eq!(kind(&mut read), expected);
+        assert_eq!(kind(&mut services), expected);
+        let result = disconnect.try_recv().unwrap().unwrap();
+        assert!(!result.success);
+        assert_eq!(result.error, 22);
+        assert!(requests.notifications.is_empty());
+        assert!(other.try_recv().is_err());
+        assert!(requests.pending.contains_key(&(ADDRESS + 1, 12)));
+    }
+
+    #[test]
+    fn device_responses_reach_every_waiter() {
+        let mut requests = GattRequests::default();
+        let mut first = device_request(&mut requests, DeviceOperation::Connection);
+        let mut second = device_request(&mut requests, DeviceOperation::Connection);
+        let mut pairing = device_request(&mut requests, DeviceOperation::Pairing);
+
+        requests.handle_message(&connection(true, 0));
+        for rx in [&mut first, &mut second] {
+            let result = rx.try_recv().unwrap().unwrap();
+            assert!(result.success);
+            assert_eq!(result.mtu, 247);
+        }
+        assert!(pairing.try_recv().is_err());
+
+        requests.handle_message(&ESPHomeMessage::BluetoothDevicePairingResponse(
+            BluetoothDevicePairingResponse {
+                address: ADDRESS,
+                paired: false,
+                error: 3,
+            },
+        ));
+        let result = pairing.try_recv().unwrap().unwrap();
+        assert!(!result.success);
+        assert_eq!(result.error, 3);
+    }
+
+    #[test]
+    fn abandoned_waiters_are_pruned() {
+        let mut requests = GattRequests::default();
+        drop(device_request(&mut requests, DeviceOperation::ClearCache));
+        drop(discovery(&mut requests));
+        let _waiting = device_request(&mut requests, DeviceOperation::Unpairing);
+
+        requests.handle_message(&connection(true, 0));
+        assert_eq!(
+            requests.devices.keys().collect::<Vec<_>>(),
+            vec![&(ADDRESS, DeviceOperation::Unpairing)]
+        );
+        assert!(requests.discoveries.is_empty());
+    }
+
+    #[test]
+    fn services_are_assembled() {
+        let mut requests = GattRequests::default();
+        let mut services = discovery(&mut requests);
+        let service = |handle: u32, characteristic: u32| BluetoothGattService {
+            uuid: vec![0x0000180f00001000, 0x800000805f9b34fb],
+            handle,
+            characteristics: vec![BluetoothGattCharacteristic {
+                uuid: vec![0x00002a1900001000, 0x800000805f9b34fb],
+                handle: characteristic,
+                properties: PROPERTY_READ | PROPERTY_NOTIFY,
+                descriptors: vec![BluetoothGattDescriptor {
+                    uuid: vec![0x0000290200001000, 0x800000805f9b34fb],
+                    handle: characteristic + 1,
+                }],
+            }],
+        };
+
+        for handle in [10, 20] {
+            requests.handle_message(&ESPHomeMessage::BluetoothGattGetServicesResponse(
+                BluetoothGattGetServicesResponse {
+                    address: ADDRESS,
+                    services: vec![service(handle, handle + 1)],
+                },
+            ));
+        }
+        assert!(services.try_recv().is_err());
+        requests.handle_message(&ESPHomeMessage::BluetoothGattGetServicesDoneResponse(
+            BluetoothGattGetServicesDoneResponse { address: ADDRESS },
+        ));
+
+        let services = services.try_recv().unwrap().unwrap();
+        assert_eq!(services.iter().map(|s| s.handle).collect::<Vec<_>>(), vec![10, 20]);
+        assert_eq!(
+            services[0].uuid,
+            Uuid::parse_str("0000180f-0000-1000-8000-00805f9b34fb").unwrap()
+        );
+        let characteristic = &services[1].characteristics[0];
+        assert_eq!(characteristic.handle, 21);
+        assert!(characteristic.has_property(PROPERTY_NOTIFY));
+        assert!(!characteristic.has_property(PROPERTY_WRITE));
+        assert_eq!(
+            characteristic.descriptors,
+            vec![GattDescriptor {
+                uuid: Uuid::parse_str("00002902-0000-1000-8000-00805f9b34fb").unwrap(),
+                handle: 22,
+            }]
+        );
+        assert!(requests.discoveries.is_empty());
+    }
+
+    #[test]
+    fn notifications_are_forwarded() {
+        let mut requests = GattRequests::default();
+        let (tx, mut rx) = mpsc::channel(1);
+        requests.notifications.insert((ADDRESS, 14), tx);
+        let notification = |data: &'static [u8]| {
+            ESPHomeMessage::BluetoothGattNotifyDataResponse(BluetoothGattNotifyDataResponse {
+                address: ADDRESS,
+                handle: 14,
+                data: Bytes::from_static(data),
+            })
+        };
+
+        assert!(requests.handle_message(&notification(b"a")));
+        // the queue is full, the value is dropped
+        assert!(requests.handle_message(&notification(b"b")));
+        assert_eq!(rx.try_recv().unwrap(), Bytes::from_static(b"a"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn proxy_disconnect_fails_everything() {
+        let mut requests = GattRequests::default();
+        let mut read = request(&mut requests, 12, GattOperation::Read);
+        let mut connect = device_request(&mut requests, DeviceOperation::Connection);
+        let mut services = discovery(&mut requests);
+        let (tx, _rx) = mpsc::channel(1);
+        requests.notifications.insert((ADDRESS, 14), tx);
+
+        requests.fail_all_disconnected();
+        assert_eq!(kind(&mut read), ErrorKind::Disconnected);
+        assert_eq!(kind(&mut connect), ErrorKind::Disconnected);
+        assert_eq!(kind(&mut services), ErrorKind::Disconnected);
+        assert!(requests.pending.is_empty() && requests.devices.is_empty());
+        assert!(requests.discoveries.is_empty() && requests.notifications.is_empty());
+    }
+
+    #[test]
+    fn other_messages_are_not_consumed() {
+        let mut requests = GattRequests::default();
+        assert!(!requests.handle_message(&ESPHomeMessage::PingRequest(PingRequest {})));
+    }
+}
@@ -0,0 +1,398 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use tokio::{
+    sync::{watch, Mutex, Notify},
+    time::timeout,
+};
+
+use crate::{
+    api::SubscribeBluetoothConnectionsFreeRequest,
+    bluetooth::{
+        format_mac,
+        gatt::{BleClient, GattRequests},
+    },
+    connection::CommandSender,
+    messages::ESPHomeMessage,
+    result::{Error, Result},
+};
+
+const DEFAULT_RSSI_MAX_AGE: Duration = Duration::from_secs(60);
+// the proxy reports its free slots after a connect, until then the slot stays reserved
+const SLOTS_UPDATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConnectionSlots {
+    pub free: u32,
+    pub limit: u32,
+}
+
+struct SeenDevice {
+    rssi: i32,
+    last_seen: Instant,
+}
+
+// Connection slots and advertisement RSSI of a single proxy, updated by the device reader task
+pub(crate) struct ProxyTracker {
+    slots: watch::Sender<ConnectionSlots>,
+    seen: Mutex<HashMap<u64, SeenDevice>>,
+}
+
+impl ProxyTracker {
+    pub(crate) fn new() -> Self {
+        Self {
+            slots: watch::channel(ConnectionSlots::default()).0,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // returns true when the message was consumed
+    pub(crate) fn handle_message(&self, msg: &ESPHomeMessage) -> bool {
+        if let ESPHomeMessage::BluetoothConnectionsFreeResponse(m) = msg {
+            self.slots.send_replace(ConnectionSlots {
+                free: m.free,
+                limit: m.limit,
+            });
+            return true;
+        }
+        false
+    }
+
+    pub(crate) async fn seen(&self, address: u64, rssi: i32) {
+        self.seen.lock().await.insert(
+            address,
+            SeenDevice {
+                rssi,
+                last_seen: Instant::now(),
+            },
+        );
+    }
+}
+
+#[derive(Clone)]
+pub struct BluetoothProxy {
+    name: String,
+    feature_flags: u32,
+    commands: CommandSender,
+    tracker: Arc<ProxyTracker>,
+    gatt_requests: Arc<Mutex<GattRequests>>,
+}
+
+impl BluetoothProxy {
+    pub(crate) fn new(
+        name: String,
+        feature_flags: u32,
+        commands: CommandSender,
+        tracker: Arc<ProxyTracker>,
+        gatt_requests: Arc<Mutex<GattRequests>>,
+    ) -> Self {
+        Self {
+            name,
+            feature_flags,
+            commands,
+            tracker,
+            gatt_requests,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn feature_flags(&self) -> u32 {
+        self.feature_flags
+    }
+
+    pub fn slots(&self) -> ConnectionSlots {
+        *self.tracker.slots.borrow()
+    }
+
+    pub fn watch_slots(&self) -> watch::Receiver<ConnectionSlots> {
+        self.tracker.slots.subscribe()
+    }
+
+    // RSSI of the last advertisement of the device, if it was seen within `max_age`
+    pub async fn last_rssi(&self, address: u64, max_age: Duration) -> Option<i32> {
+        self.tracker
+            .seen
+            .lock()
+            .await
+            .get(&address)
+            .filter(|seen| seen.last_seen.elapsed() <= max_age)
+            .map(|seen| seen.rssi)
+    }
+
+    pub async fn subscribe_connections_free(&self) -> Result<()> {
+        self.commands
+            .send_message(ESPHomeMessage::SubscribeBluetoothConnectionsFreeRequest(
+                SubscribeBluetoothConnectionsFreeRequest {},
+            ))
+            .await
+    }
+
+    pub fn ble_client(&self, address: u64) -> BleClient {
+        BleClient::new(
+            address,
+            self.feature_flags,
+            self.commands.clone(),
+            self.gatt_requests.clone(),
+        )
+    }
+}
+
+struct PooledProxy {
+    proxy: BluetoothProxy,
+    reserved: AtomicU32,
+}
+
+impl PooledProxy {
+    fn available(&self) -> u32 {
+        self.proxy
+            .slots()
+            .free
+            .saturating_sub(self.reserved.load(Ordering::SeqCst))
+    }
+
+    fn release(&self, changed: &Notify) {
+        self.reserved.fetch_sub(1, Ordering::SeqCst);
+        changed.notify_waiters();
+    }
+}
+
+// Picks a proxy with a free connection slot for a peripheral and queues requests when all slots are taken
+pub struct ProxyPool {
+    proxies: Vec<Arc<PooledProxy>>,
+    queue: Mutex<()>,
+    changed: Arc<Notify>,
+    rssi_max_age: Duration,
+}
+
+impl Default for ProxyPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProxyPool {
+    pub fn new() -> Self {
+        Self {
+            proxies: Vec::new(),
+            queue: Mutex::new(()),
+            changed: Arc::new(Notify::new()),
+            rssi_max_age: DEFAULT_RSSI_MAX_AGE,
+        }
+    }
+
+    // only advertisements seen within this time are considered when picking a proxy
+    pub fn set_rssi_max_age(&mut self, max_age: Duration) {
+        self.rssi_max_age = max_age;
+    }
+
+    pub async fn add(&mut self, proxy: BluetoothProxy) -> Result<()> {
+        proxy.subscribe_connections_free().await?;
+
+        let mut slots = proxy.watch_slots();
+        let changed = self.changed.clone();
+        tokio::spawn(async move {
+            while slots.changed().await.is_ok() {
+                changed.notify_waiters();
+            }
+        });
+
+        self.proxies.push(Arc::new(PooledProxy {
+            proxy,
+            reserved: AtomicU32::new(0),
+        }));
+        Ok(())
+    }
+
+    pub fn proxies(&self) -> Vec<BluetoothProxy> {
+        self.proxies.iter().map(|p| p.proxy.clone()).collect()
+    }
+
+    // prefers the proxy with the strongest recent RSSI for the device, then the one with most free slots
+    async fn select(&self, address: u64) -> Option<Arc<PooledProxy>> {
+        let mut best: Option<(Option<i32>, u32, Arc<PooledProxy>)> = None;
+        for pooled in &self.proxies {
+            let available = pooled.available();
+            if available == 0 {
+                continue;
+            }
+            let rssi = pooled.proxy.last_rssi(address, self.rssi_max_age).await;
+            let better = match &best {
+                None => true,
+                Some((best_rssi, best_available, _)) => (rssi, available) > (*best_rssi, *best_available),
+            };
+            if better {
+                best = Some((rssi, available, pooled.clone()));
+            }
+        }
+        best.map(|(_, _, pooled)| pooled)
+    }
+
+    // waits until a slot is free on any proxy and connects through the best one
+    pub async fn connect(&self, address: u64) -> Result<BleClient> {
+        if self.proxies.is_empty() {
+            return Err(Error::new("no bluetooth proxy available"));
+        }
+
+        let pooled = {
+            // requests are served in order, the queue lock is fair
+            let _queue = self.queue.lock().await;
+            loop {
+                let notified = self.changed.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+
+                if let Some(pooled) = self.select(address).await {
+                    pooled.reserved.fetch_add(1, Ordering::SeqCst);
+                    break pooled;
+                }
+                debug!("no free bluetooth connection slot for {}, waiting", format_mac(address));
+                notified.await;
+            }
+        };
+
+        debug!(
+            "connecting {} through proxy {}",
+            format_mac(address),
+            pooled.proxy.name()
+        );
+        let mut slots = pooled.proxy.watch_slots();
+        slots.borrow_and_update();
+        let mut client = pooled.proxy.ble_client(address);
+        if let Err(err) = client.connect().await {
+            pooled.release(&self.changed);
+            return Err(err);
+        }
+        if slots.has_changed().unwrap_or(true) {
+            pooled.release(&self.changed);
+        } else {
+            // the free count still includes the new connection
+            let changed = self.changed.clone();
+            tokio::spawn(async move {
+                let _ = timeout(SLOTS_UPDATE_TIMEOUT, slots.changed()).await;
+                pooled.release(&changed);
+            });
+        }
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        api::{BluetoothConnectionsFreeResponse, BluetoothDeviceConnectionResponse},
+        commands::DeviceCommand,
+        connection::Connection,
+    };
+
+    struct FakeProxy {
+        proxy: BluetoothProxy,
+        tracker: Arc<ProxyTracker>,
+        // addresses of the connect requests the proxy received
+        connects: mpsc::UnboundedReceiver<u64>,
+    }
+
+    impl FakeProxy {
+        // answers every connect request right away, the free slots are reported by `slots`
+        fn new(free: u32, limit: u32) -> Self {
+            let (cmd_tx, mut cmd_rx) = mpsc::channel(8);
+            let (_, msg_rx) = mpsc::channel(8);
+            let gatt_requests = Arc::new(Mutex::new(GattRequests::default()));
+            let tracker = Arc::new(ProxyTracker::new());
+            let proxy = Connection::new(cmd_tx, msg_rx)
+                .with_bluetooth("proxy".to_string(), 0, gatt_requests.clone(), tracker.clone())
+                .bluetooth_proxy();
+            let (connects_tx, connects) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                while let Some(DeviceCommand::SendMessage(msg)) = cmd_rx.recv().await {
+                    if let ESPHomeMessage::BluetoothDeviceRequest(request) = *msg {
+                        let _ = connects_tx.send(request.address);
+                        gatt_requests
+                            .lock()
+                            .await
+                            .handle_message(&ESPHomeMessage::BluetoothDeviceConnectionResponse(
+                                BluetoothDeviceConnectionResponse {
+                                    address: request.address,
+                                    connected: true,
+                                    mtu: 23,
+                                    error: 0,
+                                },
+                            ));
+                    }
+                }
+            });
+            let fake = Self {
+                proxy,
+                tracker,
+                connects,
+            };
+            fake.slots(free, limit);
+            fake
+        }
+
+        fn slots(&self, free: u32, limit: u32) {
+            self.tracker
+                .handle_message(&ESPHomeMessage::BluetoothConnectionsFreeResponse(
+                    BluetoothConnectionsFreeResponse { free, limit },
+                ));
+        }
+    }
+
+    #[tokio::test]
+    async fn slot_stays_reserved_until_the_proxy_reports() {
+        let mut fake = FakeProxy::new(1, 1);
+        let mut pool = ProxyPool::new();
+        pool.add(fake.proxy.clone()).await.unwrap();
+        let pool = Arc::new(pool);
+
+        pool.connect(1).await.unwrap();
+        assert_eq!(fake.connects.recv().await, Some(1));
+
+        // the proxy did not report the taken slot yet, the second connect has to wait
+        let second = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.connect(2).await.map(|client| client.address()) }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!second.is_finished());
+
+        fake.slots(0, 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!second.is_finished());
+        assert!(fake.connects.try_recv().is_err());
+
+        fake.slots(1, 1);
+        assert_eq!(second.await.unwrap().unwrap(), 2);
+        assert_eq!(fake.connects.recv().await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn proxy_with_most_free_slots_is_picked() {
+        let mut busy = FakeProxy::new(1, 3);
+        let mut idle = FakeProxy::new(3, 3);
+        let mut pool = ProxyPool::new();
+        pool.add(busy.proxy.clone()).await.unwrap();
+        pool.add(idle.proxy.clone()).await.unwrap();
+
+        pool.connect(1).await.unwrap();
+        assert_eq!(idle.connects.recv().await, Some(1));
+        assert!(busy.connects.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn empty_pool() {
+        assert!(ProxyPool::new().connect(1).await.is_err());
+    }
+}
@@ -1,4 +1,8 @@
-use crate::messages::ESPHomeMessage;
+use bytes::Bytes;
+
+#[cfg(feature = "bluetooth")]
+use crate::bluetooth::{decoders::Measurement, Advertisement};
+use crate::{api::SubscribeLogsResponse, custom::CustomMessage, messages::ESPHomeMessage};
 
 #[derive(Clone, Debug)]
 pub struct DaviceState {
@@ -16,6 +20,18 @@ impl DaviceState {
 pub enum DeviceMessage {
     Disconnected,
     DeviceStateChange(Box<DaviceState>),
+    #[cfg(feature = "bluetooth")]
+    BluetoothAdvertisement(Box<Advertisement>),
+    #[cfg(feature = "bluetooth")]
+    BluetoothMeasurement(Box<Measurement>),
+    // log line of the device, after a SubscribeLogsRequest
+    Log(Box<SubscribeLogsResponse>),
+    // message with an id unknown to the protocol and without a registered decoder
+    UnknownMessage {
+        id: u32,
+        payload: Bytes,
+    },
+    CustomMessage(Box<CustomMessage>),
 }
 
 #[derive(Clone, Debug)]
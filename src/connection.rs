@@ -1,19 +1,246 @@
-use log::debug;
-use tokio::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
 
+use chrono::Utc;
+use log::{debug, warn};
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    Mutex,
+};
+
+#[cfg(feature = "bluetooth")]
+use crate::bluetooth::{
+    gatt::{BleClient, GattRequests},
+    slots::{BluetoothProxy, ProxyTracker},
+};
 use crate::{
+    api::{DeviceInfoResponse, DisconnectRequest},
+    audit::{AuditRecord, AuditSink},
     commands::{DeviceCommand, DeviceMessage},
-    result::Result,
+    entity::{Entity, EntityList},
+    messages::{ESPHomeMessage, ProtocolMode},
+    policy::{CommandPolicy, DenyReason, PolicyCommand},
+    redact::SecretEntities,
+    result::{Error, ErrorKind, Result},
+    stats::{ConnectionStats, StatsCounters},
+};
+#[cfg(feature = "voice")]
+use crate::{
+    api::{VoiceAssistantRequest, VoiceAssistantResponse},
+    voice::{VoiceAssistant, VoicePipeline},
 };
 
-pub struct Connection {
+// Sends messages of a connection after the checks every outbound command goes through: read-only mode,
+// command policy and audit. Shared by the connection, its BLE clients and the voice assistant.
+#[derive(Clone)]
+pub(crate) struct CommandSender {
     sender: Sender<DeviceCommand>,
+    secret_entities: SecretEntities,
+    read_only: bool,
+    entities: Arc<Mutex<EntityList>>,
+    command_policy: Option<Arc<dyn CommandPolicy>>,
+    audit: Option<Arc<dyn AuditSink>>,
+    device_info: DeviceInfoResponse,
+}
+
+impl CommandSender {
+    fn new(sender: Sender<DeviceCommand>) -> Self {
+        Self {
+            sender,
+            secret_entities: SecretEntities::default(),
+            read_only: false,
+            entities: Arc::new(Mutex::new(EntityList::new())),
+            command_policy: None,
+            audit: None,
+            device_info: DeviceInfoResponse::default(),
+        }
+    }
+
+    #[cfg(any(feature = "bluetooth", feature = "voice"))]
+    pub(crate) async fn send_message(&self, msg: ESPHomeMessage) -> Result<()> {
+        self.send(DeviceCommand::SendMessage(Box::new(msg)), None).await
+    }
+
+    async fn send(&self, cmd: DeviceCommand, actor: Option<String>) -> Result<()> {
+        if let DeviceCommand::SendMessage(msg) = &cmd {
+            self.check(msg, actor).await?;
+        }
+        self.sender.send(cmd).await?;
+        Ok(())
+    }
+
+    async fn check(&self, msg: &ESPHomeMessage, actor: Option<String>) -> Result<()> {
+        if self.read_only && !msg.is_read_only() {
+            return Err(msg.read_only_error());
+        }
+        let entity = match msg.command_key() {
+            Some(key) => self.entities.lock().await.get_by_key(key),
+            None => None,
+        };
+        self.check_command_policy(msg, entity.as_ref()).await?;
+        self.audit(msg, entity.as_ref(), actor).await
+    }
+
+    async fn check_command_policy(&self, msg: &ESPHomeMessage, entity: Option<&Entity>) -> Result<()> {
+        let (Some(policy), Some(key)) = (&self.command_policy, msg.command_key()) else {
+            return Ok(());
+        };
+        let result = match entity {
+            Some(entity) => {
+                policy
+                    .check(&PolicyCommand {
+                        entity: entity.clone(),
+                        message: msg.clone(),
+                    })
+                    .await
+            }
+            None => Err(DenyReason::UnknownEntity),
+        };
+        result.map_err(|reason| {
+            let entity_id = entity.map(|e| e.entity_id()).unwrap_or_else(|| key.to_string());
+            Error::with_kind(
+                ErrorKind::CommandDenied {
+                    entity_id: entity_id.clone(),
+                    reason: reason.clone(),
+                },
+                &format!("{} to {} denied: {}", msg.name(), entity_id, reason),
+            )
+        })
+    }
+
+    // handshake, subscriptions and reads are not commands and are not recorded
+    async fn audit(&self, msg: &ESPHomeMessage, entity: Option<&Entity>, actor: Option<String>) -> Result<()> {
+        let Some(audit) = &self.audit else {
+            return Ok(());
+        };
+        if msg.is_read_only() {
+            return Ok(());
+        }
+        let record = AuditRecord {
+            timestamp: Utc::now(),
+            device_name: self.device_info.name.clone(),
+            mac_address: self.device_info.mac_address.clone(),
+            entity_id: entity.map(|e| e.entity_id()),
+            command: msg.name(),
+            message_id: msg.id(),
+            fields: self.secret_entities.mask(msg).fields(),
+            actor,
+        };
+        audit.record(&record).await
+    }
+}
+
+pub struct Connection {
+    commands: CommandSender,
     receiver: Receiver<DeviceMessage>,
+    #[cfg(feature = "bluetooth")]
+    bluetooth: BluetoothProxy,
+    #[cfg(feature = "voice")]
+    voice_requests: Arc<Mutex<Option<Sender<VoiceAssistantRequest>>>>,
+    protocol_mode: ProtocolMode,
+    stats: Arc<StatsCounters>,
+    actor: Option<String>,
 }
 
 impl Connection {
     pub fn new(sender: Sender<DeviceCommand>, receiver: Receiver<DeviceMessage>) -> Self {
-        Self { sender, receiver }
+        let commands = CommandSender::new(sender);
+        Self {
+            #[cfg(feature = "bluetooth")]
+            bluetooth: BluetoothProxy::new(
+                String::new(),
+                0,
+                commands.clone(),
+                Arc::new(ProxyTracker::new()),
+                Arc::new(Mutex::new(GattRequests::default())),
+            ),
+            commands,
+            receiver,
+            #[cfg(feature = "voice")]
+            voice_requests: Arc::new(Mutex::new(None)),
+            protocol_mode: ProtocolMode::default(),
+            stats: Arc::new(StatsCounters::default()),
+            actor: None,
+        }
+    }
+
+    #[cfg(feature = "bluetooth")]
+    pub(crate) fn with_bluetooth(
+        mut self,
+        name: String,
+        bluetooth_feature_flags: u32,
+        gatt_requests: Arc<Mutex<GattRequests>>,
+        proxy_tracker: Arc<ProxyTracker>,
+    ) -> Self {
+        self.bluetooth = BluetoothProxy::new(
+            name,
+            bluetooth_feature_flags,
+            self.commands.clone(),
+            proxy_tracker,
+            gatt_requests,
+        );
+        self
+    }
+
+    #[cfg(feature = "voice")]
+    pub(crate) fn with_voice_requests(
+        mut self,
+        voice_requests: Arc<Mutex<Option<Sender<VoiceAssistantRequest>>>>,
+    ) -> Self {
+        self.voice_requests = voice_requests;
+        self
+    }
+
+    pub(crate) fn with_stats(mut self, stats: Arc<StatsCounters>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    // the following are set before with_bluetooth, the bluetooth proxy sends its commands with the same checks
+    pub(crate) fn with_secret_entities(mut self, secret_entities: SecretEntities) -> Self {
+        self.commands.secret_entities = secret_entities;
+        self
+    }
+
+    pub(crate) fn with_read_only(mut self, read_only: bool) -> Self {
+        self.commands.read_only = read_only;
+        self
+    }
+
+    pub(crate) fn with_entities(mut self, entities: Arc<Mutex<EntityList>>) -> Self {
+        self.commands.entities = entities;
+        self
+    }
+
+    pub(crate) fn with_command_policy(mut self, command_policy: Option<Arc<dyn CommandPolicy>>) -> Self {
+        self.commands.command_policy = command_policy;
+        self
+    }
+
+    pub(crate) fn with_audit(mut self, audit: Option<Arc<dyn AuditSink>>, device_info: DeviceInfoResponse) -> Self {
+        self.commands.audit = audit;
+        self.commands.device_info = device_info;
+        self
+    }
+
+    // actor recorded in the audit log for commands sent with send_message
+    pub fn set_actor(&mut self, actor: Option<String>) {
+        self.actor = actor;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.commands.read_only
+    }
+
+    pub fn set_protocol_mode(&mut self, protocol_mode: ProtocolMode) {
+        self.protocol_mode = protocol_mode;
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.snapshot()
+    }
+
+    pub(crate) fn stats_counters(&self) -> Arc<StatsCounters> {
+        self.stats.clone()
     }
 
     pub async fn wait_for_message(&mut self) -> Result<DeviceMessage> {
@@ -25,8 +252,121 @@ impl Connection {
     }
 
     pub async fn send_message(&mut self, cmd: DeviceCommand) -> Result<()> {
-        debug!("try to message to device {:?}", cmd);
-        self.sender.send(cmd).await?;
+        let actor = self.actor.clone();
+        self.send(cmd, actor).await
+    }
+
+    // like send_message, with the actor recorded in the audit log, e.g. the user or automation
+    pub async fn send_message_as(&mut self, actor: &str, cmd: DeviceCommand) -> Result<()> {
+        self.send(cmd, Some(actor.to_string())).await
+    }
+
+    async fn send(&mut self, cmd: DeviceCommand, actor: Option<String>) -> Result<()> {
+        if let DeviceCommand::SendMessage(msg) = &cmd {
+            debug!(
+                "try to message to device {:?}",
+                self.commands.secret_entities.redact(msg)
+            );
+            if !msg.sent_by_client() {
+                if self.protocol_mode == ProtocolMode::Strict {
+                    return Err(Error::with_kind(
+                        ErrorKind::ProtocolViolation { message_id: msg.id() },
+                        &format!("{} can only be sent by the device", msg.name()),
+                    ));
+                }
+                warn!("sending {} which can only be sent by the device", msg.name());
+            }
+        }
+        self.commands.send(cmd, actor).await
+    }
+
+    // asks the device to close the connection and waits until it did, everything sent before is delivered
+    pub async fn disconnect(&mut self) -> Result<()> {
+        self.send_message(DeviceCommand::SendMessage(Box::new(ESPHomeMessage::DisconnectRequest(
+            DisconnectRequest {},
+        ))))
+        .await?;
+        while !matches!(self.wait_for_message().await?, DeviceMessage::Disconnected) {}
         Ok(())
     }
+
+    // GATT client for a BLE peripheral reachable through this bluetooth proxy
+    #[cfg(feature = "bluetooth")]
+    pub fn ble_client(&self, address: u64) -> BleClient {
+        self.bluetooth.ble_client(address)
+    }
+
+    // handle used to track connection slots of this bluetooth proxy, e.g. in a ProxyPool
+    #[cfg(feature = "bluetooth")]
+    pub fn bluetooth_proxy(&self) -> BluetoothProxy {
+        self.bluetooth.clone()
+    }
+
+    // subscribes to voice assistant requests and answers them with the pipeline
+    #[cfg(feature = "voice")]
+    pub async fn voice_assistant<P: VoicePipeline + 'static>(&self, pipeline: P) -> Result<VoiceAssistant> {
+        // the assistant has to answer requests of the device
+        if self.commands.read_only {
+            return Err(ESPHomeMessage::VoiceAssistantResponse(VoiceAssistantResponse::default()).read_only_error());
+        }
+        VoiceAssistant::start(self.commands.clone(), self.voice_requests.clone(), Arc::new(pipeline)).await
+    }
+}
+
+#[cfg(all(test, feature = "bluetooth"))]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::audit::AuditFuture;
+
+    #[derive(Default)]
+    struct Commands(StdMutex<Vec<&'static str>>);
+
+    impl AuditSink for Commands {
+        fn record<'a>(&'a self, record: &'a AuditRecord) -> AuditFuture<'a> {
+            self.0.lock().unwrap().push(record.command);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn connection(read_only: bool, audit: Arc<Commands>) -> (Connection, mpsc::Receiver<DeviceCommand>) {
+        let (cmd_tx, cmd_rx) = mpsc::channel(8);
+        let (_, msg_rx) = mpsc::channel(8);
+        let connection = Connection::new(cmd_tx, msg_rx)
+            .with_read_only(read_only)
+            .with_audit(Some(audit), DeviceInfoResponse::default())
+            .with_bluetooth(
+                "proxy".to_string(),
+                0,
+                Arc::new(Mutex::new(GattRequests::default())),
+                Arc::new(ProxyTracker::new()),
+            );
+        (connection, cmd_rx)
+    }
+
+    #[tokio::test]
+    async fn ble_writes_are_audited() {
+        let audit = Arc::new(Commands::default());
+        let (connection, mut commands) = connection(false, audit.clone());
+
+        let client = connection.ble_client(0xA4C1_3812_3456);
+        client.write_characteristic(42, vec![1, 2], false).await.unwrap();
+        assert!(matches!(commands.recv().await, Some(DeviceCommand::SendMessage(_))));
+        assert_eq!(*audit.0.lock().unwrap(), ["BluetoothGattWriteRequest"]);
+    }
+
+    #[tokio::test]
+    async fn ble_writes_are_rejected_when_read_only() {
+        let audit = Arc::new(Commands::default());
+        let (connection, mut commands) = connection(true, audit.clone());
+
+        let client = connection.ble_client(0xA4C1_3812_3456);
+        let err = client.write_characteristic(42, vec![1, 2], false).await.unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::ReadOnly { .. }));
+        assert!(commands.try_recv().is_err());
+        assert!(audit.0.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,61 @@
+use std::{any::Any, collections::HashMap, fmt::Debug, sync::Arc};
+
+use bytes::Bytes;
+use prost::Message;
+
+use crate::result::Result;
+
+// decoded message of a custom id, use `CustomMessage::get` to access the concrete type
+pub trait CustomMessageData: Debug + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<M: Message + 'static> CustomMessageData for M {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CustomMessage {
+    pub id: u32,
+    pub payload: Bytes,
+    pub message: Arc<dyn CustomMessageData>,
+}
+
+impl CustomMessage {
+    pub fn get<M: Message + 'static>(&self) -> Option<&M> {
+        self.message.as_any().downcast_ref::<M>()
+    }
+}
+
+type DecodeFn = Box<dyn Fn(Bytes) -> Result<Arc<dyn CustomMessageData>> + Send + Sync>;
+
+// Decoders for message ids that are not part of api.proto, e.g. added by a forked firmware
+#[derive(Default)]
+pub struct CustomMessages {
+    decoders: HashMap<u32, DecodeFn>,
+}
+
+impl CustomMessages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<M: Message + Default + 'static>(&mut self, id: u32) {
+        self.decoders.insert(
+            id,
+            Box::new(|payload| Ok(Arc::new(M::decode(payload)?) as Arc<dyn CustomMessageData>)),
+        );
+    }
+
+    // returns None when no decoder is registered for the id
+    pub fn decode(&self, id: u32, payload: &Bytes) -> Option<Result<CustomMessage>> {
+        let decoder = self.decoders.get(&id)?;
+        Some(decoder(payload.clone()).map(|message| CustomMessage {
+            id,
+            payload: payload.clone(),
+            message,
+        }))
+    }
+}
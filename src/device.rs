@@ -1,26 +1,92 @@
+use bytes::Bytes;
 use log::{debug, error, warn};
 use std::collections::HashMap;
+use std::net::Ipv6Addr;
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::time::Instant;
 
 use tokio::sync::{mpsc, Mutex};
 
+#[cfg(feature = "bluetooth")]
+use crate::bluetooth::{decoders::DecoderRegistry, gatt::GattRequests, slots::ProxyTracker, Advertisement};
 use crate::commands::DeviceMessage;
 use crate::commands::{DaviceState, DeviceCommand};
-use crate::messages::{ESPHOmeMessageRead, ESPHomeMessage, ESPHomeMessageWrite};
+use crate::custom::CustomMessages;
+#[cfg(feature = "discovery")]
+use crate::discovery::DiscoveredNode;
+use crate::messages::{ESPHOmeMessageRead, ESPHomeMessage, ESPHomeMessageWrite, FrameReader, ProtocolMode};
 use crate::{add_entity_from_response, api::*, handle_state_responses};
 use crate::{
+    audit::AuditSink,
     connection::Connection,
     entity::{Entity, EntityList},
-    result::Result,
+    policy::CommandPolicy,
+    recording::SessionRecorder,
+    redact::SecretEntities,
+    result::{Error, ErrorKind, Result},
+    stats::StatsCounters,
 };
 
+// port of the native API unless configured otherwise
+pub const DEFAULT_PORT: u16 = 6053;
+
+// host:port to pass to Device::new, the port defaults to DEFAULT_PORT and IPv6 addresses are put in brackets,
+// e.g. 10.0.0.2 becomes 10.0.0.2:6053 and fe80::1 becomes [fe80::1]:6053
+pub fn api_address(address: &str) -> String {
+    let (host, port) = split_address(address);
+    let port = port.and_then(|p| p.parse().ok()).unwrap_or(DEFAULT_PORT);
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+// host and port of host, host:port, a bare IPv6 address or [IPv6]:port
+pub(crate) fn split_address(address: &str) -> (&str, Option<&str>) {
+    if address.parse::<Ipv6Addr>().is_ok() {
+        return (address, None);
+    }
+    if let Some((host, rest)) = address.strip_prefix('[').and_then(|a| a.split_once(']')) {
+        let port = match rest {
+            "" => None,
+            rest => Some(rest.strip_prefix(':').unwrap_or(rest)),
+        };
+        return (host, port);
+    }
+    match address.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (address, None),
+    }
+}
+
+// transport of the native API connection
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+// messages without `no_delay` are held back this long to be sent together with following ones
+const BATCH_DELAY: Duration = Duration::from_millis(10);
+const WRITE_BUFFER: usize = 4096;
+
 pub struct Device {
     client_name: String,
     address: String,
     password: Option<String>,
     device_info: Option<DeviceInfoResponse>,
     entities: Arc<Mutex<EntityList>>,
+    secret_entities: SecretEntities,
+    #[cfg(feature = "bluetooth")]
+    ble_decoders: Option<Arc<DecoderRegistry>>,
+    custom_messages: Arc<CustomMessages>,
+    protocol_mode: ProtocolMode,
+    read_only: bool,
+    command_policy: Option<Arc<dyn CommandPolicy>>,
+    audit: Option<Arc<dyn AuditSink>>,
+    session_recorder: Option<SessionRecorder>,
+    services: HashMap<String, ListEntitiesServicesResponse>,
 }
 
 impl Device {
@@ -31,7 +97,68 @@ impl Device {
             password,
             device_info: None,
             entities: Arc::new(Mutex::new(EntityList::new())),
+            secret_entities: SecretEntities::default(),
+            #[cfg(feature = "bluetooth")]
+            ble_decoders: None,
+            custom_messages: Arc::new(CustomMessages::new()),
+            protocol_mode: ProtocolMode::default(),
+            read_only: false,
+            command_policy: None,
+            audit: None,
+            session_recorder: None,
+            services: HashMap::new(),
+        }
+    }
+
+    // device at the address of a node found by Discovery, nodes that expect an encrypted api are Unsupported
+    #[cfg(feature = "discovery")]
+    pub fn from_discovery(client_name: String, node: &DiscoveredNode, password: Option<String>) -> Result<Self> {
+        if let Some(encryption) = &node.api_encryption {
+            return Err(Error::with_kind(
+                ErrorKind::Unsupported,
+                &format!(
+                    "{} expects {} encryption, only plaintext connections are supported",
+                    node.name, encryption
+                ),
+            ));
         }
+        Ok(Self::new(client_name, node.address(), password))
+    }
+
+    // decode BLE advertisements forwarded by a bluetooth proxy into measurements
+    #[cfg(feature = "bluetooth")]
+    pub fn set_ble_decoders(&mut self, decoders: DecoderRegistry) {
+        self.ble_decoders = Some(Arc::new(decoders));
+    }
+
+    // decoders for message ids of a forked firmware, other unknown messages are forwarded raw
+    pub fn set_custom_messages(&mut self, custom_messages: CustomMessages) {
+        self.custom_messages = Arc::new(custom_messages);
+    }
+
+    // strict mode rejects messages sent in the wrong direction, lenient mode only logs them
+    pub fn set_protocol_mode(&mut self, protocol_mode: ProtocolMode) {
+        self.protocol_mode = protocol_mode;
+    }
+
+    // read-only connections reject everything but handshake, list, subscribe, ping, time and log messages
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    // consulted before every command targeting an entity, see PolicyEngine
+    pub fn set_command_policy<P: CommandPolicy + 'static>(&mut self, policy: P) {
+        self.command_policy = Some(Arc::new(policy));
+    }
+
+    // records every command sent to the device, e.g. JsonLinesAudit
+    pub fn set_audit_sink<S: AuditSink + 'static>(&mut self, sink: S) {
+        self.audit = Some(Arc::new(sink));
+    }
+
+    // records the frames of every connection, see SessionReplay to play them back
+    pub fn set_session_recorder(&mut self, recorder: SessionRecorder) {
+        self.session_recorder = Some(recorder);
     }
 
     pub fn device_info(&self) -> Result<DeviceInfoResponse> {
@@ -41,8 +168,20 @@ impl Device {
     }
 
     pub async fn connect(&mut self) -> Result<Connection> {
-        let mut stream = tokio::net::TcpStream::connect(&self.address).await?;
+        let stream = tokio::net::TcpStream::connect(&self.address).await?;
+        // writes are batched by the writer task, so Nagle's algorithm would only add latency
+        stream.set_nodelay(true)?;
+        match self.session_recorder.clone() {
+            Some(recorder) => self.connect_stream(recorder.wrap(stream)).await,
+            None => self.connect_stream(stream).await,
+        }
+    }
 
+    // connects over an already established transport, e.g. SessionReplay::stream
+    pub async fn connect_stream<S>(&mut self, mut stream: S) -> Result<Connection>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         // Handshaking, authorisation and request all infos
         self.send_hello(&mut stream).await?;
         self.send_auth(&mut stream).await?;
@@ -51,46 +190,62 @@ impl Device {
         self.list_entries(&mut stream).await?;
 
         // Create channels
-        let (cmd_tx, mut cmd_rx) = mpsc::channel(32); // Application -> Device
+        let (cmd_tx, cmd_rx) = mpsc::channel(32); // Application -> Device
         let (msg_tx, msg_rx) = mpsc::channel(32); // Device -> Application
 
-        let (mut esphome_rx, mut esphome_tx) = stream.into_split();
+        let (esphome_rx, esphome_tx) = tokio::io::split(stream);
+        let mut frames = FrameReader::new(esphome_rx);
+        let stats = Arc::new(StatsCounters::default());
 
         // listen for commands from application
-        tokio::spawn(async move {
-            loop {
-                let cmd = cmd_rx.recv().await;
-                match cmd {
-                    Some(cmd) => {
-                        debug!("send command to device {:?}", cmd);
-                        match cmd {
-                            DeviceCommand::Stop => (),
-                            DeviceCommand::SendMessage(msg) => {
-                                match esphome_tx.write_esphome_message(*msg).await {
-                                    Ok(()) => {}
-                                    Err(err) => {
-                                        error!("error writting message to esphome {:?}", err);
-                                    }
-                                };
-                            }
-                        }
-                    }
-                    None => break,
-                }
-            }
-        });
+        tokio::spawn(write_commands(
+            cmd_rx,
+            esphome_tx,
+            stats.clone(),
+            self.secret_entities.clone(),
+        ));
 
         // listen for messages from ESPHome
         let entities_clone = self.entities.clone();
         let cmd_tx_clone = cmd_tx.clone();
+        let custom_messages = self.custom_messages.clone();
+        let protocol_mode = self.protocol_mode;
+        let stats_clone = stats.clone();
+        let secret_entities = self.secret_entities.clone();
+        #[cfg(feature = "bluetooth")]
+        let ble_decoders = self.ble_decoders.clone();
+        #[cfg(feature = "bluetooth")]
+        let gatt_requests = Arc::new(Mutex::new(GattRequests::default()));
+        #[cfg(feature = "bluetooth")]
+        let gatt_requests_clone = gatt_requests.clone();
+        #[cfg(feature = "bluetooth")]
+        let proxy_tracker = Arc::new(ProxyTracker::new());
+        #[cfg(feature = "bluetooth")]
+        let proxy_tracker_clone = proxy_tracker.clone();
+        #[cfg(feature = "voice")]
+        let voice_requests: Arc<Mutex<Option<mpsc::Sender<VoiceAssistantRequest>>>> = Arc::new(Mutex::new(None));
+        #[cfg(feature = "voice")]
+        let voice_requests_clone = voice_requests.clone();
         tokio::spawn(async move {
             loop {
-                let msg = esphome_rx.read_esphome_message().await;
-                debug!("message from esphome {:?}", &msg);
+                let msg = frames.read_message().await;
 
                 match msg {
                     Ok(msg) => {
-                        debug!("message from esphome {:?}", &msg);
+                        debug!("message from esphome {:?}", secret_entities.redact(&msg));
+                        stats_clone.received();
+                        if !msg.sent_by_server() {
+                            error!("protocol violation, device sent {}", msg.name());
+                            if protocol_mode == ProtocolMode::Strict {
+                                continue;
+                            }
+                        }
+                        #[cfg(feature = "bluetooth")]
+                        if proxy_tracker_clone.handle_message(&msg)
+                            || gatt_requests_clone.lock().await.handle_message(&msg)
+                        {
+                            continue;
+                        }
                         match &msg {
                             ESPHomeMessage::PingRequest(_) => {
                                 if let Err(err) = cmd_tx_clone
@@ -123,6 +278,7 @@ impl Device {
                             ESPHomeMessage::TextSensorStateResponse(m) => {
                                 handle_state_responses!(msg, m, entities_clone, msg_tx)
                             }
+                            #[cfg(feature = "camera")]
                             ESPHomeMessage::CameraImageResponse(m) => {
                                 handle_state_responses!(msg, m, entities_clone, msg_tx)
                             }
@@ -138,29 +294,105 @@ impl Device {
                             ESPHomeMessage::LockStateResponse(m) => {
                                 handle_state_responses!(msg, m, entities_clone, msg_tx)
                             }
+                            #[cfg(feature = "media-player")]
                             ESPHomeMessage::MediaPlayerStateResponse(m) => {
                                 handle_state_responses!(msg, m, entities_clone, msg_tx)
                             }
+                            #[cfg(feature = "alarm")]
                             ESPHomeMessage::AlarmControlPanelStateResponse(m) => {
                                 handle_state_responses!(msg, m, entities_clone, msg_tx)
                             }
                             ESPHomeMessage::TextStateResponse(m) => {
                                 handle_state_responses!(msg, m, entities_clone, msg_tx)
                             }
+                            ESPHomeMessage::DateStateResponse(m) => {
+                                handle_state_responses!(msg, m, entities_clone, msg_tx)
+                            }
+                            ESPHomeMessage::TimeStateResponse(m) => {
+                                handle_state_responses!(msg, m, entities_clone, msg_tx)
+                            }
+                            ESPHomeMessage::EventResponse(m) => {
+                                handle_state_responses!(msg, m, entities_clone, msg_tx)
+                            }
+                            ESPHomeMessage::ValveStateResponse(m) => {
+                                handle_state_responses!(msg, m, entities_clone, msg_tx)
+                            }
+                            ESPHomeMessage::DateTimeStateResponse(m) => {
+                                handle_state_responses!(msg, m, entities_clone, msg_tx)
+                            }
+                            ESPHomeMessage::UpdateStateResponse(m) => {
+                                handle_state_responses!(msg, m, entities_clone, msg_tx)
+                            }
+                            #[cfg(feature = "bluetooth")]
+                            ESPHomeMessage::BluetoothLeAdvertisementResponse(m) => {
+                                proxy_tracker_clone.seen(m.address, m.rssi).await;
+                                forward_advertisement(Advertisement::from(m), &ble_decoders, &msg_tx).await;
+                            }
+                            #[cfg(feature = "bluetooth")]
+                            ESPHomeMessage::BluetoothLeRawAdvertisementsResponse(m) => {
+                                for raw in &m.advertisements {
+                                    proxy_tracker_clone.seen(raw.address, raw.rssi).await;
+                                    forward_advertisement(Advertisement::from_raw(raw), &ble_decoders, &msg_tx).await;
+                                }
+                            }
+                            #[cfg(feature = "voice")]
+                            ESPHomeMessage::VoiceAssistantRequest(m) => {
+                                if let Some(tx) = voice_requests_clone.lock().await.as_ref() {
+                                    if let Err(err) = tx.send(m.clone()).await {
+                                        error!("unable to send voice assistant request {:?}", err);
+                                    }
+                                }
+                            }
+                            ESPHomeMessage::SubscribeLogsResponse(m) => {
+                                if let Err(err) = msg_tx.send(DeviceMessage::Log(Box::new(m.clone()))).await {
+                                    error!("unable to send message to Application {:?}", err);
+                                }
+                            }
+                            ESPHomeMessage::Unknown { id, payload } => {
+                                forward_unknown(*id, payload, &custom_messages, &msg_tx).await;
+                            }
                             _ => (),
                         }
                     }
+                    Err(err) if *err.kind() == ErrorKind::Disconnected => {
+                        debug!("connection closed by esphome");
+                        break;
+                    }
                     Err(err) => {
                         error!("error when reading message from esphome {:?}", err);
                         break;
                     }
                 };
             }
+            #[cfg(feature = "bluetooth")]
+            gatt_requests_clone.lock().await.fail_all_disconnected();
         });
-        Ok(Connection::new(cmd_tx, msg_rx))
+        let mut connection = Connection::new(cmd_tx, msg_rx)
+            .with_stats(stats)
+            .with_secret_entities(self.secret_entities.clone())
+            .with_read_only(self.read_only)
+            .with_entities(self.entities.clone())
+            .with_command_policy(self.command_policy.clone())
+            .with_audit(self.audit.clone(), self.device_info.clone().unwrap_or_default());
+        #[cfg(feature = "bluetooth")]
+        {
+            let info = self.device_info.clone().unwrap_or_default();
+            connection = connection.with_bluetooth(
+                info.name,
+                info.bluetooth_proxy_feature_flags,
+                gatt_requests,
+                proxy_tracker,
+            );
+        }
+        #[cfg(feature = "voice")]
+        {
+            connection = connection.with_voice_requests(voice_requests);
+        }
+        connection.set_protocol_mode(self.protocol_mode);
+        Ok(connection)
     }
 
-    async fn list_entries(&mut self, stream: &mut TcpStream) -> Result<()> {
+    async fn list_entries<S: Stream>(&mut self, stream: &mut S) -> Result<()> {
         stream
             .write_esphome_message(ESPHomeMessage::ListEntitiesRequest(ListEntitiesRequest {}))
             .await?;
@@ -172,58 +404,84 @@ impl Device {
                 break;
             }
 
+            let definition = resp.clone();
             match resp {
                 ESPHomeMessage::ListEntitiesBinarySensorResponse(r) => {
-                    add_entity_from_response!(self, r); // Binary Sensor (e.g. Switches, but also other status)
+                    add_entity_from_response!(self, r, definition, "binary_sensor");
+                    // Binary Sensor (e.g. Switches, but also other status)
                 }
                 ESPHomeMessage::ListEntitiesCoverResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "cover");
                 }
                 ESPHomeMessage::ListEntitiesFanResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "fan");
                 }
                 ESPHomeMessage::ListEntitiesLightResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "light");
                 }
                 ESPHomeMessage::ListEntitiesSensorResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "sensor");
                 }
                 ESPHomeMessage::ListEntitiesSwitchResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "switch");
                 }
                 ESPHomeMessage::ListEntitiesTextSensorResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "text_sensor");
+                }
+                // user defined services are not entities, they have no state
+                ESPHomeMessage::ListEntitiesServicesResponse(r) => {
+                    self.services.insert(r.name.clone(), r);
                 }
-                // ESPHomeMessage::ListEntitiesServicesResponse(_r) => {
-                //     //TODO: need custom implementation
-                //     //add_entity_from_response!(self, r);
-                // }
+                #[cfg(feature = "camera")]
                 ESPHomeMessage::ListEntitiesCameraResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "camera");
                 }
                 ESPHomeMessage::ListEntitiesClimateResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "climate");
                 }
                 ESPHomeMessage::ListEntitiesNumberResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "number");
                 }
                 ESPHomeMessage::ListEntitiesSelectResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "select");
                 }
                 ESPHomeMessage::ListEntitiesLockResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "lock");
                 }
                 ESPHomeMessage::ListEntitiesButtonResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "button");
                 }
+                #[cfg(feature = "media-player")]
                 ESPHomeMessage::ListEntitiesMediaPlayerResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "media_player");
                 }
+                #[cfg(feature = "alarm")]
                 ESPHomeMessage::ListEntitiesAlarmControlPanelResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    add_entity_from_response!(self, r, definition, "alarm_control_panel");
                 }
                 ESPHomeMessage::ListEntitiesTextResponse(r) => {
-                    add_entity_from_response!(self, r);
+                    if r.mode == TextMode::Password as i32 {
+                        self.secret_entities.add(r.key);
+                    }
+                    add_entity_from_response!(self, r, definition, "text");
+                }
+                ESPHomeMessage::ListEntitiesDateResponse(r) => {
+                    add_entity_from_response!(self, r, definition, "date");
+                }
+                ESPHomeMessage::ListEntitiesTimeResponse(r) => {
+                    add_entity_from_response!(self, r, definition, "time");
+                }
+                ESPHomeMessage::ListEntitiesEventResponse(r) => {
+                    add_entity_from_response!(self, r, definition, "event");
+                }
+                ESPHomeMessage::ListEntitiesValveResponse(r) => {
+                    add_entity_from_response!(self, r, definition, "valve");
+                }
+                ESPHomeMessage::ListEntitiesDateTimeResponse(r) => {
+                    add_entity_from_response!(self, r, definition, "datetime");
+                }
+                ESPHomeMessage::ListEntitiesUpdateResponse(r) => {
+                    add_entity_from_response!(self, r, definition, "update");
                 }
                 _ => {
                     warn!("unexpected response {:?}", resp);
@@ -233,7 +491,7 @@ impl Device {
         Ok(())
     }
 
-    async fn send_hello(&self, stream: &mut TcpStream) -> Result<()> {
+    async fn send_hello<S: Stream>(&self, stream: &mut S) -> Result<()> {
         let message = ESPHomeMessage::HelloRequest(HelloRequest {
             client_info: self.client_name.to_string(),
             ..Default::default()
@@ -252,7 +510,7 @@ impl Device {
         Ok(())
     }
 
-    async fn send_auth(&self, stream: &mut TcpStream) -> Result<()> {
+    async fn send_auth<S: Stream>(&self, stream: &mut S) -> Result<()> {
         let message = ESPHomeMessage::ConnectRequest(ConnectRequest {
             password: self.password.clone().unwrap_or_default(),
         });
@@ -261,6 +519,9 @@ impl Device {
             let resp = stream.read_esphome_message().await?;
             if let ESPHomeMessage::ConnectResponse(r) = resp {
                 debug!("connect response received {:?}", r);
+                if r.invalid_password {
+                    return Err(Error::with_kind(ErrorKind::InvalidPassword, "invalid password"));
+                }
                 break;
             } else {
                 warn!("got unexpected response {:?}", resp);
@@ -269,7 +530,7 @@ impl Device {
         Ok(())
     }
 
-    async fn send_ping(&self, stream: &mut TcpStream) -> Result<()> {
+    async fn send_ping<S: Stream>(&self, stream: &mut S) -> Result<()> {
         stream
             .write_esphome_message(ESPHomeMessage::PingRequest(PingRequest {}))
             .await?;
@@ -285,7 +546,7 @@ impl Device {
         Ok(())
     }
 
-    async fn enquire_device_info(&mut self, stream: &mut TcpStream) -> Result<()> {
+    async fn enquire_device_info<S: Stream>(&mut self, stream: &mut S) -> Result<()> {
         stream
             .write_esphome_message(ESPHomeMessage::DeviceInfoRequest(DeviceInfoRequest {}))
             .await?;
@@ -302,6 +563,11 @@ impl Device {
         Ok(())
     }
 
+    // shared with the connections, it is filled while connecting
+    pub(crate) fn entity_list(&self) -> Arc<Mutex<EntityList>> {
+        self.entities.clone()
+    }
+
     pub async fn add_entity(&mut self, entity: Entity) {
         let mut entities = self.entities.lock().await;
         entities.add(entity);
@@ -330,8 +596,134 @@ impl Device {
         entities.entities()
     }
 
+    // user defined services of the device by name, call them with ExecuteServiceRequest
+    pub fn services(&self) -> HashMap<String, ListEntitiesServicesResponse> {
+        self.services.clone()
+    }
+
     pub async fn get_entity_key(&self, id: &str) -> Option<u32> {
         let entities = self.entities.lock().await;
         entities.get_key_for_id(id)
     }
 }
+
+#[cfg(feature = "bluetooth")]
+async fn forward_advertisement(
+    adv: Advertisement,
+    decoders: &Option<Arc<DecoderRegistry>>,
+    msg_tx: &mpsc::Sender<DeviceMessage>,
+) {
+    if let Some(decoders) = decoders {
+        for measurement in decoders.decode(&adv) {
+            if let Err(err) = msg_tx
+                .send(DeviceMessage::BluetoothMeasurement(Box::new(measurement)))
+                .await
+            {
+                error!("unable to send message to Application {:?}", err);
+            }
+        }
+    }
+    if let Err(err) = msg_tx.send(DeviceMessage::BluetoothAdvertisement(Box::new(adv))).await {
+        error!("unable to send message to Application {:?}", err);
+    }
+}
+
+async fn forward_unknown(
+    id: u32,
+    payload: &Bytes,
+    custom_messages: &CustomMessages,
+    msg_tx: &mpsc::Sender<DeviceMessage>,
+) {
+    let app_message = match custom_messages.decode(id, payload) {
+        Some(Ok(custom)) => DeviceMessage::CustomMessage(Box::new(custom)),
+        Some(Err(err)) => {
+            error!("unable to decode custom message {}: {:?}", id, err);
+            DeviceMessage::UnknownMessage {
+                id,
+                payload: payload.clone(),
+            }
+        }
+        None => DeviceMessage::UnknownMessage {
+            id,
+            payload: payload.clone(),
+        },
+    };
+    if let Err(err) = msg_tx.send(app_message).await {
+        error!("unable to send message to Application {:?}", err);
+    }
+}
+
+// Writes commands to the device, frames queued together are sent with a single write
+async fn write_commands<W: AsyncWrite + Unpin>(
+    mut cmd_rx: mpsc::Receiver<DeviceCommand>,
+    esphome_tx: W,
+    stats: Arc<StatsCounters>,
+    secret_entities: SecretEntities,
+) {
+    let mut writer = BufWriter::with_capacity(WRITE_BUFFER, esphome_tx);
+    // time at which buffered messages are sent at the latest
+    let mut flush_at: Option<Instant> = None;
+    loop {
+        let cmd = if let Some(deadline) = flush_at {
+            tokio::select! {
+                cmd = cmd_rx.recv() => cmd,
+                _ = tokio::time::sleep_until(deadline) => {
+                    if let Err(err) = writer.flush().await {
+                        error!("error writting message to esphome {:?}", err);
+                    }
+                    stats.flushed();
+                    flush_at = None;
+                    continue;
+                }
+            }
+        } else {
+            cmd_rx.recv().await
+        };
+
+        let msg = match cmd {
+            Some(DeviceCommand::SendMessage(msg)) => msg,
+            Some(DeviceCommand::Stop) => continue,
+            None => break,
+        };
+        debug!("send command to device {:?}", secret_entities.redact(&msg));
+
+        let frame = match msg.to_frame() {
+            Ok(frame) => frame,
+            Err(err) => {
+                error!("unable to encode message {:?}", err);
+                continue;
+            }
+        };
+        if let Err(err) = writer.write_all(&frame).await {
+            error!("error writting message to esphome {:?}", err);
+            continue;
+        }
+        stats.sent(frame.len());
+        flush_at.get_or_insert_with(|| Instant::now() + BATCH_DELAY);
+
+        // no_delay messages go out right away, together with everything queued so far
+        if msg.no_delay() {
+            if let Err(err) = writer.flush().await {
+                error!("error writting message to esphome {:?}", err);
+            }
+            stats.flushed();
+            flush_at = None;
+        }
+    }
+    let _ = writer.flush().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_addresses() {
+        assert_eq!(api_address("10.0.0.2"), "10.0.0.2:6053");
+        assert_eq!(api_address("10.0.0.2:6054"), "10.0.0.2:6054");
+        assert_eq!(api_address("kitchen.local"), "kitchen.local:6053");
+        assert_eq!(api_address("fe80::1"), "[fe80::1]:6053");
+        assert_eq!(api_address("[fe80::1]"), "[fe80::1]:6053");
+        assert_eq!(api_address("[fe80::1]:6054"), "[fe80::1]:6054");
+    }
+}
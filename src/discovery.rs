@@ -0,0 +1,304 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use log::{debug, warn};
+use mdns_sd::{IfKind, Receiver, ServiceDaemon, ServiceEvent, ServiceInfo};
+
+use crate::result::Result;
+
+// service announced by the api component of ESPHome
+pub const SERVICE_TYPE: &str = "_esphomelib._tcp.local.";
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// A node found on the network, TXT records ESPHome announces are copied into fields
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiscoveredNode {
+    // instance name, the node name of the device, e.g. bedroom
+    pub name: String,
+    // e.g. bedroom.local.
+    pub host: String,
+    // IPv4 addresses first
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub mac: Option<String>,
+    pub version: Option<String>,
+    pub platform: Option<String>,
+    pub board: Option<String>,
+    pub friendly_name: Option<String>,
+    // encryption the api expects, e.g. Noise_NNpsk0_25519_ChaChaPoly_SHA256
+    pub api_encryption: Option<String>,
+    pub project_name: Option<String>,
+    // all TXT records, including the ones above
+    pub txt: HashMap<String, String>,
+}
+
+impl DiscoveredNode {
+    fn from_service(info: &ServiceInfo) -> Self {
+        let txt: HashMap<String, String> = info
+            .get_properties()
+            .iter()
+            .map(|p| (p.key().to_string(), p.val_str().to_string()))
+            .collect();
+        let mut addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+        addresses.sort_by_key(|a| (a.is_ipv6(), *a));
+        let field = |key: &str| txt.get(key).cloned();
+        Self {
+            name: instance_name(info.get_fullname()).to_string(),
+            host: info.get_hostname().to_string(),
+            addresses,
+            port: info.get_port(),
+            mac: field("mac"),
+            version: field("version"),
+            platform: field("platform"),
+            board: field("board"),
+            friendly_name: field("friendly_name"),
+            api_encryption: field("api_encryption"),
+            project_name: field("project_name"),
+            txt,
+        }
+    }
+
+    // address to pass to Device::new, the host name when no address was resolved
+    pub fn address(&self) -> String {
+        match self.addresses.first() {
+            Some(ip) => SocketAddr::new(*ip, self.port).to_string(),
+            None => format!("{}:{}", self.host.trim_end_matches('.'), self.port),
+        }
+    }
+}
+
+fn instance_name(fullname: &str) -> &str {
+    fullname
+        .strip_suffix(SERVICE_TYPE)
+        .map(|name| name.trim_end_matches('.'))
+        .unwrap_or(fullname)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiscoveryEvent {
+    Added(DiscoveredNode),
+    // announced again with other addresses or TXT records, e.g. after an update of the firmware
+    Updated(DiscoveredNode),
+    // last known state of a node that said goodbye or whose records expired
+    Removed(DiscoveredNode),
+}
+
+// Browses the network for ESPHome nodes until dropped
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    events: Receiver<ServiceEvent>,
+    nodes: HashMap<String, DiscoveredNode>,
+}
+
+impl Discovery {
+    pub fn new() -> Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+        let events = daemon.browse(SERVICE_TYPE)?;
+        Ok(Self {
+            daemon,
+            events,
+            nodes: HashMap::new(),
+        })
+    }
+
+    // also browse on the loopback interface, e.g. to find an Announcement of the same host
+    pub fn enable_loopback(&self) -> Result<()> {
+        self.daemon.enable_interface(IfKind::LoopbackV4)?;
+        Ok(())
+    }
+
+    // nodes found so far and not removed
+    pub fn nodes(&self) -> Vec<DiscoveredNode> {
+        self.nodes.values().cloned().collect()
+    }
+
+    // None once the browsing stopped
+    pub async fn wait_for_event(&mut self) -> Option<DiscoveryEvent> {
+        loop {
+            let event = self.events.recv_async().await.ok()?;
+            match event {
+                ServiceEvent::ServiceResolved(info) => {
+                    let node = DiscoveredNode::from_service(&info);
+                    debug!("discovered {} at {}", node.name, node.address());
+                    match self.nodes.insert(info.get_fullname().to_string(), node.clone()) {
+                        None => return Some(DiscoveryEvent::Added(node)),
+                        Some(previous) if previous != node => return Some(DiscoveryEvent::Updated(node)),
+                        Some(_) => (),
+                    }
+                }
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    if let Some(node) = self.nodes.remove(&fullname) {
+                        debug!("{} left the network", node.name);
+                        return Some(DiscoveryEvent::Removed(node));
+                    }
+                }
+                ServiceEvent::SearchStopped(_) => return None,
+                _ => (),
+            }
+        }
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        if let Err(err) = self.daemon.shutdown() {
+            warn!("unable to stop mDNS browsing {:?}", err);
+        }
+    }
+}
+
+// Announces a node like an ESPHome device does, a stand-in for real devices e.g. in front of a SimulatedDevice,
+// the node is removed when dropped
+pub struct Announcement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Announcement {
+    // name, host, addresses, port and txt of the node are announced, the other fields are not read
+    pub fn new(node: &DiscoveredNode) -> Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+        if node.addresses.iter().any(|a| a.is_loopback()) {
+            daemon.enable_interface(IfKind::LoopbackV4)?;
+        }
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &node.name,
+            &node.host,
+            &node.addresses[..],
+            node.port,
+            node.txt.clone(),
+        )?;
+        let fullname = info.get_fullname().to_string();
+        daemon.register(info)?;
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for Announcement {
+    fn drop(&mut self) {
+        // sends the goodbye packets before the daemon stops
+        if let Err(err) = self
+            .daemon
+            .unregister(&self.fullname)
+            .map(|status| status.recv_timeout(GOODBYE_TIMEOUT))
+            .and_then(|_| self.daemon.shutdown())
+        {
+            warn!("unable to remove announcement {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::{device::Device, result::ErrorKind};
+
+    fn node(name: &str, addresses: Vec<IpAddr>) -> DiscoveredNode {
+        DiscoveredNode {
+            name: name.to_string(),
+            host: format!("{}.local.", name),
+            addresses,
+            port: 6053,
+            txt: HashMap::from([
+                ("mac".to_string(), "AABBCCDDEEFF".to_string()),
+                ("version".to_string(), "2024.6.0".to_string()),
+                ("platform".to_string(), "ESP32".to_string()),
+                ("friendly_name".to_string(), "Bedroom".to_string()),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn txt_records() {
+        let v6: IpAddr = "fe80::1".parse().unwrap();
+        let v4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 12));
+        let announced = node("bedroom", vec![v6, v4]);
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &announced.name,
+            &announced.host,
+            &announced.addresses[..],
+            announced.port,
+            announced.txt.clone(),
+        )
+        .unwrap();
+        let found = DiscoveredNode::from_service(&info);
+        assert_eq!(found.name, "bedroom");
+        assert_eq!(found.host, "bedroom.local.");
+        assert_eq!(found.addresses, vec![v4, v6]);
+        assert_eq!(found.port, 6053);
+        assert_eq!(found.mac.as_deref(), Some("AABBCCDDEEFF"));
+        assert_eq!(found.version.as_deref(), Some("2024.6.0"));
+        assert_eq!(found.platform.as_deref(), Some("ESP32"));
+        assert_eq!(found.friendly_name.as_deref(), Some("Bedroom"));
+        assert_eq!(found.board, None);
+        assert_eq!(found.api_encryption, None);
+        assert_eq!(found.txt, announced.txt);
+    }
+
+    #[test]
+    fn addresses() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 12));
+        let v6: IpAddr = "fe80::1".parse().unwrap();
+        assert_eq!(node("bedroom", vec![v4, v6]).address(), "10.0.0.12:6053");
+        assert_eq!(node("bedroom", vec![v6]).address(), "[fe80::1]:6053");
+        assert_eq!(node("bedroom", vec![]).address(), "bedroom.local:6053");
+        assert_eq!(instance_name("bedroom._esphomelib._tcp.local."), "bedroom");
+        assert_eq!(instance_name("bedroom.local."), "bedroom.local.");
+    }
+
+    #[test]
+    fn encrypted_nodes_are_unsupported() {
+        let mut node = node("bedroom", vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 12))]);
+        assert!(Device::from_discovery("test".to_string(), &node, None).is_ok());
+        node.api_encryption = Some("Noise_NNpsk0_25519_ChaChaPoly_SHA256".to_string());
+        let err = Device::from_discovery("test".to_string(), &node, None).err().unwrap();
+        assert_eq!(err.kind(), &ErrorKind::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn announced_nodes_are_discovered() {
+        let name = format!("test-{}", std::process::id());
+        let announced = node(&name, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        let mut discovery = Discovery::new().unwrap();
+        discovery.enable_loopback().unwrap();
+        let announcement = Announcement::new(&announced).unwrap();
+
+        let found = timeout(Duration::from_secs(10), async {
+            loop {
+                match discovery.wait_for_event().await.unwrap() {
+                    DiscoveryEvent::Added(node) if node.name == name => return node,
+                    _ => (),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(found.address(), "127.0.0.1:6053");
+        assert_eq!(found.friendly_name.as_deref(), Some("Bedroom"));
+        assert!(discovery.nodes().contains(&found));
+
+        drop(announcement);
+        let removed = timeout(Duration::from_secs(10), async {
+            loop {
+                match discovery.wait_for_event().await.unwrap() {
+                    DiscoveryEvent::Removed(node) if node.name == name => return node,
+                    _ => (),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(removed, found);
+        assert!(!discovery.nodes().iter().any(|n| n.name == name));
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use crate::result::{Error, Result};
+
+// durations like ESPHome writes them: 500ms, 10s, 5min, 1h, plain numbers are seconds
+pub(crate) fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| Error::new(&format!("invalid duration {}", value)))?;
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "min" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(Error::new(&format!("invalid duration {}", value))),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| Error::new(&format!("invalid duration {}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration(" 1.5 ").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("5min").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10 days").is_err());
+        assert!(parse_duration("1.2.3s").is_err());
+        // larger than a Duration can hold
+        assert!(parse_duration(&format!("{}h", "9".repeat(400))).is_err());
+    }
+}
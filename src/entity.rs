@@ -1,25 +1,71 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
 
-use crate::{api::EntityCategory, messages::ESPHomeMessage, result::Error, result::Result};
+use crate::{
+    api::{EntityCategory, TextMode},
+    messages::ESPHomeMessage,
+    redact::mask_secret,
+    result::Error,
+    result::Result,
+};
 
 #[derive(Clone, Debug)]
 pub struct Entity {
     pub id: String,
     pub key: u32,
+    // e.g. switch, light or alarm_control_panel
+    pub domain: &'static str,
     pub name: String,
     pub icon: String,
     pub category: EntityCategory,
     pub unique_id: String,
     pub entity_data: Arc<Box<dyn prost::Message>>,
+    // ListEntities response of the entity, e.g. min, max and step of a number or the options of a select
+    pub definition: ESPHomeMessage,
     pub state: ESPHomeMessage,
 }
 
 impl Entity {
+    // domain and object id, e.g. switch.pump
+    pub fn entity_id(&self) -> String {
+        format!("{}.{}", self.domain, self.id)
+    }
+
+    // entity as listed by the device before its first state, for tests of code working with entities
+    #[doc(hidden)]
+    pub fn from_definition(domain: &'static str, definition: ESPHomeMessage) -> Self {
+        let fields = definition.fields();
+        let text = |name: &str| fields[name].as_str().unwrap_or_default().to_string();
+        let category = fields["entity_category"].as_i64().unwrap_or_default() as i32;
+        Self {
+            id: text("object_id"),
+            key: fields["key"].as_u64().unwrap_or_default() as u32,
+            domain,
+            name: text("name"),
+            icon: text("icon"),
+            category: EntityCategory::try_from(category).unwrap_or_default(),
+            unique_id: text("unique_id"),
+            entity_data: Arc::new(definition.boxed().unwrap_or_else(|| Box::new(()))),
+            definition,
+            state: ESPHomeMessage::None,
+        }
+    }
+
     pub fn update_state(&mut self, new_state: ESPHomeMessage) {
         self.state = new_state;
     }
+
+    // text entities in password mode, their state is not shown or logged
+    pub fn is_secret(&self) -> bool {
+        matches!(&self.definition, ESPHomeMessage::ListEntitiesTextResponse(d) if d.mode == TextMode::Password as i32)
+    }
+
+    // state for showing it to users, with the value of secret entities masked
+    pub fn masked_state(&self) -> Cow<'_, ESPHomeMessage> {
+        mask_secret(&self.state, |_| self.is_secret())
+    }
 }
 
+#[derive(Default)]
 pub struct EntityList {
     entities: HashMap<String, Entity>,     // Using String as key type
     entities_by_key: HashMap<u32, String>, // Lookup by key
@@ -67,3 +113,53 @@ impl EntityList {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{ListEntitiesTextResponse, TextStateResponse};
+
+    fn text(mode: TextMode) -> Entity {
+        let mut entity = Entity::from_definition(
+            "text",
+            ESPHomeMessage::ListEntitiesTextResponse(ListEntitiesTextResponse {
+                object_id: "wifi_key".to_string(),
+                key: 1,
+                name: "WiFi key".to_string(),
+                entity_category: EntityCategory::Config as i32,
+                mode: mode as i32,
+                ..Default::default()
+            }),
+        );
+        entity.update_state(ESPHomeMessage::TextStateResponse(TextStateResponse {
+            key: 1,
+            state: "hunter2".to_string(),
+            missing_state: false,
+        }));
+        entity
+    }
+
+    #[test]
+    fn from_definition() {
+        let entity = text(TextMode::Text);
+        assert_eq!(entity.entity_id(), "text.wifi_key");
+        assert_eq!(entity.key, 1);
+        assert_eq!(entity.name, "WiFi key");
+        assert_eq!(entity.category, EntityCategory::Config);
+    }
+
+    #[test]
+    fn password_text_is_masked() {
+        let entity = text(TextMode::Password);
+        assert!(entity.is_secret());
+        let ESPHomeMessage::TextStateResponse(state) = entity.masked_state().into_owned() else {
+            panic!("not a text state");
+        };
+        assert_eq!(state.state, "<redacted>");
+        assert_eq!(state.key, 1);
+
+        let entity = text(TextMode::Text);
+        assert!(!entity.is_secret());
+        assert_eq!(entity.masked_state().as_ref(), &entity.state);
+    }
+}
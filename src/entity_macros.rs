@@ -1,15 +1,17 @@
 #[macro_export]
 macro_rules! add_entity_from_response {
-    ($self:expr, $msg:expr) => {
+    ($self:expr, $msg:expr, $definition:expr, $domain:expr) => {
         let d = Box::new($msg.clone()) as Box<dyn prost::Message>;
         let entity = Entity {
             id: $msg.object_id,
             key: $msg.key,
+            domain: $domain,
             name: $msg.name,
             icon: $msg.icon,
             category: EntityCategory::try_from($msg.entity_category).unwrap_or_default(),
             unique_id: $msg.unique_id,
             state: ESPHomeMessage::None,
+            definition: $definition,
             entity_data: Arc::new(d),
         };
 
@@ -0,0 +1,597 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, error, warn};
+use tokio::{
+    sync::{mpsc, oneshot, Mutex, Semaphore},
+    task::{JoinHandle, JoinSet},
+    time::{sleep, timeout},
+};
+
+use crate::{
+    commands::{DeviceCommand, DeviceMessage},
+    connection::Connection,
+    device::Device,
+    entity::{Entity, EntityList},
+    messages::ESPHomeMessage,
+    result::{Error, Result},
+    stats::{ConnectionStats, StatsCounters},
+};
+
+mod config;
+
+pub use config::{DeviceConfig, FleetConfig, ReconnectConfig, Subscription};
+
+const DEFAULT_MAX_PARALLEL_CONNECTS: usize = 8;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const EVENT_BUFFER: usize = 1024;
+const COMMAND_BUFFER: usize = 16;
+
+// A message of one device of the fleet
+#[derive(Debug)]
+pub struct FleetEvent {
+    // name the device was added with
+    pub device: String,
+    pub mac_address: String,
+    pub message: DeviceMessage,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HealthStatus {
+    NotConnected,
+    Connecting,
+    Connected,
+    // closed by the device or the network, Fleet::connect connects it again
+    Disconnected,
+    Failed(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceHealth {
+    pub name: String,
+    // known once the device was connected
+    pub mac_address: Option<String>,
+    pub status: HealthStatus,
+    pub connected_since: Option<DateTime<Utc>>,
+    pub last_message: Option<DateTime<Utc>>,
+    // connection attempts failed in a row
+    pub failed_attempts: u32,
+    // traffic of the current or last connection
+    pub stats: Option<ConnectionStats>,
+}
+
+// Delay before connecting a failed or disconnected device again, doubled after every failed attempt
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    fn delay(&self, failed_attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failed_attempts.saturating_sub(1));
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+type FleetCommand = (DeviceCommand, oneshot::Sender<Result<()>>);
+
+struct MemberState {
+    status: HealthStatus,
+    mac_address: Option<String>,
+    connected_since: Option<DateTime<Utc>>,
+    last_message: Option<DateTime<Utc>>,
+    failed_attempts: u32,
+    stats: Option<Arc<StatsCounters>>,
+    commands: Option<mpsc::Sender<FleetCommand>>,
+    // waiting for the reconnect delay, replaced when Fleet::connect connects the device first
+    reconnect: Option<JoinHandle<()>>,
+}
+
+struct Member {
+    name: String,
+    // locked while connecting
+    device: Mutex<Device>,
+    entities: Arc<Mutex<EntityList>>,
+    // sent after every connect
+    subscriptions: StdMutex<Vec<ESPHomeMessage>>,
+    // alias to object id or entity id
+    aliases: StdMutex<HashMap<String, String>>,
+    state: StdMutex<MemberState>,
+}
+
+impl Member {
+    fn health(&self) -> DeviceHealth {
+        let state = self.state.lock().unwrap();
+        DeviceHealth {
+            name: self.name.clone(),
+            mac_address: state.mac_address.clone(),
+            status: state.status.clone(),
+            connected_since: state.connected_since,
+            last_message: state.last_message,
+            failed_attempts: state.failed_attempts,
+            stats: state.stats.as_ref().map(|s| s.snapshot()),
+        }
+    }
+
+    fn matches(&self, name_or_mac: &str) -> bool {
+        self.name == name_or_mac
+            || self
+                .state
+                .lock()
+                .unwrap()
+                .mac_address
+                .as_deref()
+                .is_some_and(|mac| mac.eq_ignore_ascii_case(name_or_mac))
+    }
+}
+
+// settings the connect and reconnect tasks need
+#[derive(Clone)]
+struct Connector {
+    slots: Arc<Semaphore>,
+    connect_timeout: Duration,
+    reconnect: Option<ReconnectPolicy>,
+    events: mpsc::Sender<FleetEvent>,
+}
+
+// Many devices keyed by name or MAC address, their messages are merged into one stream of FleetEvents
+pub struct Fleet {
+    members: Vec<Arc<Member>>,
+    connector: Connector,
+    events: Mutex<mpsc::Receiver<FleetEvent>>,
+}
+
+impl Default for Fleet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fleet {
+    pub fn new() -> Self {
+        let (events_tx, events) = mpsc::channel(EVENT_BUFFER);
+        Self {
+            members: Vec::new(),
+            connector: Connector {
+                slots: Arc::new(Semaphore::new(DEFAULT_MAX_PARALLEL_CONNECTS)),
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                reconnect: None,
+                events: events_tx,
+            },
+            events: Mutex::new(events),
+        }
+    }
+
+    // devices connecting at the same time, keeps a large fleet from flooding the network
+    pub fn set_max_parallel_connects(&mut self, max_parallel_connects: usize) {
+        self.connector.slots = Arc::new(Semaphore::new(max_parallel_connects.max(1)));
+    }
+
+    pub fn set_connect_timeout(&mut self, connect_timeout: Duration) {
+        self.connector.connect_timeout = connect_timeout;
+    }
+
+    // without a policy failed and disconnected devices wait for the next Fleet::connect
+    pub fn set_reconnect(&mut self, reconnect: Option<ReconnectPolicy>) {
+        self.connector.reconnect = reconnect;
+    }
+
+    pub fn add_device(&mut self, name: &str, device: Device) -> Result<()> {
+        if self.members.iter().any(|m| m.name == name) {
+            return Err(Error::new(&format!("device {} is already part of the fleet", name)));
+        }
+        self.members.push(Arc::new(Member {
+            name: name.to_string(),
+            entities: device.entity_list(),
+            device: Mutex::new(device),
+            subscriptions: StdMutex::new(Vec::new()),
+            aliases: StdMutex::new(HashMap::new()),
+            state: StdMutex::new(MemberState {
+                status: HealthStatus::NotConnected,
+                mac_address: None,
+                connected_since: None,
+                last_message: None,
+                failed_attempts: 0,
+                stats: None,
+                commands: None,
+                reconnect: None,
+            }),
+        }));
+        Ok(())
+    }
+
+    // request sent to the device after every connect, e.g. SubscribeStatesRequest
+    pub fn subscribe(&mut self, device: &str, request: ESPHomeMessage) -> Result<()> {
+        self.known_member(device)?.subscriptions.lock().unwrap().push(request);
+        Ok(())
+    }
+
+    // another name of an entity in Fleet::entity, e.g. "temperature" for "sensor.bme280_temperature"
+    pub fn add_alias(&mut self, device: &str, alias: &str, entity: &str) -> Result<()> {
+        self.known_member(device)?
+            .aliases
+            .lock()
+            .unwrap()
+            .insert(alias.to_string(), entity.to_string());
+        Ok(())
+    }
+
+    // connects every device that is not connected and returns once each one connected or failed,
+    // failures are reported by health
+    pub async fn connect(&self) {
+        let mut attempts = JoinSet::new();
+        for member in &self.members {
+            if let Some(reconnect) = member.state.lock().unwrap().reconnect.take() {
+                reconnect.abort();
+            }
+            attempts.spawn(connect_member(member.clone(), self.connector.clone()));
+        }
+        while let Some(result) = attempts.join_next().await {
+            if let Err(err) = result {
+                error!("connecting a fleet device failed {:?}", err);
+            }
+        }
+    }
+
+    // next message of any device, they are buffered and the devices are held back once the buffer is full
+    pub async fn wait_for_event(&self) -> Option<FleetEvent> {
+        self.events.lock().await.recv().await
+    }
+
+    pub async fn send_message(&self, device: &str, cmd: DeviceCommand) -> Result<()> {
+        let member = self.known_member(device)?;
+        let commands = member.state.lock().unwrap().commands.clone();
+        let commands = commands.ok_or_else(|| Error::new(&format!("device {} is not connected", device)))?;
+        let (reply, result) = oneshot::channel();
+        commands.send((cmd, reply)).await?;
+        result.await?
+    }
+
+    // entity by device and object id, entity id or alias, e.g. "bedroom/temperature" or
+    // "bedroom/sensor.temperature", the device is given by name or MAC address
+    pub async fn entity(&self, path: &str) -> Option<Entity> {
+        let (device, id) = path.split_once('/')?;
+        let member = self.member(device)?;
+        let id = member
+            .aliases
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .unwrap_or(id.to_string());
+        let entities = member.entities.lock().await;
+        match id.split_once('.') {
+            Some((domain, object_id)) => entities.get(object_id).filter(|e| e.domain == domain),
+            None => entities.get(&id),
+        }
+    }
+
+    pub fn health(&self) -> Vec<DeviceHealth> {
+        self.members.iter().map(|m| m.health()).collect()
+    }
+
+    pub fn device_health(&self, device: &str) -> Option<DeviceHealth> {
+        self.member(device).map(|m| m.health())
+    }
+
+    fn member(&self, name_or_mac: &str) -> Option<&Arc<Member>> {
+        self.members.iter().find(|m| m.matches(name_or_mac))
+    }
+
+    fn known_member(&self, name_or_mac: &str) -> Result<&Arc<Member>> {
+        self.member(name_or_mac)
+            .ok_or_else(|| Error::new(&format!("unknown device {}", name_or_mac)))
+    }
+}
+
+async fn connect_member(member: Arc<Member>, connector: Connector) {
+    let Ok(_slot) = connector.slots.clone().acquire_owned().await else {
+        return;
+    };
+    {
+        let mut state = member.state.lock().unwrap();
+        if matches!(state.status, HealthStatus::Connecting | HealthStatus::Connected) {
+            return;
+        }
+        state.status = HealthStatus::Connecting;
+    }
+
+    let mut device = member.device.lock().await;
+    let result = match timeout(connector.connect_timeout, device.connect()).await {
+        Ok(result) => result,
+        Err(elapsed) => Err(elapsed.into()),
+    };
+    let mut connection = match result {
+        Ok(connection) => connection,
+        Err(err) => {
+            drop(device);
+            connect_failed(member, connector, err);
+            return;
+        }
+    };
+    let mac_address = device.device_info().map(|i| i.mac_address).unwrap_or_default();
+    drop(device);
+
+    let subscriptions = member.subscriptions.lock().unwrap().clone();
+    for request in subscriptions {
+        if let Err(err) = connection
+            .send_message(DeviceCommand::SendMessage(Box::new(request)))
+            .await
+        {
+            connect_failed(member, connector, err);
+            return;
+        }
+    }
+    debug!("fleet device {} connected", member.name);
+
+    let (commands_tx, commands) = mpsc::channel(COMMAND_BUFFER);
+    {
+        let mut state = member.state.lock().unwrap();
+        state.status = HealthStatus::Connected;
+        state.mac_address = Some(mac_address.clone());
+        state.connected_since = Some(Utc::now());
+        state.failed_attempts = 0;
+        state.stats = Some(connection.stats_counters());
+        state.commands = Some(commands_tx);
+    }
+    tokio::spawn(serve_member(member, mac_address, connection, commands, connector));
+}
+
+fn connect_failed(member: Arc<Member>, connector: Connector, err: Error) {
+    warn!("unable to connect {}: {}", member.name, err);
+    {
+        let mut state = member.state.lock().unwrap();
+        state.status = HealthStatus::Failed(err.to_string());
+        state.failed_attempts += 1;
+    }
+    schedule_reconnect(member, connector);
+}
+
+fn schedule_reconnect(member: Arc<Member>, connector: Connector) {
+    let Some(reconnect) = connector.reconnect else {
+        return;
+    };
+    let mut state = member.state.lock().unwrap();
+    let delay = reconnect.delay(state.failed_attempts);
+    debug!("reconnect {} in {:?}", member.name, delay);
+    if let Some(pending) = state.reconnect.take() {
+        pending.abort();
+    }
+    let task_member = member.clone();
+    // spawned while holding the lock, the task can only take itself out of the state once it is stored
+    state.reconnect = Some(tokio::spawn(async move {
+        sleep(delay).await;
+        // no longer abortable, it would leave the device connecting
+        task_member.state.lock().unwrap().reconnect = None;
+        // the fleet was dropped
+        if !connector.events.is_closed() {
+            Box::pin(connect_member(task_member, connector)).await;
+        }
+    }));
+}
+
+// forwards the messages of one device and sends the commands given to the fleet
+async fn serve_member(
+    member: Arc<Member>,
+    mac_address: String,
+    mut connection: Connection,
+    mut commands: mpsc::Receiver<FleetCommand>,
+    connector: Connector,
+) {
+    let events = connector.events.clone();
+    loop {
+        tokio::select! {
+            msg = connection.wait_for_message() => {
+                let message = msg.unwrap_or(DeviceMessage::Disconnected);
+                let disconnected = matches!(message, DeviceMessage::Disconnected);
+                member.state.lock().unwrap().last_message = Some(Utc::now());
+                let event = FleetEvent {
+                    device: member.name.clone(),
+                    mac_address: mac_address.clone(),
+                    message,
+                };
+                if events.send(event).await.is_err() {
+                    return;
+                }
+                if disconnected {
+                    break;
+                }
+            }
+            Some((cmd, reply)) = commands.recv() => {
+                let _ = reply.send(connection.send_message(cmd).await);
+            }
+            _ = events.closed() => return,
+        }
+    }
+    {
+        let mut state = member.state.lock().unwrap();
+        state.status = HealthStatus::Disconnected;
+        state.commands = None;
+    }
+    schedule_reconnect(member, connector);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::SubscribeStatesRequest,
+        simulator::{Failures, SimulatedDevice, SimulatedEntity, SimulatorHandle},
+    };
+
+    // address nothing listens on, connecting fails right away
+    fn closed_address() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn connect_replaces_pending_reconnect() {
+        let mut fleet = Fleet::new();
+        fleet.set_reconnect(Some(ReconnectPolicy {
+            initial_delay: Duration::from_secs(60),
+            max_delay: Duration::from_secs(60),
+        }));
+        fleet
+            .add_device("pump", Device::new("test".to_string(), closed_address(), None))
+            .unwrap();
+
+        fleet.connect().await;
+        let health = fleet.device_health("pump").unwrap();
+        assert!(matches!(health.status, HealthStatus::Failed(_)));
+        assert_eq!(health.failed_attempts, 1);
+        let first = fleet.members[0]
+            .state
+            .lock()
+            .unwrap()
+            .reconnect
+            .as_ref()
+            .unwrap()
+            .abort_handle();
+
+        fleet.connect().await;
+        assert_eq!(fleet.device_health("pump").unwrap().failed_attempts, 2);
+        tokio::task::yield_now().await;
+        assert!(first.is_finished());
+        assert!(fleet.members[0].state.lock().unwrap().reconnect.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_runs_once() {
+        let mut fleet = Fleet::new();
+        fleet.set_reconnect(Some(ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(60),
+        }));
+        // without a port the address fails in the resolver, no network I/O lets the paused clock run ahead
+        fleet
+            .add_device("pump", Device::new("test".to_string(), "127.0.0.1".to_string(), None))
+            .unwrap();
+
+        // the second call must not start another chain of reconnects
+        fleet.connect().await;
+        fleet.connect().await;
+        assert_eq!(fleet.device_health("pump").unwrap().failed_attempts, 2);
+        // attempts 3 and 4 follow after 200ms and another 400ms
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(fleet.device_health("pump").unwrap().failed_attempts, 3);
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(fleet.device_health("pump").unwrap().failed_attempts, 4);
+    }
+
+    async fn add_simulator(fleet: &mut Fleet, name: &str, simulator: SimulatedDevice) -> SimulatorHandle {
+        let handle = simulator.listen("127.0.0.1:0").await.unwrap();
+        let device = Device::new("test".to_string(), handle.local_addr().to_string(), None);
+        fleet.add_device(name, device).unwrap();
+        handle
+    }
+
+    #[tokio::test]
+    async fn events_are_tagged_with_their_device() {
+        let mut fleet = Fleet::new();
+        let mut handles = Vec::new();
+        for name in ["greenhouse", "attic"] {
+            let mut simulator = SimulatedDevice::new(name);
+            simulator.add_entity(SimulatedEntity::sensor("temperature", "Temperature", 20.0));
+            handles.push(add_simulator(&mut fleet, name, simulator).await);
+            fleet
+                .subscribe(name, ESPHomeMessage::SubscribeStatesRequest(SubscribeStatesRequest {}))
+                .unwrap();
+        }
+        fleet.connect().await;
+
+        let mut devices = Vec::new();
+        for _ in 0..2 {
+            let event = timeout(Duration::from_secs(5), fleet.wait_for_event())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(matches!(event.message, DeviceMessage::DeviceStateChange(_)));
+            let health = fleet.device_health(&event.device).unwrap();
+            assert_eq!(health.mac_address.as_ref(), Some(&event.mac_address));
+            devices.push(event.device);
+        }
+        devices.sort();
+        assert_eq!(devices, ["attic", "greenhouse"]);
+        assert_ne!(
+            fleet.device_health("attic").unwrap().mac_address,
+            fleet.device_health("greenhouse").unwrap().mac_address
+        );
+    }
+
+    async fn entity_id(fleet: &Fleet, path: String) -> Option<(&'static str, String)> {
+        fleet.entity(&path).await.map(|e| (e.domain, e.id))
+    }
+
+    #[tokio::test]
+    async fn entity_lookup() {
+        let mut fleet = Fleet::new();
+        let mut simulator = SimulatedDevice::new("greenhouse");
+        simulator.add_entity(SimulatedEntity::sensor("temperature", "Temperature", 20.0));
+        simulator.add_entity(SimulatedEntity::switch("pump", "Pump", false));
+        let _handle = add_simulator(&mut fleet, "greenhouse", simulator).await;
+        fleet.add_alias("greenhouse", "heat", "sensor.temperature").unwrap();
+        fleet.add_alias("greenhouse", "water", "pump").unwrap();
+        fleet.connect().await;
+        let mac_address = fleet.device_health("greenhouse").unwrap().mac_address.unwrap();
+
+        let id = |path: String| entity_id(&fleet, path);
+        let temperature = Some(("sensor", "temperature".to_string()));
+        assert_eq!(id("greenhouse/temperature".to_string()).await, temperature);
+        assert_eq!(id("greenhouse/sensor.temperature".to_string()).await, temperature);
+        assert_eq!(id("greenhouse/heat".to_string()).await, temperature);
+        assert_eq!(id(format!("{}/temperature", mac_address)).await, temperature);
+        assert_eq!(
+            id(format!("{}/temperature", mac_address.to_lowercase())).await,
+            temperature
+        );
+        assert_eq!(
+            id("greenhouse/water".to_string()).await,
+            Some(("switch", "pump".to_string()))
+        );
+
+        assert_eq!(id("greenhouse/switch.temperature".to_string()).await, None);
+        assert_eq!(id("greenhouse/humidity".to_string()).await, None);
+        assert_eq!(id("attic/temperature".to_string()).await, None);
+        assert_eq!(id("temperature".to_string()).await, None);
+    }
+
+    #[tokio::test]
+    async fn parallel_connects_are_bounded() {
+        let mut fleet = Fleet::new();
+        fleet.set_max_parallel_connects(2);
+        let mut handles = Vec::new();
+        for name in ["greenhouse", "attic", "garage", "shed"] {
+            let mut simulator = SimulatedDevice::new(name);
+            simulator.set_failures(Failures {
+                response_delay: Some(Duration::from_millis(20)),
+                ..Default::default()
+            });
+            handles.push(add_simulator(&mut fleet, name, simulator).await);
+        }
+
+        let connecting = || {
+            fleet
+                .health()
+                .iter()
+                .filter(|h| h.status == HealthStatus::Connecting)
+                .count()
+        };
+        let connect = fleet.connect();
+        tokio::pin!(connect);
+        let mut most_connecting = 0;
+        loop {
+            tokio::select! {
+                _ = &mut connect => break,
+                _ = sleep(Duration::from_millis(5)) => most_connecting = most_connecting.max(connecting()),
+            }
+        }
+        assert_eq!(most_connecting, 2);
+        assert!(fleet.health().iter().all(|h| h.status == HealthStatus::Connected));
+    }
+}
@@ -0,0 +1,466 @@
+use std::{collections::HashMap, env, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    api::{
+        LogLevel, SubscribeHomeAssistantStatesRequest, SubscribeHomeassistantServicesRequest, SubscribeLogsRequest,
+        SubscribeStatesRequest,
+    },
+    device::{api_address, split_address, Device},
+    duration::parse_duration,
+    fleet::{Fleet, ReconnectPolicy, DEFAULT_MAX_PARALLEL_CONNECTS},
+    messages::ESPHomeMessage,
+    result::{Error, ErrorKind, Result},
+};
+
+const DEFAULT_CLIENT_NAME: &str = "esphome-client-rs";
+const DEFAULT_CONNECT_TIMEOUT: &str = "10s";
+const DEFAULT_INITIAL_DELAY: &str = "1s";
+const DEFAULT_MAX_DELAY: &str = "5min";
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+fn default_client_name() -> String {
+    DEFAULT_CLIENT_NAME.to_string()
+}
+
+fn default_max_parallel_connects() -> usize {
+    DEFAULT_MAX_PARALLEL_CONNECTS
+}
+
+fn default_connect_timeout() -> String {
+    DEFAULT_CONNECT_TIMEOUT.to_string()
+}
+
+fn default_initial_delay() -> String {
+    DEFAULT_INITIAL_DELAY.to_string()
+}
+
+fn default_max_delay() -> String {
+    DEFAULT_MAX_DELAY.to_string()
+}
+
+fn default_log_level() -> String {
+    DEFAULT_LOG_LEVEL.to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReconnectConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_initial_delay")]
+    pub initial_delay: String,
+    #[serde(default = "default_max_delay")]
+    pub max_delay: String,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            initial_delay: default_initial_delay(),
+            max_delay: default_max_delay(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Subscription {
+    States,
+    // at the log_level of the device
+    Logs,
+    HomeassistantServices,
+    HomeassistantStates,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeviceConfig {
+    pub name: String,
+    // host, host:port, IPv6 address or [IPv6]:port, the port defaults to 6053
+    pub address: String,
+    pub client_name: Option<String>,
+    pub password: Option<String>,
+    // environment variable holding the password, keeps it out of the file
+    pub password_env: Option<String>,
+    pub encryption_key: Option<String>,
+    pub encryption_key_env: Option<String>,
+    // replace the subscriptions of the fleet
+    pub subscriptions: Option<Vec<Subscription>>,
+    pub log_level: Option<String>,
+    // alias to object id or entity id, see Fleet::add_alias
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+}
+
+impl DeviceConfig {
+    fn address(&self) -> String {
+        api_address(&self.address)
+    }
+
+    fn password(&self) -> Result<Option<String>> {
+        secret(&self.password, &self.password_env, "password")
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.name.is_empty() {
+            problems.push("name is empty".to_string());
+        }
+        if self.name.contains('/') {
+            problems.push(format!("name {} must not contain /", self.name));
+        }
+        match split_address(&self.address) {
+            _ if self.address.is_empty() => problems.push("address is empty".to_string()),
+            ("", _) => problems.push(format!("address {} has no host", self.address)),
+            (_, Some(port)) if port.parse::<u16>().is_err() => {
+                problems.push(format!("invalid port {} in address {}", port, self.address))
+            }
+            _ => (),
+        }
+        if let Err(err) = self.password() {
+            problems.push(err.to_string());
+        }
+        match secret(&self.encryption_key, &self.encryption_key_env, "encryption_key") {
+            Ok(None) => (),
+            Ok(Some(_)) => problems.push(
+                "encryption_key is set, but encrypted connections are not supported, use an API password".to_string(),
+            ),
+            Err(err) => problems.push(err.to_string()),
+        }
+        if let Some(level) = &self.log_level {
+            if let Err(err) = log_level(level) {
+                problems.push(err.to_string());
+            }
+        }
+        for (alias, entity) in &self.aliases {
+            if alias.contains('/') || alias.contains('.') {
+                problems.push(format!("alias {} must not contain / or .", alias));
+            }
+            if entity.is_empty() {
+                problems.push(format!("alias {} has no entity", alias));
+            }
+        }
+        problems
+    }
+}
+
+// Fleet described in a TOML, YAML or JSON file, see FleetConfig::load
+#[derive(Clone, Debug, Deserialize)]
+pub struct FleetConfig {
+    // used by devices without their own
+    #[serde(default = "default_client_name")]
+    pub client_name: String,
+    #[serde(default = "default_max_parallel_connects")]
+    pub max_parallel_connects: usize,
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: String,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    // of every device, unless the device lists its own
+    #[serde(default)]
+    pub subscriptions: Vec<Subscription>,
+    // error, warn, info, config, debug, verbose or very_verbose
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    pub devices: Vec<DeviceConfig>,
+}
+
+impl FleetConfig {
+    // reads and validates a file, the format follows the extension, e.g. fleet.yaml
+    //   client_name: dashboard
+    //   reconnect: { initial_delay: 1s, max_delay: 5min }
+    //   subscriptions: [states]
+    //   devices:
+    //     - name: bedroom
+    //       address: 10.0.0.12
+    //       password_env: BEDROOM_API_PASSWORD
+    //       subscriptions: [states, logs]
+    //       aliases:
+    //         temperature: sensor.bme280_temperature
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let config: FleetConfig = config::Config::builder()
+            .add_source(config::File::from(path))
+            .build()?
+            .try_deserialize()?;
+        config.validate().map_err(|err| {
+            let problems = match err.kind() {
+                ErrorKind::InvalidConfig { problems } => problems.clone(),
+                _ => vec![err.to_string()],
+            };
+            Error::invalid_config(&path.display().to_string(), problems)
+        })?;
+        Ok(config)
+    }
+
+    // reports every problem at once, each prefixed with the setting or device it is about
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if self.devices.is_empty() {
+            problems.push("no devices".to_string());
+        }
+        if self.max_parallel_connects == 0 {
+            problems.push("max_parallel_connects must be at least 1".to_string());
+        }
+        if let Err(err) = parse_duration(&self.connect_timeout) {
+            problems.push(format!("connect_timeout: {}", err));
+        }
+        if let Err(err) = self.reconnect_policy() {
+            problems.push(format!("reconnect: {}", err));
+        }
+        if let Err(err) = log_level(&self.log_level) {
+            problems.push(err.to_string());
+        }
+        let mut names = HashMap::new();
+        for (index, device) in self.devices.iter().enumerate() {
+            let label = format!("devices[{}] ({})", index, device.name);
+            if let Some(first) = names.insert(device.name.as_str(), index) {
+                problems.push(format!("{}: name is already used by devices[{}]", label, first));
+            }
+            problems.extend(device.problems().into_iter().map(|p| format!("{}: {}", label, p)));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::invalid_config("fleet", problems))
+        }
+    }
+
+    pub fn fleet(&self) -> Result<Fleet> {
+        self.validate()?;
+        let mut fleet = Fleet::new();
+        fleet.set_max_parallel_connects(self.max_parallel_connects);
+        fleet.set_connect_timeout(parse_duration(&self.connect_timeout)?);
+        fleet.set_reconnect(self.reconnect_policy()?);
+        for config in &self.devices {
+            fleet.add_device(&config.name, self.build_device(config)?)?;
+            for request in self.subscription_requests(config)? {
+                fleet.subscribe(&config.name, request)?;
+            }
+            for (alias, entity) in &config.aliases {
+                fleet.add_alias(&config.name, alias, entity)?;
+            }
+        }
+        Ok(fleet)
+    }
+
+    // a single device of the file, connected on its own
+    pub fn device(&self, name: &str) -> Result<Device> {
+        let config = self
+            .devices
+            .iter()
+            .find(|d| d.name == name)
+            .ok_or_else(|| Error::new(&format!("no device {} in the fleet", name)))?;
+        self.build_device(config)
+    }
+
+    fn build_device(&self, config: &DeviceConfig) -> Result<Device> {
+        let client_name = config.client_name.clone().unwrap_or_else(|| self.client_name.clone());
+        Ok(Device::new(client_name, config.address(), config.password()?))
+    }
+
+    fn reconnect_policy(&self) -> Result<Option<ReconnectPolicy>> {
+        if !self.reconnect.enabled {
+            return Ok(None);
+        }
+        let policy = ReconnectPolicy {
+            initial_delay: parse_duration(&self.reconnect.initial_delay)?,
+            max_delay: parse_duration(&self.reconnect.max_delay)?,
+        };
+        if policy.initial_delay > policy.max_delay {
+            return Err(Error::new("initial_delay is longer than max_delay"));
+        }
+        Ok(Some(policy))
+    }
+
+    fn subscription_requests(&self, config: &DeviceConfig) -> Result<Vec<ESPHomeMessage>> {
+        let level = log_level(config.log_level.as_deref().unwrap_or(&self.log_level))?;
+        let subscriptions = config.subscriptions.as_ref().unwrap_or(&self.subscriptions);
+        let requests = subscriptions
+            .iter()
+            .map(|subscription| match subscription {
+                Subscription::States => ESPHomeMessage::SubscribeStatesRequest(SubscribeStatesRequest {}),
+                Subscription::Logs => ESPHomeMessage::SubscribeLogsRequest(SubscribeLogsRequest {
+                    level: level as i32,
+                    dump_config: false,
+                }),
+                Subscription::HomeassistantServices => {
+                    ESPHomeMessage::SubscribeHomeassistantServicesRequest(SubscribeHomeassistantServicesRequest {})
+                }
+                Subscription::HomeassistantStates => {
+                    ESPHomeMessage::SubscribeHomeAssistantStatesRequest(SubscribeHomeAssistantStatesRequest {})
+                }
+            })
+            .collect();
+        Ok(requests)
+    }
+}
+
+// a value given in the file or read from the environment variable named by `<name>_env`
+fn secret(value: &Option<String>, env_name: &Option<String>, name: &str) -> Result<Option<String>> {
+    match (value, env_name) {
+        (Some(_), Some(_)) => Err(Error::new(&format!("only one of {} and {}_env can be set", name, name))),
+        (Some(value), None) => Ok(Some(value.clone())),
+        (None, Some(variable)) => env::var(variable)
+            .map(Some)
+            .map_err(|_| Error::new(&format!("environment variable {} of {}_env is not set", variable, name))),
+        (None, None) => Ok(None),
+    }
+}
+
+fn log_level(level: &str) -> Result<LogLevel> {
+    LogLevel::from_str_name(&format!("LOG_LEVEL_{}", level.to_uppercase()))
+        .ok_or_else(|| Error::new(&format!("unknown log level {}", level)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn parse(text: &str, format: config::FileFormat) -> FleetConfig {
+        config::Config::builder()
+            .add_source(config::File::from_str(text, format))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn device(address: &str) -> DeviceConfig {
+        parse(
+            &format!("devices:\n  - name: kitchen\n    address: \"{}\"\n", address),
+            config::FileFormat::Yaml,
+        )
+        .devices
+        .remove(0)
+    }
+
+    fn problems(config: &FleetConfig) -> Vec<String> {
+        match config.validate().unwrap_err().kind() {
+            ErrorKind::InvalidConfig { problems } => problems.clone(),
+            kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(device("10.0.0.2").address(), "10.0.0.2:6053");
+        assert_eq!(device("10.0.0.2:6054").address(), "10.0.0.2:6054");
+        assert_eq!(device("kitchen.local").address(), "kitchen.local:6053");
+        assert_eq!(device("fe80::1").address(), "[fe80::1]:6053");
+        assert_eq!(device("[fe80::1]").address(), "[fe80::1]:6053");
+        assert_eq!(device("[fe80::1]:6054").address(), "[fe80::1]:6054");
+        for address in ["10.0.0.2", "fe80::1", "[fe80::1]:6054", "kitchen.local:6053"] {
+            assert!(device(address).problems().is_empty(), "{}", address);
+        }
+        assert_eq!(
+            device("10.0.0.2:http").problems(),
+            vec!["invalid port http in address 10.0.0.2:http"]
+        );
+        assert_eq!(
+            device("[fe80::1]:70000").problems(),
+            vec!["invalid port 70000 in address [fe80::1]:70000"]
+        );
+        assert_eq!(device(":6053").problems(), vec!["address :6053 has no host"]);
+        assert_eq!(device("").problems(), vec!["address is empty"]);
+    }
+
+    #[test]
+    fn aliases_keep_their_case() {
+        let yaml =
+            "devices:\n  - name: kitchen\n    address: 10.0.0.2\n    aliases:\n      Kitchen_Light: light.Ceiling\n";
+        let toml = "[[devices]]\nname = \"kitchen\"\naddress = \"10.0.0.2\"\n[devices.aliases]\nKitchen_Light = \"light.Ceiling\"\n";
+        let json =
+            r#"{"devices":[{"name":"kitchen","address":"10.0.0.2","aliases":{"Kitchen_Light":"light.Ceiling"}}]}"#;
+        for (text, format) in [
+            (yaml, config::FileFormat::Yaml),
+            (toml, config::FileFormat::Toml),
+            (json, config::FileFormat::Json),
+        ] {
+            let fleet = parse(text, format).fleet().unwrap();
+            let aliases = fleet.members[0].aliases.lock().unwrap().clone();
+            assert_eq!(
+                aliases,
+                HashMap::from([("Kitchen_Light".to_string(), "light.Ceiling".to_string())])
+            );
+        }
+
+        let path = env::temp_dir().join(format!("fleet-{}.yaml", std::process::id()));
+        std::fs::write(&path, yaml).unwrap();
+        let config = FleetConfig::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(config.unwrap().devices[0].aliases.contains_key("Kitchen_Light"));
+    }
+
+    #[test]
+    fn valid() {
+        let config = parse(
+            "client_name: dashboard\nreconnect: { initial_delay: 1s, max_delay: 5min }\nsubscriptions: [states]\n\
+             devices:\n  - name: bedroom\n    address: 10.0.0.12\n    password: secret\n    subscriptions: [states, logs]\n    \
+             log_level: debug\n    aliases:\n      temperature: sensor.bme280_temperature\n",
+            config::FileFormat::Yaml,
+        );
+        config.validate().unwrap();
+        assert_eq!(config.subscription_requests(&config.devices[0]).unwrap().len(), 2);
+        assert_eq!(
+            config.reconnect_policy().unwrap(),
+            Some(ReconnectPolicy {
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(300),
+            })
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let config = parse(
+            "max_parallel_connects: 0\nconnect_timeout: soon\nreconnect: { initial_delay: 10min, max_delay: 1min }\n\
+             log_level: loud\ndevices:\n  - name: a/b\n    address: 10.0.0.2:x\n  - name: a/b\n    address: 10.0.0.3\n    \
+             password: secret\n    password_env: PASSWORD\n    encryption_key_env: ESPHOME_FLEET_TEST_UNSET_KEY\n    \
+             log_level: loud\n    aliases:\n      kitchen.light: \"\"\n",
+            config::FileFormat::Yaml,
+        );
+        assert_eq!(
+            problems(&config),
+            vec![
+                "max_parallel_connects must be at least 1",
+                "connect_timeout: invalid duration soon",
+                "reconnect: initial_delay is longer than max_delay",
+                "unknown log level loud",
+                "devices[0] (a/b): name a/b must not contain /",
+                "devices[0] (a/b): invalid port x in address 10.0.0.2:x",
+                "devices[1] (a/b): name is already used by devices[0]",
+                "devices[1] (a/b): name a/b must not contain /",
+                "devices[1] (a/b): only one of password and password_env can be set",
+                "devices[1] (a/b): environment variable ESPHOME_FLEET_TEST_UNSET_KEY of encryption_key_env is not set",
+                "devices[1] (a/b): unknown log level loud",
+                "devices[1] (a/b): alias kitchen.light must not contain / or .",
+                "devices[1] (a/b): alias kitchen.light has no entity",
+            ]
+        );
+
+        let config = parse("devices: []\n", config::FileFormat::Yaml);
+        assert_eq!(problems(&config), vec!["no devices"]);
+    }
+
+    #[test]
+    fn encryption_is_rejected() {
+        let mut config = parse(
+            "devices:\n  - name: kitchen\n    address: 10.0.0.2\n    encryption_key: abc\n",
+            config::FileFormat::Yaml,
+        );
+        assert_eq!(
+            problems(&config),
+            vec!["devices[0] (kitchen): encryption_key is set, but encrypted connections are not supported, use an API password"]
+        );
+        config.devices[0].encryption_key = None;
+        config.validate().unwrap();
+    }
+}
@@ -1,10 +1,25 @@
 pub mod api;
+pub mod audit;
+#[cfg(feature = "bluetooth")]
+pub mod bluetooth;
 pub mod device;
+#[cfg(feature = "discovery")]
+pub mod discovery;
+mod duration;
 pub mod messages;
 mod variant;
 #[macro_use]
 mod entity_macros;
 pub mod commands;
 pub mod connection;
-mod entity;
+pub mod custom;
+pub mod entity;
+pub mod fleet;
+pub mod policy;
+pub mod recording;
+mod redact;
 pub mod result;
+pub mod simulator;
+pub mod stats;
+#[cfg(feature = "voice")]
+pub mod voice;
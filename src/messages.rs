@@ -1,158 +1,243 @@
 use std::{future::Future, pin::Pin};
 
-use prost::{bytes::Buf, Message};
+use bytes::{Bytes, BytesMut};
+use prost::{bytes::Buf, encoding::encode_varint, Message};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::{
     api::*,
-    result::Result,
-    variant::{VarintRead, VarintWrite},
+    result::{Error, ErrorKind, Result},
+    variant::VarintRead,
 };
 
+const READ_BUFFER: usize = 8192;
+const MIN_READ: usize = 1024;
+
 macro_rules! esphome_messages {
-    ($($id:expr => $name:ident),* $(,)?) => {
+    ($($(#[$cfg:meta])* $id:literal => $name:ident {
+        source: $source:ident,
+        ifdef: $ifdef:expr,
+        log: $log:expr,
+        no_delay: $no_delay:expr $(,)?
+    }),* $(,)?) => {
         #[derive(Clone, PartialEq, Debug)]
         #[derive(Default)]
         pub enum ESPHomeMessage {
             #[default]
             None,
-            Unknown(u32),
-            $($name($name),)*
+            // message not known to this version of the protocol, kept as received
+            Unknown { id: u32, payload: Bytes },
+            $($(#[$cfg])* $name($name),)*
         }
 
         impl ESPHomeMessage {
             pub fn id(&self) -> u32 {
                 match self {
-                    $(ESPHomeMessage::$name(_) => $id,)*
+                    $($(#[$cfg])* ESPHomeMessage::$name(_) => $id,)*
                     ESPHomeMessage::None => 0,
-                    ESPHomeMessage::Unknown(id) => *id,
+                    ESPHomeMessage::Unknown { id, .. } => *id,
+                }
+            }
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $($(#[$cfg])* ESPHomeMessage::$name(_) => stringify!($name),)*
+                    ESPHomeMessage::None => "None",
+                    ESPHomeMessage::Unknown { .. } => "Unknown",
+                }
+            }
+
+            // which side of the connection sends the message
+            pub fn source(&self) -> ApiSourceType {
+                match self {
+                    $($(#[$cfg])* ESPHomeMessage::$name(_) => ApiSourceType::$source,)*
+                    ESPHomeMessage::None | ESPHomeMessage::Unknown { .. } => ApiSourceType::SourceBoth,
+                }
+            }
+
+            // firmware component the message belongs to, e.g. USE_BLUETOOTH_PROXY
+            pub fn ifdef(&self) -> Option<&'static str> {
+                match self {
+                    $($(#[$cfg])* ESPHomeMessage::$name(_) => $ifdef,)*
+                    ESPHomeMessage::None | ESPHomeMessage::Unknown { .. } => None,
+                }
+            }
+
+            pub fn log(&self) -> bool {
+                match self {
+                    $($(#[$cfg])* ESPHomeMessage::$name(_) => $log,)*
+                    ESPHomeMessage::None | ESPHomeMessage::Unknown { .. } => true,
+                }
+            }
+
+            // message should be sent immediately instead of being batched
+            pub fn no_delay(&self) -> bool {
+                match self {
+                    $($(#[$cfg])* ESPHomeMessage::$name(_) => $no_delay,)*
+                    ESPHomeMessage::None | ESPHomeMessage::Unknown { .. } => false,
                 }
             }
 
             pub fn encode(&self) -> Result<Vec<u8>> {
                 let mut bytes = Vec::new();
                 match self {
-                    $(ESPHomeMessage::$name(msg) => msg.encode(&mut bytes)?,)*
+                    $($(#[$cfg])* ESPHomeMessage::$name(msg) => msg.encode(&mut bytes)?,)*
                     ESPHomeMessage::None => return Err("Cannot encode None message".into()),
-                    ESPHomeMessage::Unknown(_) => return Err("Cannot encode unknown message type".into()),
+                    ESPHomeMessage::Unknown { payload, .. } => bytes.extend_from_slice(payload),
                 }
                 Ok(bytes)
             }
 
+            // decoded fields as JSON, secret fields are left out
+            pub fn fields(&self) -> serde_json::Value {
+                match self {
+                    $($(#[$cfg])* ESPHomeMessage::$name(msg) => serde_json::to_value(msg).unwrap_or_default(),)*
+                    ESPHomeMessage::None | ESPHomeMessage::Unknown { .. } => serde_json::Value::Null,
+                }
+            }
+
+            // the wrapped protobuf message, None for messages without one
+            pub(crate) fn boxed(&self) -> Option<Box<dyn prost::Message>> {
+                match self {
+                    $($(#[$cfg])* ESPHomeMessage::$name(msg) => Some(Box::new(msg.clone())),)*
+                    ESPHomeMessage::None | ESPHomeMessage::Unknown { .. } => None,
+                }
+            }
+
             pub(crate) fn from_buffer<B>(
                 message_id: u32,
-                buf: B
+                mut buf: B
             ) -> Result<ESPHomeMessage>
             where
                 B: Buf + std::marker::Unpin,
             {
                 match message_id {
-                    $($id => Ok(ESPHomeMessage::$name(<$name>::decode(buf)?)),)*
+                    $($(#[$cfg])* $id => Ok(ESPHomeMessage::$name(<$name>::decode(buf)?)),)*
                     0 => Ok(ESPHomeMessage::None),
-                    _ => Ok(ESPHomeMessage::Unknown(message_id)),
+                    _ => Ok(ESPHomeMessage::Unknown {
+                        id: message_id,
+                        payload: buf.copy_to_bytes(buf.remaining()),
+                    }),
                 }
             }
         }
     };
 }
 
-esphome_messages! {
-    1 => HelloRequest,
-    2 => HelloResponse,
-    3 => ConnectRequest,
-    4 => ConnectResponse,
-    5 => DisconnectRequest,
-    6 => DisconnectResponse,
-    7 => PingRequest,
-    8 => PingResponse,
-    9 => DeviceInfoRequest,
-    10 => DeviceInfoResponse,
-    11 => ListEntitiesRequest,
-    19 => ListEntitiesDoneResponse,
-    20 => SubscribeStatesRequest,
-    12 => ListEntitiesBinarySensorResponse,
-    21 => BinarySensorStateResponse,
-    13 => ListEntitiesCoverResponse,
-    22 => CoverStateResponse,
-    30 => CoverCommandRequest,
-    14 => ListEntitiesFanResponse,
-    23 => FanStateResponse,
-    31 => FanCommandRequest,
-    15 => ListEntitiesLightResponse,
-    24 => LightStateResponse,
-    32 => LightCommandRequest,
-    16 => ListEntitiesSensorResponse,
-    25 => SensorStateResponse,
-    17 => ListEntitiesSwitchResponse,
-    26 => SwitchStateResponse,
-    33 => SwitchCommandRequest,
-    18 => ListEntitiesTextSensorResponse,
-    27 => TextSensorStateResponse,
-    28 => SubscribeLogsRequest,
-    29 => SubscribeLogsResponse,
-    34 => SubscribeHomeassistantServicesRequest,
-    35 => HomeassistantServiceResponse,
-    38 => SubscribeHomeAssistantStatesRequest,
-    39 => SubscribeHomeAssistantStateResponse,
-    40 => HomeAssistantStateResponse,
-    36 => GetTimeRequest,
-    37 => GetTimeResponse,
-    41 => ListEntitiesServicesResponse,
-    42 => ExecuteServiceRequest,
-    43 => ListEntitiesCameraResponse,
-    44 => CameraImageResponse,
-    45 => CameraImageRequest,
-    46 => ListEntitiesClimateResponse,
-    47 => ClimateStateResponse,
-    48 => ClimateCommandRequest,
-    49 => ListEntitiesNumberResponse,
-    50 => NumberStateResponse,
-    51 => NumberCommandRequest,
-    52 => ListEntitiesSelectResponse,
-    53 => SelectStateResponse,
-    54 => SelectCommandRequest,
-    58 => ListEntitiesLockResponse,
-    59 => LockStateResponse,
-    60 => LockCommandRequest,
-    61 => ListEntitiesButtonResponse,
-    62 => ButtonCommandRequest,
-    63 => ListEntitiesMediaPlayerResponse,
-    64 => MediaPlayerStateResponse,
-    65 => MediaPlayerCommandRequest,
-    66 => SubscribeBluetoothLeAdvertisementsRequest,
-    67 => BluetoothLeAdvertisementResponse,
-    93 => BluetoothLeRawAdvertisementsResponse,
-    68 => BluetoothDeviceRequest,
-    69 => BluetoothDeviceConnectionResponse,
-    70 => BluetoothGattGetServicesRequest,
-    71 => BluetoothGattGetServicesResponse,
-    72 => BluetoothGattGetServicesDoneResponse,
-    73 => BluetoothGattReadRequest,
-    74 => BluetoothGattReadResponse,
-    75 => BluetoothGattWriteRequest,
-    76 => BluetoothGattReadDescriptorRequest,
-    77 => BluetoothGattWriteDescriptorRequest,
-    78 => BluetoothGattNotifyRequest,
-    79 => BluetoothGattNotifyDataResponse,
-    80 => SubscribeBluetoothConnectionsFreeRequest,
-    81 => BluetoothConnectionsFreeResponse,
-    82 => BluetoothGattErrorResponse,
-    83 => BluetoothGattWriteResponse,
-    84 => BluetoothGattNotifyResponse,
-    85 => BluetoothDevicePairingResponse,
-    86 => BluetoothDeviceUnpairingResponse,
-    87 => UnsubscribeBluetoothLeAdvertisementsRequest,
-    88 => BluetoothDeviceClearCacheResponse,
-    89 => SubscribeVoiceAssistantRequest,
-    90 => VoiceAssistantRequest,
-    91 => VoiceAssistantResponse,
-    92 => VoiceAssistantEventResponse,
-    94 => ListEntitiesAlarmControlPanelResponse,
-    95 => AlarmControlPanelStateResponse,
-    96 => AlarmControlPanelCommandRequest,
-    97 => ListEntitiesTextResponse,
-    98 => TextStateResponse,
-    99 => TextCommandRequest,
+include!(concat!(env!("OUT_DIR"), "/esphome_messages.rs"));
+
+// How messages sent in the wrong direction are handled
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProtocolMode {
+    // messages are rejected by the sender and dropped by the reader
+    #[default]
+    Strict,
+    // violations are only logged
+    Lenient,
+}
+
+impl ESPHomeMessage {
+    // message as sent over the wire: zero byte, payload length, message id and payload
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        let payload = self.encode()?;
+        let mut frame = Vec::with_capacity(payload.len() + 11);
+        frame.push(0);
+        encode_varint(payload.len() as u64, &mut frame);
+        encode_varint(self.id() as u64, &mut frame);
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    // messages that cannot change the state of the device: handshake, list, subscribe, ping, time and log
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            ESPHomeMessage::HelloRequest(_)
+            | ESPHomeMessage::ConnectRequest(_)
+            | ESPHomeMessage::DisconnectRequest(_)
+            | ESPHomeMessage::DisconnectResponse(_)
+            | ESPHomeMessage::PingRequest(_)
+            | ESPHomeMessage::PingResponse(_)
+            | ESPHomeMessage::DeviceInfoRequest(_)
+            | ESPHomeMessage::ListEntitiesRequest(_)
+            | ESPHomeMessage::SubscribeStatesRequest(_)
+            | ESPHomeMessage::SubscribeLogsRequest(_)
+            | ESPHomeMessage::SubscribeHomeassistantServicesRequest(_)
+            | ESPHomeMessage::SubscribeHomeAssistantStatesRequest(_)
+            | ESPHomeMessage::GetTimeRequest(_)
+            | ESPHomeMessage::GetTimeResponse(_) => true,
+            // image stream is a subscription as well
+            #[cfg(feature = "camera")]
+            ESPHomeMessage::CameraImageRequest(_) => true,
+            #[cfg(feature = "bluetooth")]
+            ESPHomeMessage::SubscribeBluetoothLeAdvertisementsRequest(_)
+            | ESPHomeMessage::UnsubscribeBluetoothLeAdvertisementsRequest(_)
+            | ESPHomeMessage::SubscribeBluetoothConnectionsFreeRequest(_)
+            | ESPHomeMessage::BluetoothGattGetServicesRequest(_)
+            | ESPHomeMessage::BluetoothGattReadRequest(_)
+            | ESPHomeMessage::BluetoothGattReadDescriptorRequest(_)
+            | ESPHomeMessage::BluetoothGattNotifyRequest(_) => true,
+            // connecting is needed to read from a peripheral, pairing and cache clearing are not
+            #[cfg(feature = "bluetooth")]
+            ESPHomeMessage::BluetoothDeviceRequest(m) => !matches!(
+                BluetoothDeviceRequestType::try_from(m.request_type),
+                Ok(BluetoothDeviceRequestType::Pair)
+                    | Ok(BluetoothDeviceRequestType::Unpair)
+                    | Ok(BluetoothDeviceRequestType::ClearCache)
+                    | Err(_)
+            ),
+            _ => false,
+        }
+    }
+
+    // error returned for messages rejected in read-only mode
+    pub(crate) fn read_only_error(&self) -> Error {
+        Error::with_kind(
+            ErrorKind::ReadOnly { message_id: self.id() },
+            &format!("{} is not allowed on a read-only connection", self.name()),
+        )
+    }
+
+    pub fn sent_by_client(&self) -> bool {
+        self.source() != ApiSourceType::SourceServer
+    }
+
+    pub fn sent_by_server(&self) -> bool {
+        self.source() != ApiSourceType::SourceClient
+    }
+
+    // key of the entity targeted by a command request
+    pub fn command_key(&self) -> Option<u32> {
+        match self {
+            ESPHomeMessage::CoverCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::FanCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::LightCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::SwitchCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::ClimateCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::NumberCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::SelectCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::ButtonCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::LockCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::TextCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::DateCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::TimeCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::ValveCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::DateTimeCommandRequest(m) => Some(m.key),
+            ESPHomeMessage::UpdateCommandRequest(m) => Some(m.key),
+            #[cfg(feature = "media-player")]
+            ESPHomeMessage::MediaPlayerCommandRequest(m) => Some(m.key),
+            #[cfg(feature = "alarm")]
+            ESPHomeMessage::AlarmControlPanelCommandRequest(m) => Some(m.key),
+            _ => None,
+        }
+    }
+
+    // message with an id that is not part of api.proto, e.g. for a forked firmware
+    pub fn custom<M: Message>(id: u32, msg: &M) -> Self {
+        ESPHomeMessage::Unknown {
+            id,
+            payload: Bytes::from(msg.encode_to_vec()),
+        }
+    }
 }
 
 pub trait ESPHomeMessageWrite {
@@ -168,18 +253,14 @@ pub trait ESPHOmeMessageRead {
 
 impl<W> ESPHomeMessageWrite for W
 where
-    W: AsyncWrite + Unpin + Sized + VarintWrite + Send,
+    W: AsyncWrite + Unpin + Sized + Send,
 {
     fn write_esphome_message(
         &mut self,
         message: ESPHomeMessage,
     ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
         Box::pin(async move {
-            let message_bytes = message.encode()?;
-            self.write_u8(0).await?;
-            self.write_varint(message_bytes.len() as u32).await?;
-            self.write_varint(message.id()).await?;
-            self.write_all(&message_bytes).await?;
+            self.write_all(&message.to_frame()?).await?;
             self.flush().await?;
 
             Ok(())
@@ -201,10 +282,162 @@ where
             let len = self.read_varint().await? as usize;
             let message_id = self.read_varint().await?;
 
-            let mut buf = vec![0u8; len];
+            let mut buf = BytesMut::zeroed(len);
             self.read_exact(&mut buf).await?;
 
-            ESPHomeMessage::from_buffer(message_id, &*buf)
+            ESPHomeMessage::from_buffer(message_id, buf.freeze())
+        })
+    }
+}
+
+// returns the value and its length or None if the varint is not complete yet
+pub(crate) fn parse_varint(buf: &[u8]) -> Result<Option<(u32, usize)>> {
+    let mut value = 0u32;
+    for (i, byte) in buf.iter().take(5).enumerate() {
+        value |= ((byte & 0x7F) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+    }
+    if buf.len() >= 5 {
+        return Err("Varint too long".into());
+    }
+    Ok(None)
+}
+
+// Reads frames into a shared buffer, `bytes` fields of decoded messages are slices of it
+pub struct FrameReader<R> {
+    reader: R,
+    buffer: BytesMut,
+}
+
+impl<R> FrameReader<R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: BytesMut::with_capacity(READ_BUFFER),
+        }
+    }
+
+    pub async fn read_message(&mut self) -> Result<ESPHomeMessage> {
+        loop {
+            if let Some((message_id, payload)) = self.next_frame()? {
+                return ESPHomeMessage::from_buffer(message_id, payload);
+            }
+            if self.buffer.capacity() - self.buffer.len() < MIN_READ {
+                self.buffer.reserve(READ_BUFFER);
+            }
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                return Err(Error::with_kind(ErrorKind::Disconnected, "connection closed"));
+            }
+        }
+    }
+
+    fn next_frame(&mut self) -> Result<Option<(u32, Bytes)>> {
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        if self.buffer[0] != 0 {
+            return Err("invalid first byte of the message".into());
+        }
+        let Some((len, len_size)) = parse_varint(&self.buffer[1..])? else {
+            return Ok(None);
+        };
+        let Some((message_id, id_size)) = parse_varint(&self.buffer[1 + len_size..])? else {
+            return Ok(None);
+        };
+        let header = 1 + len_size + id_size;
+        let frame_len = header + len as usize;
+        if self.buffer.len() < frame_len {
+            self.buffer.reserve(frame_len - self.buffer.len());
+            return Ok(None);
+        }
+
+        let mut frame = self.buffer.split_to(frame_len);
+        frame.advance(header);
+        Ok(Some((message_id, frame.freeze())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{HelloRequest, PingRequest};
+
+    fn hello(client_info: &str) -> ESPHomeMessage {
+        ESPHomeMessage::HelloRequest(HelloRequest {
+            client_info: client_info.to_string(),
+            api_version_major: 1,
+            api_version_minor: 9,
         })
     }
+
+    #[test]
+    fn varints() {
+        assert_eq!(parse_varint(&[0x05]).unwrap(), Some((5, 1)));
+        assert_eq!(parse_varint(&[0xAC, 0x02, 0xFF]).unwrap(), Some((300, 2)));
+        assert_eq!(
+            parse_varint(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F]).unwrap(),
+            Some((u32::MAX, 5))
+        );
+        assert_eq!(parse_varint(&[]).unwrap(), None);
+        assert_eq!(parse_varint(&[0x80, 0x80]).unwrap(), None);
+        assert!(parse_varint(&[0x80, 0x80, 0x80, 0x80, 0x80]).is_err());
+    }
+
+    #[tokio::test]
+    async fn frame_split_across_reads() {
+        // a one byte pipe hands the frame to the reader byte by byte
+        let (mut writer, reader) = tokio::io::duplex(1);
+        let msg = hello("split");
+        let frame = msg.to_frame().unwrap();
+        tokio::spawn(async move { writer.write_all(&frame).await });
+
+        let mut frames = FrameReader::new(reader);
+        assert_eq!(frames.read_message().await.unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn multi_byte_length_and_message_id() {
+        let long = hello(&"x".repeat(300));
+        let custom = ESPHomeMessage::custom(1000, &PingRequest {});
+        let mut stream = long.to_frame().unwrap();
+        let len = long.encode().unwrap().len() as u32;
+        assert_eq!(parse_varint(&stream[1..]).unwrap(), Some((len, 2)));
+        stream.extend(custom.to_frame().unwrap());
+
+        let mut frames = FrameReader::new(stream.as_slice());
+        assert_eq!(frames.read_message().await.unwrap(), long);
+        assert_eq!(frames.read_message().await.unwrap(), custom);
+    }
+
+    #[tokio::test]
+    async fn several_frames_in_one_read() {
+        let messages = [
+            hello("one"),
+            ESPHomeMessage::PingRequest(PingRequest {}),
+            hello("three"),
+        ];
+        let stream: Vec<u8> = messages.iter().flat_map(|m| m.to_frame().unwrap()).collect();
+
+        let mut frames = FrameReader::new(stream.as_slice());
+        for msg in messages {
+            assert_eq!(frames.read_message().await.unwrap(), msg);
+        }
+        let err = frames.read_message().await.unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn bad_preamble() {
+        let mut stream = hello("encrypted").to_frame().unwrap();
+        stream[0] = 0x01;
+
+        let mut frames = FrameReader::new(stream.as_slice());
+        let err = frames.read_message().await.unwrap_err();
+        assert_eq!(err.to_string(), "invalid first byte of the message");
+    }
 }
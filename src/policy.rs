@@ -0,0 +1,366 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{api::EntityCategory, entity::Entity, messages::ESPHomeMessage};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DenyReason {
+    // denied by a rule or not allowed by any rule when denying by default
+    NotAllowed,
+    // the command targets an entity that is not in the entity list of the device
+    UnknownEntity,
+    ConfirmationRejected,
+    RateLimited { max: u32, per: Duration },
+}
+
+impl fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DenyReason::NotAllowed => write!(f, "not allowed"),
+            DenyReason::UnknownEntity => write!(f, "unknown entity"),
+            DenyReason::ConfirmationRejected => write!(f, "confirmation rejected"),
+            DenyReason::RateLimited { max, per } => write!(f, "more than {} commands per {:?}", max, per),
+        }
+    }
+}
+
+// Command about to be sent to the device with the entity it targets
+#[derive(Clone, Debug)]
+pub struct PolicyCommand {
+    pub entity: Entity,
+    pub message: ESPHomeMessage,
+}
+
+pub type PolicyFuture<'a> = Pin<Box<dyn Future<Output = Result<(), DenyReason>> + Send + 'a>>;
+
+// Consulted by `Connection::send_message` for every command targeting an entity
+pub trait CommandPolicy: Send + Sync {
+    fn check<'a>(&'a self, command: &'a PolicyCommand) -> PolicyFuture<'a>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EntitySelector {
+    // domain and object id, e.g. switch.pump
+    Entity { domain: String, id: String },
+    Domain(String),
+    Category(EntityCategory),
+}
+
+impl EntitySelector {
+    fn matches(&self, entity: &Entity) -> bool {
+        match self {
+            EntitySelector::Entity { domain, id } => entity.domain == domain && &entity.id == id,
+            EntitySelector::Domain(domain) => entity.domain == domain,
+            EntitySelector::Category(category) => entity.category == *category,
+        }
+    }
+}
+
+// "switch.pump" selects a single entity, "lock" or "lock.*" a whole domain
+impl From<&str> for EntitySelector {
+    fn from(selector: &str) -> Self {
+        match selector.split_once('.') {
+            None | Some((_, "*")) => EntitySelector::Domain(domain_of(selector).to_string()),
+            Some((domain, id)) => EntitySelector::Entity {
+                domain: domain.to_string(),
+                id: id.to_string(),
+            },
+        }
+    }
+}
+
+fn domain_of(selector: &str) -> &str {
+    selector.split('.').next().unwrap_or_default()
+}
+
+impl From<EntityCategory> for EntitySelector {
+    fn from(category: EntityCategory) -> Self {
+        EntitySelector::Category(category)
+    }
+}
+
+pub type ConfirmFuture = Pin<Box<dyn Future<Output = bool> + Send>>;
+
+enum Rule {
+    Allow,
+    Deny,
+    Confirm(Box<dyn Fn(PolicyCommand) -> ConfirmFuture + Send + Sync>),
+    RateLimit { max: u32, per: Duration },
+}
+
+// Rule based CommandPolicy: deny rules win over allow rules, confirmation and rate limits apply on top
+pub struct PolicyEngine {
+    allow_by_default: bool,
+    rules: Vec<(EntitySelector, Rule)>,
+    sent: Mutex<HashMap<(usize, u32), VecDeque<Instant>>>,
+}
+
+impl PolicyEngine {
+    // commands are allowed unless denied by a rule
+    pub fn allow_by_default() -> Self {
+        Self {
+            allow_by_default: true,
+            rules: Vec::new(),
+            sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // only commands for entities matching an allow rule are sent
+    pub fn deny_by_default() -> Self {
+        Self {
+            allow_by_default: false,
+            ..Self::allow_by_default()
+        }
+    }
+
+    pub fn allow<S: Into<EntitySelector>>(mut self, selector: S) -> Self {
+        self.rules.push((selector.into(), Rule::Allow));
+        self
+    }
+
+    pub fn deny<S: Into<EntitySelector>>(mut self, selector: S) -> Self {
+        self.rules.push((selector.into(), Rule::Deny));
+        self
+    }
+
+    // the callback decides whether the command is sent, e.g. after asking the user
+    pub fn confirm<S, F>(mut self, selector: S, callback: F) -> Self
+    where
+        S: Into<EntitySelector>,
+        F: Fn(PolicyCommand) -> ConfirmFuture + Send + Sync + 'static,
+    {
+        self.rules.push((selector.into(), Rule::Confirm(Box::new(callback))));
+        self
+    }
+
+    // at most `max` commands per entity within `per`
+    pub fn rate_limit<S: Into<EntitySelector>>(mut self, selector: S, max: u32, per: Duration) -> Self {
+        self.rules.push((selector.into(), Rule::RateLimit { max, per }));
+        self
+    }
+
+    fn matching<'a>(&'a self, entity: &'a Entity) -> impl Iterator<Item = (usize, &'a Rule)> + 'a {
+        self.rules
+            .iter()
+            .enumerate()
+            .filter(move |(_, (selector, _))| selector.matches(entity))
+            .map(|(i, (_, rule))| (i, rule))
+    }
+
+    fn check_rate_limits(&self, entity: &Entity, record: bool) -> Result<(), DenyReason> {
+        let mut sent = self.sent.lock().unwrap();
+        let now = Instant::now();
+        let mut limited = Vec::new();
+        for (i, rule) in self.matching(entity) {
+            if let Rule::RateLimit { max, per } = rule {
+                let times = sent.entry((i, entity.key)).or_default();
+                while times.front().is_some_and(|t| now.duration_since(*t) >= *per) {
+                    times.pop_front();
+                }
+                if times.len() >= *max as usize {
+                    return Err(DenyReason::RateLimited { max: *max, per: *per });
+                }
+                limited.push(i);
+            }
+        }
+        if record {
+            for i in limited {
+                sent.entry((i, entity.key)).or_default().push_back(now);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CommandPolicy for PolicyEngine {
+    fn check<'a>(&'a self, command: &'a PolicyCommand) -> PolicyFuture<'a> {
+        Box::pin(async move {
+            let entity = &command.entity;
+            if self.matching(entity).any(|(_, rule)| matches!(rule, Rule::Deny)) {
+                return Err(DenyReason::NotAllowed);
+            }
+            if !self.allow_by_default && !self.matching(entity).any(|(_, rule)| matches!(rule, Rule::Allow)) {
+                return Err(DenyReason::NotAllowed);
+            }
+            self.check_rate_limits(entity, false)?;
+
+            for (_, rule) in self.matching(entity) {
+                if let Rule::Confirm(callback) = rule {
+                    if !callback(command.clone()).await {
+                        return Err(DenyReason::ConfirmationRejected);
+                    }
+                }
+            }
+
+            // only commands that are actually sent count against the limits
+            self.check_rate_limits(entity, true)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::time::advance;
+
+    use super::*;
+    use crate::api::{LockCommandRequest, SwitchCommandRequest};
+
+    fn command(domain: &'static str, id: &str, key: u32, category: EntityCategory) -> PolicyCommand {
+        let message = match domain {
+            "lock" => ESPHomeMessage::LockCommandRequest(LockCommandRequest {
+                key,
+                ..Default::default()
+            }),
+            _ => ESPHomeMessage::SwitchCommandRequest(SwitchCommandRequest { key, state: true }),
+        };
+        PolicyCommand {
+            entity: Entity {
+                id: id.to_string(),
+                key,
+                domain,
+                name: id.to_string(),
+                icon: String::new(),
+                category,
+                unique_id: id.to_string(),
+                entity_data: Arc::new(Box::new(SwitchCommandRequest::default())),
+                definition: ESPHomeMessage::None,
+                state: ESPHomeMessage::None,
+            },
+            message,
+        }
+    }
+
+    fn switch(id: &str, key: u32) -> PolicyCommand {
+        command("switch", id, key, EntityCategory::None)
+    }
+
+    #[test]
+    fn selectors() {
+        assert_eq!(
+            EntitySelector::from("switch.pump"),
+            EntitySelector::Entity {
+                domain: "switch".to_string(),
+                id: "pump".to_string()
+            }
+        );
+        assert_eq!(EntitySelector::from("lock"), EntitySelector::Domain("lock".to_string()));
+        assert_eq!(
+            EntitySelector::from("lock.*"),
+            EntitySelector::Domain("lock".to_string())
+        );
+        assert!(EntitySelector::from(EntityCategory::Config)
+            .matches(&command("switch", "restart", 1, EntityCategory::Config).entity));
+    }
+
+    #[tokio::test]
+    async fn deny_wins_over_allow() {
+        let policy = PolicyEngine::deny_by_default()
+            .allow("switch")
+            .deny("switch.pump")
+            .allow("switch.pump");
+        assert_eq!(policy.check(&switch("pump", 1)).await, Err(DenyReason::NotAllowed));
+        assert_eq!(policy.check(&switch("fan", 2)).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn default_decision() {
+        let lock = command("lock", "front_door", 3, EntityCategory::None);
+
+        let allowing = PolicyEngine::allow_by_default().deny("lock");
+        assert_eq!(allowing.check(&switch("pump", 1)).await, Ok(()));
+        assert_eq!(allowing.check(&lock).await, Err(DenyReason::NotAllowed));
+
+        let denying = PolicyEngine::deny_by_default().allow("lock.*");
+        assert_eq!(denying.check(&switch("pump", 1)).await, Err(DenyReason::NotAllowed));
+        assert_eq!(denying.check(&lock).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn domain_and_category_rules() {
+        let policy = PolicyEngine::allow_by_default()
+            .deny("lock")
+            .deny(EntityCategory::Config);
+        let lock = command("lock", "garage", 3, EntityCategory::None);
+        let config = command("switch", "restart", 4, EntityCategory::Config);
+        assert_eq!(policy.check(&lock).await, Err(DenyReason::NotAllowed));
+        assert_eq!(policy.check(&config).await, Err(DenyReason::NotAllowed));
+        assert_eq!(policy.check(&switch("pump", 1)).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn confirmation_callback() {
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counter = asked.clone();
+        let policy = PolicyEngine::allow_by_default().confirm("switch", move |command| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { command.entity.id == "pump" })
+        });
+        assert_eq!(policy.check(&switch("pump", 1)).await, Ok(()));
+        assert_eq!(
+            policy.check(&switch("heater", 2)).await,
+            Err(DenyReason::ConfirmationRejected)
+        );
+        assert_eq!(asked.load(Ordering::SeqCst), 2);
+
+        // denied commands are not brought up for confirmation
+        let policy = PolicyEngine::allow_by_default()
+            .deny("switch.heater")
+            .confirm("switch", |_| {
+                Box::pin(async { panic!("asked to confirm a denied command") })
+            });
+        assert_eq!(policy.check(&switch("heater", 2)).await, Err(DenyReason::NotAllowed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_window() {
+        let per = Duration::from_millis(200);
+        let policy = PolicyEngine::allow_by_default().rate_limit("switch", 2, per);
+        let limited = Err(DenyReason::RateLimited { max: 2, per });
+
+        assert_eq!(policy.check(&switch("pump", 1)).await, Ok(()));
+        assert_eq!(policy.check(&switch("pump", 1)).await, Ok(()));
+        assert_eq!(policy.check(&switch("pump", 1)).await, limited);
+        // limits count per entity
+        assert_eq!(policy.check(&switch("fan", 2)).await, Ok(()));
+
+        advance(per - Duration::from_millis(1)).await;
+        assert_eq!(policy.check(&switch("pump", 1)).await, limited);
+        advance(Duration::from_millis(1)).await;
+        assert_eq!(policy.check(&switch("pump", 1)).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn rejected_commands_do_not_count_against_the_limit() {
+        let per = Duration::from_secs(60);
+        let asked = AtomicUsize::new(0);
+        // the first command is rejected, the following ones confirmed
+        let policy = PolicyEngine::allow_by_default()
+            .rate_limit("switch", 1, per)
+            .confirm("switch", move |_| {
+                let confirmed = asked.fetch_add(1, Ordering::SeqCst) > 0;
+                Box::pin(async move { confirmed })
+            });
+        assert_eq!(
+            policy.check(&switch("pump", 1)).await,
+            Err(DenyReason::ConfirmationRejected)
+        );
+        assert_eq!(policy.check(&switch("pump", 1)).await, Ok(()));
+        assert_eq!(
+            policy.check(&switch("pump", 1)).await,
+            Err(DenyReason::RateLimited { max: 1, per })
+        );
+    }
+}
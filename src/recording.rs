@@ -0,0 +1,520 @@
+use std::{
+    io,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{duplex, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, DuplexStream, ReadBuf},
+    sync::mpsc,
+    time::{sleep_until, timeout, Instant},
+};
+
+#[cfg(feature = "alarm")]
+use crate::api::AlarmControlPanelCommandRequest;
+use crate::{
+    api::{ConnectRequest, LockCommandRequest},
+    messages::{parse_varint, ESPHomeMessage, FrameReader},
+    result::{Error, Result},
+};
+
+// buffer of the in-memory connection between Device and the replay
+const REPLAY_BUFFER: usize = 64 * 1024;
+const DEFAULT_CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// seen from the client, Sent frames go to the device
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+// One line of a recorded session
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    // since the start of the connection
+    pub time_ms: u64,
+    pub direction: Direction,
+    pub id: u32,
+    #[serde(with = "hex")]
+    pub payload: Bytes,
+}
+
+impl RecordedFrame {
+    pub fn message(&self) -> Result<ESPHomeMessage> {
+        ESPHomeMessage::from_buffer(self.id, self.payload.clone())
+    }
+
+    fn to_frame(&self) -> Result<Vec<u8>> {
+        ESPHomeMessage::Unknown {
+            id: self.id,
+            payload: self.payload.clone(),
+        }
+        .to_frame()
+    }
+}
+
+mod hex {
+    use bytes::Bytes;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(payload: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&payload.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        if !text.is_ascii() {
+            return Err(D::Error::custom("hex payload is not ASCII"));
+        }
+        if text.len() % 2 != 0 {
+            return Err(D::Error::custom("odd length of hex payload"));
+        }
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(D::Error::custom))
+            .collect::<Result<Vec<u8>, _>>()
+            .map(Bytes::from)
+    }
+}
+
+// Writes the frames of wrapped connections as JSON lines, see Device::set_session_recorder
+#[derive(Clone)]
+pub struct SessionRecorder {
+    frames: mpsc::UnboundedSender<RecordedFrame>,
+}
+
+impl SessionRecorder {
+    pub async fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::create(path).await?;
+        let (frames, mut rx) = mpsc::unbounded_channel::<RecordedFrame>();
+        tokio::spawn(async move {
+            let mut writer = BufWriter::new(file);
+            while let Some(frame) = rx.recv().await {
+                let result = match serde_json::to_vec(&frame) {
+                    Ok(mut line) => {
+                        line.push(b'\n');
+                        writer.write_all(&line).await
+                    }
+                    Err(err) => Err(err.into()),
+                };
+                // written out whenever the connection goes quiet
+                let result = match result {
+                    Ok(()) if rx.is_empty() => writer.flush().await,
+                    result => result,
+                };
+                if let Err(err) = result {
+                    error!("unable to record frame {:?}", err);
+                }
+            }
+        });
+        Ok(Self { frames })
+    }
+
+    // records the frames passing through the stream
+    pub fn wrap<S>(&self, stream: S) -> RecordingStream<S> {
+        RecordingStream {
+            inner: stream,
+            started: Instant::now(),
+            frames: self.frames.clone(),
+            sent: FrameSplitter::default(),
+            received: FrameSplitter::default(),
+        }
+    }
+}
+
+// collects the bytes of one direction until a frame is complete
+#[derive(Default)]
+struct FrameSplitter {
+    buffer: BytesMut,
+    // set after bytes that are not a plaintext frame, e.g. an encrypted connection
+    failed: bool,
+}
+
+impl FrameSplitter {
+    fn push(&mut self, data: &[u8]) -> Vec<(u32, Bytes)> {
+        let mut frames = Vec::new();
+        if self.failed {
+            return frames;
+        }
+        self.buffer.extend_from_slice(data);
+        loop {
+            match self.next_frame() {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("stop recording {:?}", err);
+                    self.failed = true;
+                    self.buffer.clear();
+                    break;
+                }
+            }
+        }
+        frames
+    }
+
+    fn next_frame(&mut self) -> Result<Option<(u32, Bytes)>> {
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        if self.buffer[0] != 0 {
+            return Err("invalid first byte of the message".into());
+        }
+        let Some((len, len_size)) = parse_varint(&self.buffer[1..])? else {
+            return Ok(None);
+        };
+        let Some((id, id_size)) = parse_varint(&self.buffer[1 + len_size..])? else {
+            return Ok(None);
+        };
+        let header = 1 + len_size + id_size;
+        if self.buffer.len() < header + len as usize {
+            return Ok(None);
+        }
+        let mut frame = self.buffer.split_to(header + len as usize);
+        frame.advance(header);
+        Ok(Some((id, frame.freeze())))
+    }
+}
+
+// passwords and codes sent by the client are not written to the recording, the replay does not need them
+fn without_secrets(id: u32, payload: Bytes) -> Bytes {
+    let msg = match ESPHomeMessage::from_buffer(id, payload.clone()) {
+        Ok(ESPHomeMessage::ConnectRequest(_)) => ESPHomeMessage::ConnectRequest(ConnectRequest::default()),
+        Ok(ESPHomeMessage::LockCommandRequest(m)) => ESPHomeMessage::LockCommandRequest(LockCommandRequest {
+            code: String::new(),
+            ..m
+        }),
+        #[cfg(feature = "alarm")]
+        Ok(ESPHomeMessage::AlarmControlPanelCommandRequest(m)) => {
+            ESPHomeMessage::AlarmControlPanelCommandRequest(AlarmControlPanelCommandRequest {
+                code: String::new(),
+                ..m
+            })
+        }
+        _ => return payload,
+    };
+    msg.encode().map(Bytes::from).unwrap_or_default()
+}
+
+pub struct RecordingStream<S> {
+    inner: S,
+    started: Instant,
+    frames: mpsc::UnboundedSender<RecordedFrame>,
+    sent: FrameSplitter,
+    received: FrameSplitter,
+}
+
+impl<S> RecordingStream<S> {
+    fn record(&mut self, direction: Direction, data: &[u8]) {
+        let splitter = match direction {
+            Direction::Sent => &mut self.sent,
+            Direction::Received => &mut self.received,
+        };
+        let time_ms = self.started.elapsed().as_millis() as u64;
+        for (id, payload) in splitter.push(data) {
+            let payload = match direction {
+                Direction::Sent => without_secrets(id, payload),
+                Direction::Received => payload,
+            };
+            let _ = self.frames.send(RecordedFrame {
+                time_ms,
+                direction,
+                id,
+                payload,
+            });
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordingStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let data = buf.filled()[before..].to_vec();
+            self.record(Direction::Received, &data);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordingStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.record(Direction::Sent, &buf[..written]);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// Plays the device side of a recorded session, answering the client's messages in order
+pub struct SessionReplay {
+    frames: Vec<RecordedFrame>,
+    time_scale: f64,
+    client_timeout: Duration,
+}
+
+impl SessionReplay {
+    pub async fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut lines = BufReader::new(File::open(path).await?).lines();
+        let mut frames = Vec::new();
+        while let Some(line) = lines.next_line().await? {
+            if !line.trim().is_empty() {
+                frames.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(Self::new(frames))
+    }
+
+    pub fn new(frames: Vec<RecordedFrame>) -> Self {
+        Self {
+            frames,
+            time_scale: 1.0,
+            client_timeout: DEFAULT_CLIENT_TIMEOUT,
+        }
+    }
+
+    // 1.0 keeps the recorded timing, 0.1 replays ten times faster and 0.0 without any delay
+    pub fn set_time_scale(&mut self, time_scale: f64) -> Result<()> {
+        if !time_scale.is_finite() || time_scale < 0.0 {
+            return Err(Error::new(&format!("invalid time scale {}", time_scale)));
+        }
+        self.time_scale = time_scale;
+        Ok(())
+    }
+
+    // how long the replay waits for each recorded client message before it skips it and goes on,
+    // e.g. when the client sends fewer commands than were recorded
+    pub fn set_client_timeout(&mut self, client_timeout: Duration) {
+        self.client_timeout = client_timeout;
+    }
+
+    // connection to pass to Device::connect_stream, closed once the recording is played
+    pub fn stream(self) -> DuplexStream {
+        let (client, server) = duplex(REPLAY_BUFFER);
+        tokio::spawn(async move {
+            if let Err(err) = self.play(server).await {
+                warn!("replay stopped {:?}", err);
+            }
+        });
+        client
+    }
+
+    async fn play(self, stream: DuplexStream) -> Result<()> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut frames = FrameReader::new(reader);
+        let started = Instant::now();
+        // after a client message did not arrive the following ones up to the next answer are skipped too
+        let mut skipping = false;
+        for frame in &self.frames {
+            match frame.direction {
+                Direction::Sent if skipping => debug!("replay skips message {}", frame.id),
+                Direction::Sent => match timeout(self.client_timeout, frames.read_message()).await {
+                    Ok(msg) => {
+                        let msg = msg?;
+                        if msg.id() != frame.id {
+                            warn!(
+                                "replay expected message {} from the client, got {}",
+                                frame.id,
+                                msg.name()
+                            );
+                        }
+                    }
+                    Err(_) => {
+                        debug!("replay skips message {} the client did not send", frame.id);
+                        skipping = true;
+                    }
+                },
+                Direction::Received => {
+                    skipping = false;
+                    let offset = Duration::from_millis(frame.time_ms).mul_f64(self.time_scale);
+                    sleep_until(started + offset).await;
+                    debug!("replay message {}", frame.id);
+                    writer.write_all(&frame.to_frame()?).await?;
+                    writer.flush().await?;
+                }
+            }
+        }
+        writer.shutdown().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+
+    use tokio::{fs, time::sleep};
+
+    use super::*;
+    use crate::{
+        api::{SubscribeStatesRequest, SwitchCommandRequest},
+        commands::{DeviceCommand, DeviceMessage},
+        connection::Connection,
+        device::Device,
+        simulator::{SimulatedDevice, SimulatedEntity},
+    };
+
+    fn temp_file(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("esphome-{}-{}.jsonl", name, std::process::id()))
+    }
+
+    async fn send(connection: &mut Connection, msg: ESPHomeMessage) {
+        connection
+            .send_message(DeviceCommand::SendMessage(Box::new(msg)))
+            .await
+            .unwrap();
+    }
+
+    // switch state after the next state change
+    async fn next_switch_state(connection: &mut Connection) -> bool {
+        loop {
+            match connection.wait_for_message().await.unwrap() {
+                DeviceMessage::DeviceStateChange(change) => match change.state {
+                    ESPHomeMessage::SwitchStateResponse(s) => return s.state,
+                    _ => continue,
+                },
+                DeviceMessage::Disconnected => panic!("disconnected before the state change"),
+                _ => continue,
+            }
+        }
+    }
+
+    // subscribes to the states and turns the pump on
+    async fn session(device: &mut Device, connection: &mut Connection) {
+        let key = device.get_entity_key("pump").await.unwrap();
+        send(
+            connection,
+            ESPHomeMessage::SubscribeStatesRequest(SubscribeStatesRequest {}),
+        )
+        .await;
+        assert!(!next_switch_state(connection).await);
+        send(
+            connection,
+            ESPHomeMessage::SwitchCommandRequest(SwitchCommandRequest { key, state: true }),
+        )
+        .await;
+        assert!(next_switch_state(connection).await);
+    }
+
+    async fn record(path: &Path) -> Vec<RecordedFrame> {
+        let mut simulator = SimulatedDevice::new("greenhouse");
+        simulator.add_entity(SimulatedEntity::switch("pump", "Pump", false));
+        let handle = simulator.listen("127.0.0.1:0").await.unwrap();
+
+        let mut device = Device::new("test".to_string(), handle.local_addr().to_string(), None);
+        device.set_session_recorder(SessionRecorder::create(path).await.unwrap());
+        let mut connection = device.connect().await.unwrap();
+        session(&mut device, &mut connection).await;
+        connection.disconnect().await.unwrap();
+
+        // the recorder writes in the background
+        for _ in 0..50 {
+            let frames = SessionReplay::load(path).await.unwrap().frames;
+            if frames.iter().any(|f| f.direction == Direction::Received && f.id == 6) {
+                return frames;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        panic!("DisconnectResponse was not recorded");
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let path = temp_file("round-trip");
+        let frames = record(&path).await;
+        assert_eq!(frames.first().map(|f| (f.direction, f.id)), Some((Direction::Sent, 1)));
+        // the password of the ConnectRequest is not recorded
+        let connect = frames.iter().find(|f| f.id == 3).unwrap();
+        assert_eq!(
+            connect.message().unwrap(),
+            ESPHomeMessage::ConnectRequest(ConnectRequest::default())
+        );
+
+        let mut replay = SessionReplay::load(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        replay.set_time_scale(0.0).unwrap();
+        let mut device = Device::new("test".to_string(), "replay".to_string(), None);
+        let mut connection = device.connect_stream(replay.stream()).await.unwrap();
+        assert_eq!(device.device_info().unwrap().name, "greenhouse");
+        session(&mut device, &mut connection).await;
+        connection.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_goes_on_without_client_messages() {
+        let path = temp_file("missing-commands");
+        record(&path).await;
+        let mut replay = SessionReplay::load(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        replay.set_time_scale(0.0).unwrap();
+        replay.set_client_timeout(Duration::from_millis(50));
+
+        // the client neither subscribes nor sends the command, the recorded answers are played anyway
+        let mut device = Device::new("test".to_string(), "replay".to_string(), None);
+        let mut connection = device.connect_stream(replay.stream()).await.unwrap();
+        let messages = timeout(Duration::from_secs(2), async {
+            let mut messages = 0;
+            while !matches!(
+                connection.wait_for_message().await.unwrap(),
+                DeviceMessage::Disconnected
+            ) {
+                messages += 1;
+            }
+            messages
+        })
+        .await
+        .unwrap();
+        assert!(messages >= 2);
+    }
+
+    #[test]
+    fn time_scale_must_be_finite() {
+        let mut replay = SessionReplay::new(Vec::new());
+        assert!(replay.set_time_scale(f64::INFINITY).is_err());
+        assert!(replay.set_time_scale(f64::NAN).is_err());
+        assert!(replay.set_time_scale(-1.0).is_err());
+        assert!(replay.set_time_scale(0.5).is_ok());
+    }
+
+    #[tokio::test]
+    async fn corrupt_payloads_are_rejected() {
+        let frame = |payload: &str| {
+            format!(
+                r#"{{"time_ms":0,"direction":"received","id":8,"payload":"{}"}}"#,
+                payload
+            )
+        };
+        let parsed: RecordedFrame = serde_json::from_str(&frame("0a0b")).unwrap();
+        assert_eq!(parsed.payload, Bytes::from_static(&[0x0a, 0x0b]));
+        for payload in ["aéb", "abc", "zz"] {
+            assert!(
+                serde_json::from_str::<RecordedFrame>(&frame(payload)).is_err(),
+                "{}",
+                payload
+            );
+        }
+
+        let path = temp_file("corrupt");
+        fs::write(&path, frame("aéb")).await.unwrap();
+        let replay = SessionReplay::load(&path).await;
+        fs::remove_file(&path).await.unwrap();
+        assert!(replay.is_err());
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    api::{TextCommandRequest, TextStateResponse},
+    messages::ESPHomeMessage,
+};
+
+const REDACTED: &str = "<redacted>";
+
+// Debug format of secret fields in the generated messages, see build.rs
+pub(crate) fn redacted<T>(_: &T, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(REDACTED)
+}
+
+// Keys of entities with a secret state, e.g. text entities in password mode
+#[derive(Clone, Default)]
+pub(crate) struct SecretEntities(Arc<RwLock<HashSet<u32>>>);
+
+impl SecretEntities {
+    pub(crate) fn add(&self, key: u32) {
+        self.0.write().unwrap().insert(key);
+    }
+
+    fn contains(&self, key: u32) -> bool {
+        self.0.read().unwrap().contains(&key)
+    }
+
+    // copy of the message with the state of secret entities replaced
+    pub(crate) fn mask<'a>(&self, msg: &'a ESPHomeMessage) -> Cow<'a, ESPHomeMessage> {
+        mask_secret(msg, |key| self.contains(key))
+    }
+
+    // Debug view of the message for logging, with the state of secret entities masked
+    pub(crate) fn redact<'a>(&'a self, msg: &'a ESPHomeMessage) -> RedactedMessage<'a> {
+        RedactedMessage { secrets: self, msg }
+    }
+}
+
+// copy of the message with its state replaced when it is the state of a secret entity
+pub(crate) fn mask_secret(msg: &ESPHomeMessage, is_secret: impl Fn(u32) -> bool) -> Cow<'_, ESPHomeMessage> {
+    match msg {
+        ESPHomeMessage::TextStateResponse(m) if is_secret(m.key) => {
+            Cow::Owned(ESPHomeMessage::TextStateResponse(TextStateResponse {
+                state: REDACTED.to_string(),
+                ..m.clone()
+            }))
+        }
+        ESPHomeMessage::TextCommandRequest(m) if is_secret(m.key) => {
+            Cow::Owned(ESPHomeMessage::TextCommandRequest(TextCommandRequest {
+                state: REDACTED.to_string(),
+                ..m.clone()
+            }))
+        }
+        msg => Cow::Borrowed(msg),
+    }
+}
+
+pub(crate) struct RedactedMessage<'a> {
+    secrets: &'a SecretEntities,
+    msg: &'a ESPHomeMessage,
+}
+
+impl fmt::Debug for RedactedMessage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.secrets.mask(self.msg).fmt(f)
+    }
+}
@@ -1,17 +1,76 @@
 use std::fmt;
 use std::fmt::Debug;
 
+use crate::policy::DenyReason;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ErrorKind {
+    #[default]
+    Other,
+    Timeout,
+    Disconnected,
+    Unsupported,
+    // the device rejected the API password
+    InvalidPassword,
+    // message sent in the wrong direction, e.g. a state response sent to the device
+    ProtocolViolation {
+        message_id: u32,
+    },
+    // message rejected because the device was opened read-only
+    ReadOnly {
+        message_id: u32,
+    },
+    // command rejected by the command policy of the device
+    CommandDenied {
+        entity_id: String,
+        reason: DenyReason,
+    },
+    // every problem found in a configuration file, e.g. a missing environment variable
+    InvalidConfig {
+        problems: Vec<String>,
+    },
+    BluetoothDevice {
+        address: u64,
+        error: i32,
+    },
+    BluetoothConnection {
+        address: u64,
+        error: i32,
+    },
+    BluetoothGatt {
+        address: u64,
+        handle: u32,
+        error: i32,
+    },
+}
+
 #[derive(Debug)]
 pub struct Error {
+    kind: ErrorKind,
     message: String,
 }
 
 impl Error {
     pub fn new(message: &str) -> Error {
+        Error::with_kind(ErrorKind::Other, message)
+    }
+
+    pub fn with_kind(kind: ErrorKind, message: &str) -> Error {
         Error {
+            kind,
             message: message.to_string(),
         }
     }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    // all problems of a configuration file, one per line after the name of the file
+    pub(crate) fn invalid_config(source: &str, problems: Vec<String>) -> Error {
+        let message = format!("invalid configuration {}:\n  {}", source, problems.join("\n  "));
+        Error::with_kind(ErrorKind::InvalidConfig { problems }, &message)
+    }
 }
 
 impl fmt::Display for Error {
@@ -48,12 +107,43 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::new(&format!("JSON error: {}", err))
+    }
+}
+
+impl From<config::ConfigError> for Error {
+    fn from(err: config::ConfigError) -> Error {
+        Error::new(&format!("Config error: {}", err))
+    }
+}
+
+#[cfg(feature = "discovery")]
+impl From<mdns_sd::Error> for Error {
+    fn from(err: mdns_sd::Error) -> Error {
+        Error::new(&format!("mDNS error: {}", err))
+    }
+}
+
 impl From<std::sync::mpsc::RecvError> for Error {
     fn from(err: std::sync::mpsc::RecvError) -> Error {
         Error::new(&format!("Recv error: {}", err))
     }
 }
 
+impl From<tokio::time::error::Elapsed> for Error {
+    fn from(err: tokio::time::error::Elapsed) -> Error {
+        Error::with_kind(ErrorKind::Timeout, &format!("Timeout: {}", err))
+    }
+}
+
+impl From<tokio::sync::oneshot::error::RecvError> for Error {
+    fn from(err: tokio::sync::oneshot::error::RecvError) -> Error {
+        Error::with_kind(ErrorKind::Disconnected, &format!("Recv error: {}", err))
+    }
+}
+
 impl<T> From<tokio::sync::mpsc::error::SendError<T>> for Error
 where
     T: Debug,
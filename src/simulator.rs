@@ -0,0 +1,536 @@
+mod behavior;
+mod config;
+mod entities;
+
+pub use behavior::SensorBehavior;
+pub use config::SimulatorConfig;
+pub use entities::{SimulatedEntity, SimulatedState};
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+use tokio::{
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::{broadcast, watch},
+    task::JoinHandle,
+    time::{interval, sleep, Instant},
+};
+
+use crate::{
+    api::*,
+    messages::{ESPHomeMessage, ESPHomeMessageWrite, FrameReader},
+    result::{Error, ErrorKind, Result},
+    simulator::behavior::{positive, BehaviorState},
+};
+
+const API_VERSION_MAJOR: u32 = 1;
+const API_VERSION_MINOR: u32 = 9;
+// how often moving covers report their position
+const MOTION_INTERVAL: Duration = Duration::from_millis(100);
+
+// Misbehaviour of the simulated device, e.g. to test reconnect handling
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Failures {
+    // connections are dropped without a DisconnectRequest after this long
+    pub disconnect_after: Option<Duration>,
+    // delay before each response
+    pub response_delay: Option<Duration>,
+    // every ConnectRequest is answered with invalid_password
+    pub reject_password: bool,
+}
+
+struct Shared {
+    info: DeviceInfoResponse,
+    password: Option<String>,
+    failures: Failures,
+    entities: Mutex<Vec<SimulatedEntity>>,
+    // state responses for all subscribed clients
+    updates: broadcast::Sender<ESPHomeMessage>,
+}
+
+impl Shared {
+    fn states(&self) -> Vec<ESPHomeMessage> {
+        self.entities
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.state_response())
+            .collect()
+    }
+
+    fn apply(&self, key: u32, msg: &ESPHomeMessage) {
+        let mut entities = self.entities.lock().unwrap();
+        match entities.iter_mut().find(|e| e.key == key) {
+            Some(entity) => {
+                if entity.apply(msg) {
+                    let _ = self.updates.send(entity.state_response());
+                }
+            }
+            None => warn!("{} for unknown entity {}", msg.name(), key),
+        }
+    }
+
+    fn set_state(&self, object_id: &str, state: SimulatedState) -> Result<()> {
+        let mut entities = self.entities.lock().unwrap();
+        let entity = entities
+            .iter_mut()
+            .find(|e| e.object_id == object_id)
+            .ok_or_else(|| Error::new(&format!("unknown entity {}", object_id)))?;
+        if std::mem::discriminant(&entity.state) != std::mem::discriminant(&state) {
+            return Err(Error::with_kind(
+                ErrorKind::Unsupported,
+                &format!("{} can not change its entity type", object_id),
+            ));
+        }
+        entity.state = state;
+        let _ = self.updates.send(entity.state_response());
+        Ok(())
+    }
+
+    fn step(&self, elapsed: Duration) {
+        for entity in self.entities.lock().unwrap().iter_mut() {
+            if entity.step(elapsed) {
+                let _ = self.updates.send(entity.state_response());
+            }
+        }
+    }
+}
+
+// Server side of the native API answering from a declarative list of entities, e.g. for testing automations
+pub struct SimulatedDevice {
+    info: DeviceInfoResponse,
+    password: Option<String>,
+    entities: Vec<SimulatedEntity>,
+    behaviors: Vec<(String, SensorBehavior, Duration)>,
+    failures: Failures,
+}
+
+impl SimulatedDevice {
+    pub fn new(name: &str) -> Self {
+        let mac = entities::fnv1_hash(name).to_be_bytes();
+        Self {
+            info: DeviceInfoResponse {
+                name: name.to_string(),
+                friendly_name: name.to_string(),
+                // locally administered address derived from the name
+                mac_address: format!("02:00:{:02X}:{:02X}:{:02X}:{:02X}", mac[0], mac[1], mac[2], mac[3]),
+                esphome_version: env!("CARGO_PKG_VERSION").to_string(),
+                model: "simulator".to_string(),
+                manufacturer: "esphome-client-rs".to_string(),
+                ..Default::default()
+            },
+            password: None,
+            entities: Vec::new(),
+            behaviors: Vec::new(),
+            failures: Failures::default(),
+        }
+    }
+
+    pub fn set_device_info(&mut self, info: DeviceInfoResponse) {
+        self.info = info;
+    }
+
+    // clients sending a different password are disconnected
+    pub fn set_password(&mut self, password: Option<String>) {
+        self.password = password;
+    }
+
+    pub fn add_entity(&mut self, entity: SimulatedEntity) {
+        self.entities.push(entity);
+    }
+
+    // drives the value of a sensor added with add_entity
+    pub fn add_behavior(&mut self, object_id: &str, behavior: SensorBehavior, update_interval: Duration) -> Result<()> {
+        positive(update_interval)
+            .map_err(|err| Error::new(&format!("update_interval: {}", err)))
+            .and_then(|_| behavior.validate())
+            .map_err(|err| Error::new(&format!("behavior of {}: {}", object_id, err)))?;
+        self.behaviors.push((object_id.to_string(), behavior, update_interval));
+        Ok(())
+    }
+
+    pub fn set_failures(&mut self, failures: Failures) {
+        self.failures = failures;
+    }
+
+    // accepts connections until the returned handle is stopped or dropped, use port 0 to pick a free port
+    pub async fn listen(self, address: &str) -> Result<SimulatorHandle> {
+        let listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+        let (updates, _) = broadcast::channel(64);
+        let shared = Arc::new(Shared {
+            info: DeviceInfoResponse {
+                uses_password: self.password.is_some(),
+                ..self.info
+            },
+            password: self.password,
+            failures: self.failures,
+            entities: Mutex::new(self.entities),
+            updates,
+        });
+        let (shutdown, _) = watch::channel(false);
+
+        let accept_shared = shared.clone();
+        let accept_shutdown = shutdown.clone();
+        let accept = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        debug!("simulator accepted connection from {}", peer);
+                        tokio::spawn(serve(stream, accept_shared.clone(), accept_shutdown.subscribe()));
+                    }
+                    Err(err) => warn!("simulator failed to accept connection {:?}", err),
+                }
+            }
+        });
+
+        let motion_shared = shared.clone();
+        let motion = tokio::spawn(async move {
+            let mut ticks = interval(MOTION_INTERVAL);
+            let mut last = Instant::now();
+            loop {
+                ticks.tick().await;
+                let now = Instant::now();
+                motion_shared.step(now - last);
+                last = now;
+            }
+        });
+
+        let mut tasks = vec![accept, motion];
+        for (object_id, behavior, update_interval) in self.behaviors {
+            tasks.push(tokio::spawn(run_behavior(
+                shared.clone(),
+                object_id,
+                behavior,
+                update_interval,
+            )));
+        }
+
+        Ok(SimulatorHandle {
+            local_addr,
+            shared,
+            shutdown,
+            tasks,
+        })
+    }
+}
+
+pub struct SimulatorHandle {
+    local_addr: SocketAddr,
+    shared: Arc<Shared>,
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl SimulatorHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn state(&self, object_id: &str) -> Option<SimulatedState> {
+        let entities = self.shared.entities.lock().unwrap();
+        entities
+            .iter()
+            .find(|e| e.object_id == object_id)
+            .map(|e| e.state.clone())
+    }
+
+    // changes the state of an entity and pushes it to subscribed clients, e.g. a sensor reading
+    pub fn set_state(&self, object_id: &str, state: SimulatedState) -> Result<()> {
+        self.shared.set_state(object_id, state)
+    }
+
+    // disconnects all clients and stops listening
+    pub fn stop(&self) {
+        let _ = self.shutdown.send(true);
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for SimulatorHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+async fn run_behavior(shared: Arc<Shared>, object_id: String, behavior: SensorBehavior, update_interval: Duration) {
+    let initial = match shared
+        .entities
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|e| e.object_id == object_id)
+    {
+        Some(SimulatedEntity {
+            state: SimulatedState::Sensor(value),
+            ..
+        }) => *value,
+        _ => {
+            warn!("simulator behavior for {} which is not a sensor", object_id);
+            return;
+        }
+    };
+    let mut state = BehaviorState::new(behavior, initial);
+    // the first tick completes immediately and replaces the initial value
+    let mut ticks = interval(update_interval);
+    loop {
+        ticks.tick().await;
+        let Some(value) = state.next(update_interval) else {
+            return;
+        };
+        if let Err(err) = shared.set_state(&object_id, SimulatedState::Sensor(value)) {
+            warn!("simulator behavior stopped {:?}", err);
+            return;
+        }
+    }
+}
+
+async fn serve(stream: TcpStream, shared: Arc<Shared>, mut shutdown: watch::Receiver<bool>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut frames = FrameReader::new(reader);
+    let mut updates = shared.updates.subscribe();
+    let mut session = Session::default();
+    let disconnect = async {
+        match shared.failures.disconnect_after {
+            Some(after) => sleep(after).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(disconnect);
+
+    loop {
+        let result = tokio::select! {
+            msg = frames.read_message() => match msg {
+                Ok(msg) => session.handle(msg, &shared, &mut writer).await,
+                Err(err) => Err(err),
+            },
+            update = updates.recv(), if session.subscribed => match update {
+                Ok(msg) => writer.write_esphome_message(msg).await,
+                // too slow to keep up, the current states replace the missed updates
+                Err(broadcast::error::RecvError::Lagged(_)) => session.send_states(&shared, &mut writer).await,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = &mut disconnect => {
+                debug!("simulator dropping connection");
+                break;
+            }
+            _ = shutdown.changed() => {
+                let _ = writer.write_esphome_message(ESPHomeMessage::DisconnectRequest(DisconnectRequest {})).await;
+                break;
+            }
+        };
+        match result {
+            Ok(()) if session.closed => break,
+            Ok(()) => (),
+            Err(err) if *err.kind() == ErrorKind::Disconnected => break,
+            Err(err) => {
+                warn!("simulator closing connection {:?}", err);
+                break;
+            }
+        }
+    }
+    debug!("simulator connection closed");
+}
+
+#[derive(Default)]
+struct Session {
+    hello: bool,
+    authenticated: bool,
+    subscribed: bool,
+    closed: bool,
+}
+
+impl Session {
+    async fn handle(&mut self, msg: ESPHomeMessage, shared: &Shared, writer: &mut OwnedWriteHalf) -> Result<()> {
+        debug!("simulator received {:?}", msg.name());
+        if let Some(delay) = shared.failures.response_delay {
+            sleep(delay).await;
+        }
+        match msg {
+            ESPHomeMessage::HelloRequest(_) => {
+                self.hello = true;
+                let response = ESPHomeMessage::HelloResponse(HelloResponse {
+                    api_version_major: API_VERSION_MAJOR,
+                    api_version_minor: API_VERSION_MINOR,
+                    server_info: format!("esphome-client-rs simulator {}", env!("CARGO_PKG_VERSION")),
+                    name: shared.info.name.clone(),
+                });
+                return writer.write_esphome_message(response).await;
+            }
+            ESPHomeMessage::ConnectRequest(r) => {
+                let invalid_password =
+                    shared.failures.reject_password || shared.password.as_ref().is_some_and(|p| *p != r.password);
+                self.authenticated = !invalid_password;
+                self.closed = invalid_password;
+                let response = ESPHomeMessage::ConnectResponse(ConnectResponse { invalid_password });
+                return writer.write_esphome_message(response).await;
+            }
+            ESPHomeMessage::PingRequest(_) => {
+                return writer
+                    .write_esphome_message(ESPHomeMessage::PingResponse(PingResponse {}))
+                    .await;
+            }
+            ESPHomeMessage::DisconnectRequest(_) => {
+                self.closed = true;
+                return writer
+                    .write_esphome_message(ESPHomeMessage::DisconnectResponse(DisconnectResponse {}))
+                    .await;
+            }
+            ESPHomeMessage::DeviceInfoRequest(_) if self.hello => {
+                return writer
+                    .write_esphome_message(ESPHomeMessage::DeviceInfoResponse(shared.info.clone()))
+                    .await;
+            }
+            ESPHomeMessage::PingResponse(_) | ESPHomeMessage::DisconnectResponse(_) => return Ok(()),
+            _ => (),
+        }
+
+        // like the firmware, everything else needs a successful ConnectRequest first
+        if !self.authenticated {
+            self.closed = true;
+            warn!("simulator received {} before authentication", msg.name());
+            return Ok(());
+        }
+        match &msg {
+            ESPHomeMessage::ListEntitiesRequest(_) => {
+                let responses: Vec<_> = shared
+                    .entities
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|e| e.list_response())
+                    .collect();
+                for response in responses {
+                    writer.write_esphome_message(response).await?;
+                }
+                writer
+                    .write_esphome_message(ESPHomeMessage::ListEntitiesDoneResponse(ListEntitiesDoneResponse {}))
+                    .await?;
+            }
+            ESPHomeMessage::SubscribeStatesRequest(_) => {
+                self.subscribed = true;
+                self.send_states(shared, writer).await?;
+            }
+            ESPHomeMessage::GetTimeRequest(_) => {
+                let epoch_seconds = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs() as u32;
+                writer
+                    .write_esphome_message(ESPHomeMessage::GetTimeResponse(GetTimeResponse { epoch_seconds }))
+                    .await?;
+            }
+            ESPHomeMessage::SubscribeLogsRequest(_)
+            | ESPHomeMessage::SubscribeHomeassistantServicesRequest(_)
+            | ESPHomeMessage::SubscribeHomeAssistantStatesRequest(_) => (),
+            msg => match msg.command_key() {
+                Some(key) => shared.apply(key, msg),
+                None => debug!("simulator ignores {}", msg.name()),
+            },
+        }
+        Ok(())
+    }
+
+    async fn send_states(&self, shared: &Shared, writer: &mut OwnedWriteHalf) -> Result<()> {
+        for state in shared.states() {
+            writer.write_esphome_message(state).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device::Device, result::ErrorKind};
+
+    async fn connect(simulator: SimulatedDevice, password: Option<&str>) -> Result<()> {
+        let handle = simulator.listen("127.0.0.1:0").await?;
+        let mut device = Device::new(
+            "test".to_string(),
+            handle.local_addr().to_string(),
+            password.map(str::to_string),
+        );
+        device.connect().await.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn password_is_checked() {
+        let mut simulator = SimulatedDevice::new("greenhouse");
+        simulator.set_password(Some("secret".to_string()));
+        assert!(connect(simulator, Some("secret")).await.is_ok());
+
+        let mut simulator = SimulatedDevice::new("greenhouse");
+        simulator.set_password(Some("secret".to_string()));
+        let err = connect(simulator, Some("guess")).await.unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidPassword);
+    }
+
+    #[tokio::test]
+    async fn rejected_password_failure() {
+        let mut simulator = SimulatedDevice::new("greenhouse");
+        simulator.set_failures(Failures {
+            reject_password: true,
+            ..Default::default()
+        });
+        let err = connect(simulator, None).await.unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidPassword);
+    }
+
+    #[test]
+    fn behaviors_are_validated() {
+        let mut simulator = SimulatedDevice::new("greenhouse");
+        simulator.add_entity(SimulatedEntity::sensor("temperature", "Temperature", 20.0));
+        let walk = |min: f32, max: f32, step: f32| SensorBehavior::RandomWalk {
+            min,
+            max,
+            step,
+            seed: 1,
+        };
+        let second = Duration::from_secs(1);
+
+        let problem = |simulator: &mut SimulatedDevice, behavior, update_interval| {
+            simulator
+                .add_behavior("temperature", behavior, update_interval)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            problem(&mut simulator, walk(18.0, 24.0, 0.5), Duration::ZERO),
+            "behavior of temperature: update_interval: must be longer than 0s"
+        );
+        assert_eq!(
+            problem(&mut simulator, walk(24.0, 18.0, 0.5), second),
+            "behavior of temperature: min 24 is greater than max 18"
+        );
+        assert_eq!(
+            problem(&mut simulator, walk(f32::NAN, 24.0, 0.5), second),
+            "behavior of temperature: min and max must be numbers"
+        );
+        assert_eq!(
+            problem(&mut simulator, walk(18.0, 24.0, -1.0), second),
+            "behavior of temperature: step -1 is not a positive number"
+        );
+        let sine = SensorBehavior::Sine {
+            min: 18.0,
+            max: 24.0,
+            period: Duration::ZERO,
+        };
+        assert_eq!(
+            problem(&mut simulator, sine, second),
+            "behavior of temperature: period: must be longer than 0s"
+        );
+        assert!(simulator.behaviors.is_empty());
+
+        simulator
+            .add_behavior("temperature", walk(18.0, 24.0, 0.5), second)
+            .unwrap();
+        assert_eq!(simulator.behaviors.len(), 1);
+    }
+}
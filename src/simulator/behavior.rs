@@ -0,0 +1,104 @@
+use std::{f32::consts::PI, time::Duration};
+
+use crate::result::{Error, Result};
+
+// Scripted values of a simulated sensor, updated every update_interval
+#[derive(Clone, Debug, PartialEq)]
+pub enum SensorBehavior {
+    // oscillates between min and max, starting in the middle
+    Sine { min: f32, max: f32, period: Duration },
+    // moves up or down by at most step each update, seeded to be reproducible
+    RandomWalk { min: f32, max: f32, step: f32, seed: u64 },
+    // values in order, e.g. read from a CSV file
+    Replay { values: Vec<f32>, repeat: bool },
+}
+
+impl SensorBehavior {
+    // rejects values the simulation cannot run with, e.g. min greater than max or a NaN bound
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            SensorBehavior::Sine { min, max, period } => {
+                value_range(*min, *max)?;
+                positive(*period).map_err(|err| Error::new(&format!("period: {}", err)))
+            }
+            SensorBehavior::RandomWalk { min, max, step, .. } => {
+                value_range(*min, *max)?;
+                if !step.is_finite() || *step < 0.0 {
+                    return Err(Error::new(&format!("step {} is not a positive number", step)));
+                }
+                Ok(())
+            }
+            SensorBehavior::Replay { .. } => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn positive(duration: Duration) -> Result<()> {
+    if duration.is_zero() {
+        return Err(Error::new("must be longer than 0s"));
+    }
+    Ok(())
+}
+
+fn value_range(min: f32, max: f32) -> Result<()> {
+    if !min.is_finite() || !max.is_finite() {
+        return Err(Error::new("min and max must be numbers"));
+    }
+    if min > max {
+        return Err(Error::new(&format!("min {} is greater than max {}", min, max)));
+    }
+    Ok(())
+}
+
+pub(crate) struct BehaviorState {
+    behavior: SensorBehavior,
+    elapsed: Duration,
+    value: f32,
+    rng: u64,
+    index: usize,
+}
+
+impl BehaviorState {
+    pub(crate) fn new(behavior: SensorBehavior, initial: f32) -> Self {
+        let (value, rng) = match &behavior {
+            SensorBehavior::RandomWalk { min, max, seed, .. } => (initial.clamp(*min, *max), (*seed).max(1)),
+            _ => (initial, 1),
+        };
+        Self {
+            behavior,
+            elapsed: Duration::ZERO,
+            value,
+            rng,
+            index: 0,
+        }
+    }
+
+    // value for now, the following one is `interval` later; None once a replay without repeat is exhausted
+    pub(crate) fn next(&mut self, interval: Duration) -> Option<f32> {
+        let elapsed = self.elapsed;
+        self.elapsed += interval;
+        match &self.behavior {
+            SensorBehavior::Sine { min, max, period } => {
+                let phase = elapsed.as_secs_f32() / period.as_secs_f32().max(f32::EPSILON);
+                Some(min + (max - min) * (1.0 + (2.0 * PI * phase).sin()) / 2.0)
+            }
+            SensorBehavior::RandomWalk { min, max, step, .. } => {
+                // xorshift64, good enough for test values and keeps the walk reproducible
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 7;
+                self.rng ^= self.rng << 17;
+                let unit = (self.rng >> 40) as f32 / (1u64 << 24) as f32;
+                self.value = (self.value + step * (2.0 * unit - 1.0)).clamp(*min, *max);
+                Some(self.value)
+            }
+            SensorBehavior::Replay { values, repeat } => {
+                if values.is_empty() || (!repeat && self.index >= values.len()) {
+                    return None;
+                }
+                let value = values[self.index % values.len()];
+                self.index += 1;
+                Some(value)
+            }
+        }
+    }
+}
@@ -0,0 +1,520 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    api::ClimateMode,
+    duration::parse_duration,
+    result::{Error, ErrorKind, Result},
+    simulator::{
+        behavior::positive, Failures, SensorBehavior, SimulatedDevice, SimulatedEntity, SimulatedState, SimulatorHandle,
+    },
+};
+
+const DEFAULT_PORT: u16 = 6053;
+const DEFAULT_UPDATE_INTERVAL: &str = "60s";
+const DEFAULT_TRAVEL_TIME: &str = "10s";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EsphomeConfig {
+    pub name: String,
+    pub friendly_name: Option<String>,
+    pub mac_address: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApiConfig {
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub password: Option<String>,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            password: None,
+        }
+    }
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+fn default_update_interval() -> String {
+    DEFAULT_UPDATE_INTERVAL.to_string()
+}
+
+fn default_travel_time() -> String {
+    DEFAULT_TRAVEL_TIME.to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SineConfig {
+    pub min: f32,
+    pub max: f32,
+    pub period: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RandomWalkConfig {
+    pub min: f32,
+    pub max: f32,
+    pub step: f32,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+// values of one column, lines that are not a number (e.g. a header) are skipped
+#[derive(Clone, Debug, Deserialize)]
+pub struct CsvConfig {
+    pub file: PathBuf,
+    #[serde(default)]
+    pub column: usize,
+    #[serde(default = "default_true")]
+    pub repeat: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SensorConfig {
+    // object id, derived from the name like ESPHome does when not set
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub initial_value: f32,
+    #[serde(default = "default_update_interval")]
+    pub update_interval: String,
+    pub sine: Option<SineConfig>,
+    pub random_walk: Option<RandomWalkConfig>,
+    pub csv: Option<CsvConfig>,
+}
+
+// binary sensors, switches and lights
+#[derive(Clone, Debug, Deserialize)]
+pub struct ToggleConfig {
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub initial_state: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CoverConfig {
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub position: f32,
+    #[serde(default = "default_travel_time")]
+    pub travel_time: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClimateConfig {
+    pub id: Option<String>,
+    pub name: String,
+    pub current_temperature: f32,
+    pub target_temperature: f32,
+    // off, heat or cool
+    pub mode: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct FailuresConfig {
+    pub disconnect_after: Option<String>,
+    pub response_delay: Option<String>,
+    #[serde(default)]
+    pub reject_password: bool,
+}
+
+// Simulated device described like an ESPHome node, see SimulatorConfig::load
+#[derive(Clone, Debug, Deserialize)]
+pub struct SimulatorConfig {
+    pub esphome: EsphomeConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub sensor: Vec<SensorConfig>,
+    #[serde(default)]
+    pub binary_sensor: Vec<ToggleConfig>,
+    #[serde(default)]
+    pub switch: Vec<ToggleConfig>,
+    #[serde(default)]
+    pub light: Vec<ToggleConfig>,
+    #[serde(default)]
+    pub cover: Vec<CoverConfig>,
+    #[serde(default)]
+    pub climate: Vec<ClimateConfig>,
+    #[serde(default)]
+    pub failures: FailuresConfig,
+    // CSV files are relative to the directory of the config file
+    #[serde(skip)]
+    base_dir: PathBuf,
+}
+
+impl SimulatorConfig {
+    // reads a YAML file, e.g.
+    //   esphome:
+    //     name: greenhouse
+    //   api:
+    //     port: 0
+    //   sensor:
+    //     - name: Temperature
+    //       update_interval: 1s
+    //       sine: { min: 18, max: 24, period: 10min }
+    //   switch:
+    //     - name: Pump
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut config: SimulatorConfig = config::Config::builder()
+            .add_source(config::File::from(path))
+            .build()?
+            .try_deserialize()?;
+        config.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        config.validate().map_err(|err| {
+            let problems = match err.kind() {
+                ErrorKind::InvalidConfig { problems } => problems.clone(),
+                _ => vec![err.to_string()],
+            };
+            Error::invalid_config(&path.display().to_string(), problems)
+        })?;
+        Ok(config)
+    }
+
+    // reports every setting the simulator cannot run with at once, e.g. a zero update_interval
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        for sensor in &self.sensor {
+            let label = format!("sensor {}", sensor.name);
+            if let Err(err) = positive_duration(&sensor.update_interval) {
+                problems.push(format!("{}: update_interval: {}", label, err));
+            }
+            if let Some(sine) = &sensor.sine {
+                match parse_duration(&sine.period) {
+                    Ok(period) => {
+                        let behavior = SensorBehavior::Sine {
+                            min: sine.min,
+                            max: sine.max,
+                            period,
+                        };
+                        if let Err(err) = behavior.validate() {
+                            problems.push(format!("{}: sine: {}", label, err));
+                        }
+                    }
+                    Err(err) => problems.push(format!("{}: sine: period: {}", label, err)),
+                }
+            }
+            if let Some(walk) = &sensor.random_walk {
+                let behavior = SensorBehavior::RandomWalk {
+                    min: walk.min,
+                    max: walk.max,
+                    step: walk.step,
+                    seed: walk.seed,
+                };
+                if let Err(err) = behavior.validate() {
+                    problems.push(format!("{}: random_walk: {}", label, err));
+                }
+            }
+            let behaviors = [
+                sensor.sine.is_some(),
+                sensor.random_walk.is_some(),
+                sensor.csv.is_some(),
+            ];
+            if behaviors.iter().filter(|b| **b).count() > 1 {
+                problems.push(format!("{}: only one of sine, random_walk and csv can be set", label));
+            }
+        }
+        for cover in &self.cover {
+            if let Err(err) = parse_duration(&cover.travel_time) {
+                problems.push(format!("cover {}: travel_time: {}", cover.name, err));
+            }
+        }
+        for climate in &self.climate {
+            if let Some(mode) = &climate.mode {
+                if let Err(err) = climate_mode(mode) {
+                    problems.push(format!("climate {}: {}", climate.name, err));
+                }
+            }
+        }
+        let failures = [
+            ("disconnect_after", &self.failures.disconnect_after),
+            ("response_delay", &self.failures.response_delay),
+        ];
+        for (name, value) in failures {
+            if let Some(Err(err)) = value.as_deref().map(parse_duration) {
+                problems.push(format!("failures: {}: {}", name, err));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::invalid_config(&self.esphome.name, problems))
+        }
+    }
+
+    pub fn device(&self) -> Result<SimulatedDevice> {
+        self.validate()?;
+        let mut device = SimulatedDevice::new(&self.esphome.name);
+        let mut info = device.info.clone();
+        if let Some(friendly_name) = &self.esphome.friendly_name {
+            info.friendly_name = friendly_name.clone();
+        }
+        if let Some(mac_address) = &self.esphome.mac_address {
+            info.mac_address = mac_address.clone();
+        }
+        device.set_device_info(info);
+        device.set_password(self.api.password.clone());
+
+        for sensor in &self.sensor {
+            let object_id = object_id(&sensor.id, &sensor.name);
+            device.add_entity(SimulatedEntity::sensor(&object_id, &sensor.name, sensor.initial_value));
+            if let Some(behavior) = self.sensor_behavior(sensor)? {
+                device.add_behavior(&object_id, behavior, parse_duration(&sensor.update_interval)?)?;
+            }
+        }
+        for sensor in &self.binary_sensor {
+            let object_id = object_id(&sensor.id, &sensor.name);
+            device.add_entity(SimulatedEntity::binary_sensor(
+                &object_id,
+                &sensor.name,
+                sensor.initial_state,
+            ));
+        }
+        for switch in &self.switch {
+            let object_id = object_id(&switch.id, &switch.name);
+            device.add_entity(SimulatedEntity::switch(&object_id, &switch.name, switch.initial_state));
+        }
+        for light in &self.light {
+            let object_id = object_id(&light.id, &light.name);
+            device.add_entity(SimulatedEntity::light(&object_id, &light.name, light.initial_state));
+        }
+        for cover in &self.cover {
+            let object_id = object_id(&cover.id, &cover.name);
+            let travel_time = parse_duration(&cover.travel_time)?;
+            device.add_entity(SimulatedEntity::cover(
+                &object_id,
+                &cover.name,
+                cover.position,
+                travel_time,
+            ));
+        }
+        for climate in &self.climate {
+            let object_id = object_id(&climate.id, &climate.name);
+            let mut entity = SimulatedEntity::climate(
+                &object_id,
+                &climate.name,
+                climate.current_temperature,
+                climate.target_temperature,
+            );
+            if let (Some(mode), SimulatedState::Climate { mode: state_mode, .. }) = (&climate.mode, &mut entity.state) {
+                *state_mode = climate_mode(mode)?;
+            }
+            device.add_entity(entity);
+        }
+
+        device.set_failures(Failures {
+            disconnect_after: self
+                .failures
+                .disconnect_after
+                .as_deref()
+                .map(parse_duration)
+                .transpose()?,
+            response_delay: self
+                .failures
+                .response_delay
+                .as_deref()
+                .map(parse_duration)
+                .transpose()?,
+            reject_password: self.failures.reject_password,
+        });
+        Ok(device)
+    }
+
+    // listens on localhost at api.port, port 0 picks a free port
+    pub async fn listen(&self) -> Result<SimulatorHandle> {
+        self.device()?.listen(&format!("127.0.0.1:{}", self.api.port)).await
+    }
+
+    fn sensor_behavior(&self, sensor: &SensorConfig) -> Result<Option<SensorBehavior>> {
+        let behavior = match (&sensor.sine, &sensor.random_walk, &sensor.csv) {
+            (None, None, None) => return Ok(None),
+            (Some(sine), None, None) => SensorBehavior::Sine {
+                min: sine.min,
+                max: sine.max,
+                period: parse_duration(&sine.period)?,
+            },
+            (None, Some(walk), None) => SensorBehavior::RandomWalk {
+                min: walk.min,
+                max: walk.max,
+                step: walk.step,
+                seed: walk.seed,
+            },
+            (None, None, Some(csv)) => SensorBehavior::Replay {
+                values: read_csv(&self.base_dir.join(&csv.file), csv.column)?,
+                repeat: csv.repeat,
+            },
+            _ => {
+                return Err(Error::new(&format!(
+                    "sensor {} can only have one of sine, random_walk and csv",
+                    sensor.name
+                )))
+            }
+        };
+        Ok(Some(behavior))
+    }
+}
+
+fn climate_mode(mode: &str) -> Result<ClimateMode> {
+    ClimateMode::from_str_name(&format!("CLIMATE_MODE_{}", mode.to_uppercase()))
+        .ok_or_else(|| Error::new(&format!("unknown climate mode {}", mode)))
+}
+
+fn positive_duration(value: &str) -> Result<Duration> {
+    let duration = parse_duration(value)?;
+    positive(duration)?;
+    Ok(duration)
+}
+
+// object id the firmware derives from the entity name, e.g. "Living Room Temp" is living_room_temp
+fn object_id(id: &Option<String>, name: &str) -> String {
+    if let Some(id) = id {
+        return id.clone();
+    }
+    name.to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('_'),
+            'a'..='z' | '0'..='9' | '_' | '-' => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+fn read_csv(path: &Path, column: usize) -> Result<Vec<f32>> {
+    let values: Vec<f32> = fs::read_to_string(path)?
+        .lines()
+        .filter_map(|line| line.split(',').nth(column)?.trim().parse().ok())
+        .collect();
+    if values.is_empty() {
+        return Err(Error::new(&format!(
+            "no values in column {} of {}",
+            column,
+            path.display()
+        )));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> SimulatorConfig {
+        config::Config::builder()
+            .add_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn problems(config: &SimulatorConfig) -> Vec<String> {
+        match config.validate().unwrap_err().kind() {
+            ErrorKind::InvalidConfig { problems } => problems.clone(),
+            kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn valid_config() {
+        let config = parse(
+            "
+esphome: { name: greenhouse }
+sensor:
+  - name: Temperature
+    update_interval: 1s
+    sine: { min: 18, max: 24, period: 10min }
+  - name: Humidity
+    random_walk: { min: 40, max: 60, step: 1 }
+climate:
+  - { name: Heater, current_temperature: 20, target_temperature: 21, mode: heat }
+failures: { disconnect_after: 5min }
+",
+        );
+        assert!(config.validate().is_ok());
+        assert!(config.device().is_ok());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let config = parse(
+            "
+esphome: { name: greenhouse }
+sensor:
+  - name: Temperature
+    update_interval: 0s
+    random_walk: { min: 10, max: 0, step: 1 }
+  - name: Humidity
+    sine: { min: 40, max: 60, period: 0ms }
+cover:
+  - { name: Vent, travel_time: soon }
+climate:
+  - { name: Heater, current_temperature: 20, target_temperature: 21, mode: boost }
+failures: { response_delay: 999999999999999999999h }
+",
+        );
+        assert_eq!(
+            problems(&config),
+            [
+                "sensor Temperature: update_interval: must be longer than 0s",
+                "sensor Temperature: random_walk: min 10 is greater than max 0",
+                "sensor Humidity: sine: period: must be longer than 0s",
+                "cover Vent: travel_time: invalid duration soon",
+                "climate Heater: unknown climate mode boost",
+                "failures: response_delay: invalid duration 999999999999999999999h",
+            ]
+        );
+        assert!(config.device().is_err());
+    }
+
+    #[test]
+    fn single_behavior_per_sensor() {
+        let config = parse(
+            "
+esphome: { name: greenhouse }
+sensor:
+  - name: Temperature
+    sine: { min: 18, max: 24, period: 10min }
+    random_walk: { min: 18, max: 24, step: 0.5 }
+",
+        );
+        assert_eq!(
+            problems(&config),
+            ["sensor Temperature: only one of sine, random_walk and csv can be set"]
+        );
+    }
+
+    #[test]
+    fn load_reports_the_file() {
+        let path = std::env::temp_dir().join(format!("esphome-simulator-{}.yaml", std::process::id()));
+        fs::write(
+            &path,
+            "esphome: { name: greenhouse }\nsensor: [{ name: T, random_walk: { min: 10, max: 0, step: 1 } }]\n",
+        )
+        .unwrap();
+        let err = SimulatorConfig::load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(err
+            .to_string()
+            .starts_with(&format!("invalid configuration {}", path.display())));
+        assert!(matches!(err.kind(), ErrorKind::InvalidConfig { problems } if problems.len() == 1));
+    }
+}
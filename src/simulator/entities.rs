@@ -0,0 +1,306 @@
+use std::time::Duration;
+
+use crate::{api::*, messages::ESPHomeMessage};
+
+const MIN_TEMPERATURE: f32 = 5.0;
+const MAX_TEMPERATURE: f32 = 35.0;
+const TEMPERATURE_STEP: f32 = 0.5;
+
+// ESPHome derives entity keys from the object id with a 32-bit FNV-1 hash
+pub(crate) fn fnv1_hash(value: &str) -> u32 {
+    value.bytes().fold(2_166_136_261u32, |hash, byte| {
+        hash.wrapping_mul(16_777_619) ^ byte as u32
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SimulatedState {
+    BinarySensor(bool),
+    Sensor(f32),
+    Switch(bool),
+    // brightness from 0.0 to 1.0
+    Light {
+        on: bool,
+        brightness: f32,
+    },
+    // positions from 0.0 (closed) to 1.0 (open), a full move takes travel_time
+    Cover {
+        position: f32,
+        target: f32,
+        travel_time: Duration,
+    },
+    Climate {
+        mode: ClimateMode,
+        current_temperature: f32,
+        target_temperature: f32,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulatedEntity {
+    pub object_id: String,
+    pub name: String,
+    pub key: u32,
+    pub state: SimulatedState,
+}
+
+impl SimulatedEntity {
+    pub fn new(object_id: &str, name: &str, state: SimulatedState) -> Self {
+        Self {
+            object_id: object_id.to_string(),
+            name: name.to_string(),
+            key: fnv1_hash(object_id),
+            state,
+        }
+    }
+
+    pub fn binary_sensor(object_id: &str, name: &str, state: bool) -> Self {
+        Self::new(object_id, name, SimulatedState::BinarySensor(state))
+    }
+
+    pub fn sensor(object_id: &str, name: &str, state: f32) -> Self {
+        Self::new(object_id, name, SimulatedState::Sensor(state))
+    }
+
+    pub fn switch(object_id: &str, name: &str, state: bool) -> Self {
+        Self::new(object_id, name, SimulatedState::Switch(state))
+    }
+
+    pub fn light(object_id: &str, name: &str, on: bool) -> Self {
+        Self::new(object_id, name, SimulatedState::Light { on, brightness: 1.0 })
+    }
+
+    pub fn cover(object_id: &str, name: &str, position: f32, travel_time: Duration) -> Self {
+        Self::new(
+            object_id,
+            name,
+            SimulatedState::Cover {
+                position,
+                target: position,
+                travel_time,
+            },
+        )
+    }
+
+    pub fn climate(object_id: &str, name: &str, current_temperature: f32, target_temperature: f32) -> Self {
+        Self::new(
+            object_id,
+            name,
+            SimulatedState::Climate {
+                mode: ClimateMode::Off,
+                current_temperature,
+                target_temperature,
+            },
+        )
+    }
+
+    pub(crate) fn list_response(&self) -> ESPHomeMessage {
+        let object_id = self.object_id.clone();
+        let name = self.name.clone();
+        let unique_id = self.object_id.clone();
+        let key = self.key;
+        match &self.state {
+            SimulatedState::BinarySensor(_) => {
+                ESPHomeMessage::ListEntitiesBinarySensorResponse(ListEntitiesBinarySensorResponse {
+                    object_id,
+                    key,
+                    name,
+                    unique_id,
+                    ..Default::default()
+                })
+            }
+            SimulatedState::Sensor(_) => ESPHomeMessage::ListEntitiesSensorResponse(ListEntitiesSensorResponse {
+                object_id,
+                key,
+                name,
+                unique_id,
+                ..Default::default()
+            }),
+            SimulatedState::Switch(_) => ESPHomeMessage::ListEntitiesSwitchResponse(ListEntitiesSwitchResponse {
+                object_id,
+                key,
+                name,
+                unique_id,
+                ..Default::default()
+            }),
+            SimulatedState::Light { .. } => ESPHomeMessage::ListEntitiesLightResponse(ListEntitiesLightResponse {
+                object_id,
+                key,
+                name,
+                unique_id,
+                supported_color_modes: vec![ColorMode::Brightness as i32],
+                ..Default::default()
+            }),
+            SimulatedState::Cover { .. } => ESPHomeMessage::ListEntitiesCoverResponse(ListEntitiesCoverResponse {
+                object_id,
+                key,
+                name,
+                unique_id,
+                supports_position: true,
+                supports_stop: true,
+                ..Default::default()
+            }),
+            SimulatedState::Climate { .. } => {
+                ESPHomeMessage::ListEntitiesClimateResponse(ListEntitiesClimateResponse {
+                    object_id,
+                    key,
+                    name,
+                    unique_id,
+                    supports_current_temperature: true,
+                    supports_action: true,
+                    supported_modes: vec![
+                        ClimateMode::Off as i32,
+                        ClimateMode::Heat as i32,
+                        ClimateMode::Cool as i32,
+                    ],
+                    visual_min_temperature: MIN_TEMPERATURE,
+                    visual_max_temperature: MAX_TEMPERATURE,
+                    visual_target_temperature_step: TEMPERATURE_STEP,
+                    visual_current_temperature_step: TEMPERATURE_STEP,
+                    ..Default::default()
+                })
+            }
+        }
+    }
+
+    pub(crate) fn state_response(&self) -> ESPHomeMessage {
+        let key = self.key;
+        match &self.state {
+            SimulatedState::BinarySensor(state) => {
+                ESPHomeMessage::BinarySensorStateResponse(BinarySensorStateResponse {
+                    key,
+                    state: *state,
+                    missing_state: false,
+                })
+            }
+            SimulatedState::Sensor(state) => ESPHomeMessage::SensorStateResponse(SensorStateResponse {
+                key,
+                state: *state,
+                missing_state: false,
+            }),
+            SimulatedState::Switch(state) => {
+                ESPHomeMessage::SwitchStateResponse(SwitchStateResponse { key, state: *state })
+            }
+            SimulatedState::Light { on, brightness } => ESPHomeMessage::LightStateResponse(LightStateResponse {
+                key,
+                state: *on,
+                brightness: *brightness,
+                color_mode: ColorMode::Brightness as i32,
+                ..Default::default()
+            }),
+            SimulatedState::Cover { position, target, .. } => {
+                let operation = if target > position {
+                    CoverOperation::IsOpening
+                } else if target < position {
+                    CoverOperation::IsClosing
+                } else {
+                    CoverOperation::Idle
+                };
+                let legacy_state = if *position > 0.0 {
+                    LegacyCoverState::Open
+                } else {
+                    LegacyCoverState::Closed
+                };
+                ESPHomeMessage::CoverStateResponse(CoverStateResponse {
+                    key,
+                    legacy_state: legacy_state as i32,
+                    position: *position,
+                    tilt: 0.0,
+                    current_operation: operation as i32,
+                })
+            }
+            SimulatedState::Climate {
+                mode,
+                current_temperature,
+                target_temperature,
+            } => {
+                let action = match mode {
+                    ClimateMode::Off => ClimateAction::Off,
+                    ClimateMode::Heat if current_temperature < target_temperature => ClimateAction::Heating,
+                    ClimateMode::Cool if current_temperature > target_temperature => ClimateAction::Cooling,
+                    _ => ClimateAction::Idle,
+                };
+                ESPHomeMessage::ClimateStateResponse(ClimateStateResponse {
+                    key,
+                    mode: *mode as i32,
+                    current_temperature: *current_temperature,
+                    target_temperature: *target_temperature,
+                    action: action as i32,
+                    ..Default::default()
+                })
+            }
+        }
+    }
+
+    // applies a command addressed to this entity, returns whether the state changed
+    pub(crate) fn apply(&mut self, msg: &ESPHomeMessage) -> bool {
+        let before = self.state.clone();
+        match (&mut self.state, msg) {
+            (SimulatedState::Switch(state), ESPHomeMessage::SwitchCommandRequest(cmd)) => *state = cmd.state,
+            (SimulatedState::Light { on, brightness }, ESPHomeMessage::LightCommandRequest(cmd)) => {
+                if cmd.has_state {
+                    *on = cmd.state;
+                }
+                if cmd.has_brightness {
+                    *brightness = cmd.brightness.clamp(0.0, 1.0);
+                }
+            }
+            (SimulatedState::Cover { position, target, .. }, ESPHomeMessage::CoverCommandRequest(cmd)) => {
+                if cmd.stop {
+                    *target = *position;
+                } else if cmd.has_position {
+                    *target = cmd.position.clamp(0.0, 1.0);
+                } else if cmd.has_legacy_command {
+                    *target = match LegacyCoverCommand::try_from(cmd.legacy_command) {
+                        Ok(LegacyCoverCommand::Open) => 1.0,
+                        Ok(LegacyCoverCommand::Close) => 0.0,
+                        _ => *position,
+                    };
+                }
+            }
+            (
+                SimulatedState::Climate {
+                    mode,
+                    target_temperature,
+                    ..
+                },
+                ESPHomeMessage::ClimateCommandRequest(cmd),
+            ) => {
+                if cmd.has_mode {
+                    *mode = ClimateMode::try_from(cmd.mode).unwrap_or(*mode);
+                }
+                if cmd.has_target_temperature {
+                    *target_temperature = cmd.target_temperature.clamp(MIN_TEMPERATURE, MAX_TEMPERATURE);
+                }
+            }
+            _ => return false,
+        }
+        self.state != before
+    }
+
+    // moves covers towards their target, returns whether the state changed
+    pub(crate) fn step(&mut self, elapsed: Duration) -> bool {
+        let SimulatedState::Cover {
+            position,
+            target,
+            travel_time,
+        } = &mut self.state
+        else {
+            return false;
+        };
+        if position == target {
+            return false;
+        }
+        let distance = if travel_time.is_zero() {
+            1.0
+        } else {
+            elapsed.as_secs_f32() / travel_time.as_secs_f32()
+        };
+        *position = if *target > *position {
+            (*position + distance).min(*target)
+        } else {
+            (*position - distance).max(*target)
+        };
+        true
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Snapshot of the traffic of a connection
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConnectionStats {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    // number of writes to the socket, each may contain several messages
+    pub flushes: u64,
+    pub messages_received: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct StatsCounters {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    flushes: AtomicU64,
+    messages_received: AtomicU64,
+}
+
+impl StatsCounters {
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn flushed(&self) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -4,13 +4,7 @@ use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-
-pub trait VarintWrite {
-    fn write_varint(&mut self, value: u32) -> WriteVarint<'_, Self>
-    where
-        Self: AsyncWrite + Unpin + Sized;
-}
+use tokio::io::{AsyncRead, ReadBuf};
 
 pub trait VarintRead {
     fn read_varint(&mut self) -> ReadVarint<'_, Self>
@@ -18,14 +12,6 @@ pub trait VarintRead {
         Self: AsyncRead + Unpin + Sized;
 }
 
-pub struct WriteVarint<'a, W>
-where
-    W: AsyncWrite + Unpin + ?Sized,
-{
-    writer: &'a mut W,
-    value: u32,
-}
-
 pub struct ReadVarint<'a, R>
 where
     R: AsyncRead + Unpin + ?Sized,
@@ -33,15 +19,6 @@ where
     reader: &'a mut R,
 }
 
-impl<W> VarintWrite for W
-where
-    W: AsyncWrite + Unpin + Sized,
-{
-    fn write_varint(&mut self, value: u32) -> WriteVarint<'_, Self> {
-        WriteVarint { writer: self, value }
-    }
-}
-
 impl<R> VarintRead for R
 where
     R: AsyncRead + Unpin + Sized,
@@ -51,29 +28,6 @@ where
     }
 }
 
-impl<'a, W: AsyncWrite + Unpin + ?Sized> Future for WriteVarint<'a, W> {
-    type Output = Result<()>;
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        while self.value >= 0x80 {
-            let byte_to_write = ((self.value as u8) & 0x7F) | 0x80;
-            match Pin::new(&mut self.writer).poll_write(cx, &[byte_to_write]) {
-                Poll::Ready(Ok(_)) => self.value >>= 7,
-                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
-                Poll::Pending => return Poll::Pending,
-            }
-        }
-
-        // Now write the final byte
-        let byte_to_write = self.value as u8;
-        match Pin::new(&mut self.writer).poll_write(cx, &[byte_to_write]) {
-            Poll::Ready(Ok(_)) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
-            Poll::Pending => Poll::Pending,
-        }
-    }
-}
-
 impl<'a, R: AsyncRead + Unpin + ?Sized> Future for ReadVarint<'a, R> {
     type Output = Result<u32>;
 
@@ -0,0 +1,271 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::SystemTime};
+
+use log::{debug, error, warn};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{api::*, connection::CommandSender, messages::ESPHomeMessage, result::Result};
+
+pub mod recorder;
+
+// satellites stream raw 16-bit little endian mono PCM at this rate
+pub const SAMPLE_RATE: u32 = 16000;
+pub const SAMPLE_WIDTH: u16 = 2;
+pub const CHANNELS: u16 = 1;
+
+const AUDIO_QUEUE: usize = 64;
+const MAX_DATAGRAM: usize = 2048;
+
+// chunks of PCM audio as received over UDP, the stream ends when the device stops the session
+pub type AudioStream = mpsc::Receiver<Vec<u8>>;
+
+pub type PipelineFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoiceSession {
+    pub conversation_id: String,
+    pub flags: u32,
+    pub audio_settings: Option<VoiceAssistantAudioSettings>,
+    pub started_at: SystemTime,
+}
+
+impl VoiceSession {
+    pub fn use_vad(&self) -> bool {
+        self.flags & VoiceAssistantRequestFlag::VoiceAssistantRequestUseVad as u32 != 0
+    }
+
+    pub fn use_wake_word(&self) -> bool {
+        self.flags & VoiceAssistantRequestFlag::VoiceAssistantRequestUseWakeWord as u32 != 0
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IntentResult {
+    // text to be spoken back to the user
+    pub speech: String,
+    pub conversation_id: Option<String>,
+}
+
+pub trait VoicePipeline: Send + Sync {
+    fn speech_to_text<'a>(&'a self, session: &'a VoiceSession, audio: AudioStream) -> PipelineFuture<'a, String>;
+
+    fn recognize_intent<'a>(&'a self, session: &'a VoiceSession, text: String) -> PipelineFuture<'a, IntentResult>;
+
+    // returns URL the satellite downloads the synthesized answer from
+    fn text_to_speech<'a>(&'a self, session: &'a VoiceSession, text: String) -> PipelineFuture<'a, String>;
+
+    // false for pipelines that only consume the audio, the device is then told about the start and
+    // end of the run but not about speech, intent and answer
+    fn responds(&self) -> bool {
+        true
+    }
+}
+
+fn event(event_type: VoiceAssistantEvent, data: &[(&str, &str)]) -> ESPHomeMessage {
+    ESPHomeMessage::VoiceAssistantEventResponse(VoiceAssistantEventResponse {
+        event_type: event_type as i32,
+        data: data
+            .iter()
+            .map(|(name, value)| VoiceAssistantEventData {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect(),
+    })
+}
+
+async fn send(sender: &CommandSender, msg: ESPHomeMessage) -> Result<()> {
+    sender.send_message(msg).await
+}
+
+async fn receive_audio(socket: UdpSocket, audio_tx: mpsc::Sender<Vec<u8>>, mut stop_rx: oneshot::Receiver<()>) {
+    let mut buf = [0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            _ = &mut stop_rx => break,
+            received = socket.recv(&mut buf) => match received {
+                Ok(len) => {
+                    if audio_tx.send(buf[..len].to_vec()).await.is_err() {
+                        // pipeline is not interested in more audio
+                        break;
+                    }
+                }
+                Err(err) => {
+                    error!("error receiving voice assistant audio {:?}", err);
+                    break;
+                }
+            },
+        }
+    }
+}
+
+async fn run_pipeline(
+    pipeline: &dyn VoicePipeline,
+    session: &VoiceSession,
+    audio: AudioStream,
+    sender: &CommandSender,
+) -> Result<()> {
+    if !pipeline.responds() {
+        pipeline.speech_to_text(session, audio).await?;
+        return Ok(());
+    }
+
+    send(sender, event(VoiceAssistantEvent::VoiceAssistantSttStart, &[])).await?;
+    let text = pipeline.speech_to_text(session, audio).await?;
+    send(
+        sender,
+        event(VoiceAssistantEvent::VoiceAssistantSttEnd, &[("text", &text)]),
+    )
+    .await?;
+
+    send(sender, event(VoiceAssistantEvent::VoiceAssistantIntentStart, &[])).await?;
+    let intent = pipeline.recognize_intent(session, text).await?;
+    let conversation_id = intent
+        .conversation_id
+        .clone()
+        .unwrap_or_else(|| session.conversation_id.clone());
+    send(
+        sender,
+        event(
+            VoiceAssistantEvent::VoiceAssistantIntentEnd,
+            &[("conversation_id", &conversation_id)],
+        ),
+    )
+    .await?;
+
+    send(
+        sender,
+        event(VoiceAssistantEvent::VoiceAssistantTtsStart, &[("text", &intent.speech)]),
+    )
+    .await?;
+    let url = pipeline.text_to_speech(session, intent.speech).await?;
+    send(
+        sender,
+        event(VoiceAssistantEvent::VoiceAssistantTtsEnd, &[("url", &url)]),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn run_session(
+    pipeline: Arc<dyn VoicePipeline>,
+    session: VoiceSession,
+    audio: AudioStream,
+    sender: CommandSender,
+) -> Result<()> {
+    send(&sender, event(VoiceAssistantEvent::VoiceAssistantRunStart, &[])).await?;
+    if let Err(err) = run_pipeline(pipeline.as_ref(), &session, audio, &sender).await {
+        warn!("voice assistant pipeline failed {:?}", err);
+        let message = err.to_string();
+        send(
+            &sender,
+            event(
+                VoiceAssistantEvent::VoiceAssistantError,
+                &[("code", "pipeline-error"), ("message", &message)],
+            ),
+        )
+        .await?;
+    }
+    send(&sender, event(VoiceAssistantEvent::VoiceAssistantRunEnd, &[])).await?;
+    Ok(())
+}
+
+// Serves voice assistant requests of a single device with the given pipeline
+pub struct VoiceAssistant {
+    sender: CommandSender,
+    voice_requests: Arc<Mutex<Option<mpsc::Sender<VoiceAssistantRequest>>>>,
+    task: JoinHandle<()>,
+}
+
+impl VoiceAssistant {
+    pub(crate) async fn start(
+        sender: CommandSender,
+        voice_requests: Arc<Mutex<Option<mpsc::Sender<VoiceAssistantRequest>>>>,
+        pipeline: Arc<dyn VoicePipeline>,
+    ) -> Result<Self> {
+        let (request_tx, mut request_rx) = mpsc::channel(8);
+        *voice_requests.lock().await = Some(request_tx);
+
+        send(
+            &sender,
+            ESPHomeMessage::SubscribeVoiceAssistantRequest(SubscribeVoiceAssistantRequest { subscribe: true }),
+        )
+        .await?;
+
+        let sender_clone = sender.clone();
+        let task = tokio::spawn(async move {
+            let mut stop_audio: Option<oneshot::Sender<()>> = None;
+            while let Some(request) = request_rx.recv().await {
+                // any request ends the running audio stream, start = false just does nothing else
+                if let Some(stop) = stop_audio.take() {
+                    let _ = stop.send(());
+                }
+                if !request.start {
+                    debug!("voice assistant session {} stopped", request.conversation_id);
+                    continue;
+                }
+
+                let socket = match UdpSocket::bind("0.0.0.0:0").await {
+                    Ok(socket) => socket,
+                    Err(err) => {
+                        error!("unable to open voice assistant audio socket {:?}", err);
+                        let response = VoiceAssistantResponse { port: 0, error: true };
+                        if let Err(err) = send(&sender_clone, ESPHomeMessage::VoiceAssistantResponse(response)).await {
+                            error!("error sending voice assistant response {:?}", err);
+                        }
+                        continue;
+                    }
+                };
+                let port = socket.local_addr().map(|a| a.port() as u32).unwrap_or_default();
+                let response = VoiceAssistantResponse { port, error: false };
+                if let Err(err) = send(&sender_clone, ESPHomeMessage::VoiceAssistantResponse(response)).await {
+                    error!("error sending voice assistant response {:?}", err);
+                    continue;
+                }
+
+                let session = VoiceSession {
+                    conversation_id: request.conversation_id,
+                    flags: request.flags,
+                    audio_settings: request.audio_settings,
+                    started_at: SystemTime::now(),
+                };
+                debug!(
+                    "voice assistant session {} started on port {}",
+                    session.conversation_id, port
+                );
+
+                let (audio_tx, audio_rx) = mpsc::channel(AUDIO_QUEUE);
+                let (stop_tx, stop_rx) = oneshot::channel();
+                stop_audio = Some(stop_tx);
+                tokio::spawn(receive_audio(socket, audio_tx, stop_rx));
+
+                let pipeline = pipeline.clone();
+                let sender = sender_clone.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = run_session(pipeline, session, audio_rx, sender).await {
+                        error!("error running voice assistant session {:?}", err);
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            sender,
+            voice_requests,
+            task,
+        })
+    }
+
+    pub async fn stop(self) -> Result<()> {
+        *self.voice_requests.lock().await = None;
+        self.task.abort();
+        send(
+            &self.sender,
+            ESPHomeMessage::SubscribeVoiceAssistantRequest(SubscribeVoiceAssistantRequest { subscribe: false }),
+        )
+        .await
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, error};
+use serde::Serialize;
+use tokio::{
+    fs::File,
+    io::{AsyncSeekExt, AsyncWriteExt, SeekFrom},
+    sync::mpsc,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    result::Result,
+    voice::{
+        AudioStream, IntentResult, PipelineFuture, VoicePipeline, VoiceSession, CHANNELS, SAMPLE_RATE, SAMPLE_WIDTH,
+    },
+};
+
+const DEFAULT_MAX_DURATION: Duration = Duration::from_secs(60);
+const WAV_HEADER_LEN: u32 = 44;
+
+#[derive(Serialize)]
+struct AudioSettingsMetadata {
+    noise_suppression_level: u32,
+    auto_gain: u32,
+    volume_multiplier: f32,
+}
+
+#[derive(Serialize)]
+struct RecordingMetadata {
+    conversation_id: String,
+    started_at: DateTime<Utc>,
+    ended_at: DateTime<Utc>,
+    stop_reason: &'static str,
+    audio_bytes: u32,
+    sample_rate: u32,
+    channels: u16,
+    sample_width: u16,
+    flags: u32,
+    use_vad: bool,
+    use_wake_word: bool,
+    audio_settings: Option<AudioSettingsMetadata>,
+}
+
+fn wav_header(data_len: u32) -> Vec<u8> {
+    let byte_rate = SAMPLE_RATE * CHANNELS as u32 * SAMPLE_WIDTH as u32;
+    let mut header = Vec::with_capacity(WAV_HEADER_LEN as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(WAV_HEADER_LEN - 8 + data_len).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    header.extend_from_slice(&CHANNELS.to_le_bytes());
+    header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    header.extend_from_slice(&byte_rate.to_le_bytes());
+    header.extend_from_slice(&(CHANNELS * SAMPLE_WIDTH).to_le_bytes());
+    header.extend_from_slice(&(SAMPLE_WIDTH * 8).to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+// conversation ids come from the device, keep them safe to use in a file name
+fn file_stem(session: &VoiceSession) -> String {
+    let started_at: DateTime<Utc> = session.started_at.into();
+    let id: String = session
+        .conversation_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    let id = if id.is_empty() { "unknown".to_string() } else { id };
+    format!("{}_{}", started_at.format("%Y%m%dT%H%M%S%.3fZ"), id)
+}
+
+// Records audio of every voice assistant session to a WAV file with a JSON sidecar,
+// optionally passing the audio on to a real pipeline
+pub struct VoiceRecorder {
+    directory: PathBuf,
+    max_duration: Duration,
+    pipeline: Option<Arc<dyn VoicePipeline>>,
+}
+
+impl VoiceRecorder {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+            max_duration: DEFAULT_MAX_DURATION,
+            pipeline: None,
+        }
+    }
+
+    pub fn with_pipeline<P: AsRef<Path>, V: VoicePipeline + 'static>(directory: P, pipeline: V) -> Self {
+        Self {
+            pipeline: Some(Arc::new(pipeline)),
+            ..Self::new(directory)
+        }
+    }
+
+    // recording stops after this time even if the device does not end the session
+    pub fn set_max_duration(&mut self, max_duration: Duration) {
+        self.max_duration = max_duration;
+    }
+
+    async fn record(
+        &self,
+        session: &VoiceSession,
+        mut audio: AudioStream,
+        forward: Option<mpsc::Sender<Vec<u8>>>,
+    ) -> Result<PathBuf> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let stem = file_stem(session);
+        let wav_path = self.directory.join(format!("{}.wav", stem));
+        let mut file = File::create(&wav_path).await?;
+        file.write_all(&wav_header(0)).await?;
+
+        let deadline = Instant::now() + self.max_duration;
+        let mut audio_bytes = 0u32;
+        let stop_reason = loop {
+            match timeout_at(deadline, audio.recv()).await {
+                Ok(Some(chunk)) => {
+                    file.write_all(&chunk).await?;
+                    audio_bytes += chunk.len() as u32;
+                    if let Some(tx) = &forward {
+                        let _ = tx.send(chunk).await;
+                    }
+                }
+                Ok(None) => break "stopped",
+                Err(_) => break "timeout",
+            }
+        };
+
+        file.seek(SeekFrom::Start(0)).await?;
+        file.write_all(&wav_header(audio_bytes)).await?;
+        file.flush().await?;
+
+        let metadata = RecordingMetadata {
+            conversation_id: session.conversation_id.clone(),
+            started_at: session.started_at.into(),
+            ended_at: Utc::now(),
+            stop_reason,
+            audio_bytes,
+            sample_rate: SAMPLE_RATE,
+            channels: CHANNELS,
+            sample_width: SAMPLE_WIDTH,
+            flags: session.flags,
+            use_vad: session.use_vad(),
+            use_wake_word: session.use_wake_word(),
+            audio_settings: session.audio_settings.as_ref().map(|s| AudioSettingsMetadata {
+                noise_suppression_level: s.noise_suppression_level,
+                auto_gain: s.auto_gain,
+                volume_multiplier: s.volume_multiplier,
+            }),
+        };
+        let json = serde_json::to_vec_pretty(&metadata)?;
+        tokio::fs::write(self.directory.join(format!("{}.json", stem)), json).await?;
+
+        debug!(
+            "recorded {} bytes of audio to {:?} ({})",
+            audio_bytes, wav_path, stop_reason
+        );
+        Ok(wav_path)
+    }
+}
+
+impl VoicePipeline for VoiceRecorder {
+    fn speech_to_text<'a>(&'a self, session: &'a VoiceSession, audio: AudioStream) -> PipelineFuture<'a, String> {
+        Box::pin(async move {
+            let Some(pipeline) = &self.pipeline else {
+                self.record(session, audio, None).await?;
+                return Ok(String::new());
+            };
+
+            let (tx, rx) = mpsc::channel(64);
+            let (recorded, text) = tokio::join!(
+                self.record(session, audio, Some(tx)),
+                pipeline.speech_to_text(session, rx)
+            );
+            if let Err(err) = recorded {
+                error!("unable to record voice assistant session {:?}", err);
+            }
+            text
+        })
+    }
+
+    fn recognize_intent<'a>(&'a self, session: &'a VoiceSession, text: String) -> PipelineFuture<'a, IntentResult> {
+        Box::pin(async move {
+            match &self.pipeline {
+                Some(pipeline) => pipeline.recognize_intent(session, text).await,
+                None => Ok(IntentResult::default()),
+            }
+        })
+    }
+
+    fn text_to_speech<'a>(&'a self, session: &'a VoiceSession, text: String) -> PipelineFuture<'a, String> {
+        Box::pin(async move {
+            match &self.pipeline {
+                Some(pipeline) => pipeline.text_to_speech(session, text).await,
+                None => Ok(String::new()),
+            }
+        })
+    }
+
+    fn responds(&self) -> bool {
+        self.pipeline.as_ref().is_some_and(|pipeline| pipeline.responds())
+    }
+}